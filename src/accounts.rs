@@ -0,0 +1,303 @@
+use std::{collections::HashSet, path::{Path, PathBuf}, sync::Arc, time::{Duration, SystemTime}};
+
+use indicatif::MultiProgress;
+use tokio::{fs, sync::{mpsc::{self, UnboundedSender}, watch}, time::sleep};
+use tracing::{debug, error, info, warn};
+use twitch_gql_rs::{TwitchClient, error::TwitchError};
+
+use crate::{backend::{DropsBackend, connect}, error::{AppError, Classify, ErrorClass, RetryPolicy, retry_backup}, metrics::METRICS, proxy::{PROXY_MAP_FILE, ProxyHealth, assign_proxy, redact, probe_proxy}, ratelimit::LIMITER, r#static::{AppState, Channel}, stream::ReleaseQueue, tasks::RESTART_DELAY, vault::{SESSION_EXTENSION, VAULT_FILE, Vault}, webhook::WebhookSendFormat};
+
+const SESSION_SCAN_INTERVAL: u64 = 30;
+const MAX_FAILOVERS: usize = 2;
+const RESERVED_FILES: [&str; 5] = ["cache.json", "config.json", "cash.json", VAULT_FILE, PROXY_MAP_FILE];
+
+/// Everything a per-account task needs to join a farm that is already running.
+#[derive(Debug, Clone)]
+pub struct FarmingContext {
+    pub channel_rx: watch::Receiver<Option<Channel>>,
+    pub releases: Arc<ReleaseQueue>,
+    pub webhook_tx: mpsc::Sender<WebhookSendFormat>,
+    pub webhook_is_active: bool,
+    pub drop_id_tx: UnboundedSender<String>,
+    pub bars: Arc<MultiProgress>,
+}
+
+/// Plaintext sessions are `<login>.json`, vault-sealed ones are `<login>.session`.
+pub fn is_session_file (path: &Path) -> bool {
+    let file_name = path.file_name().unwrap_or_default();
+    let plaintext = path.extension().is_some_and(|s| s == "json") && !RESERVED_FILES.iter().any(|reserved| file_name == *reserved);
+    let sealed = path.extension().is_some_and(|s| s == SESSION_EXTENSION);
+    path.is_file() && (plaintext || sealed)
+}
+
+async fn read_session (path: &Path, proxy: &Option<String>, vault: Option<&Vault>) -> Result<TwitchClient, AppError> {
+    let client = if path.extension().is_some_and(|s| s == SESSION_EXTENSION) {
+        let vault = vault.ok_or_else(|| AppError::Config("Found an encrypted session but the vault is disabled in config".to_string()))?;
+        vault.load_client(path, proxy).await?
+    } else {
+        TwitchClient::load_from_file(path, proxy).await?
+    };
+    if let Some(login) = &client.login {
+        LIMITER.set_egress(login, proxy.as_deref());
+    }
+    Ok(client)
+}
+
+/// Writes a freshly authenticated client to `home_dir`, sealed if the vault is enabled,
+/// and returns the session path. An existing session for the same login is kept as is.
+pub async fn write_session (client: &TwitchClient, home_dir: &Path, vault: Option<&Vault>) -> Result<PathBuf, AppError> {
+    let login = client.login.clone().ok_or_else(|| TwitchError::MissingField("login".to_string()))?;
+    match vault {
+        Some(vault) => {
+            let path = home_dir.join(format!("{login}.{SESSION_EXTENSION}"));
+            if !path.exists() {
+                vault.save_client(client, &path).await?;
+            }
+            Ok(path)
+        },
+        None => {
+            let path = home_dir.join(format!("{login}.json"));
+            if !path.exists() {
+                client.save_file(&path).await?;
+            }
+            Ok(path)
+        }
+    }
+}
+
+/// Loads a session file through the account's sticky proxy and validates it against Twitch.
+/// If validation fails and the proxy doesn't pass a health probe, the proxy is marked dead and the
+/// account is retried on another one. Sessions rejected by Twitch are moved as-is to `delete_dir`
+/// and `None` is returned. So is `None` when Twitch only failed transiently, but the session stays where it is.
+pub async fn load_session (path: &Path, delete_dir: &Path, state: &AppState) -> Result<Option<Arc<dyn DropsBackend>>, AppError> {
+    let login = path.file_stem().unwrap_or_default().to_string_lossy().to_string();
+    for _ in 0..=MAX_FAILOVERS {
+        let proxy = assign_proxy(state, &login, None).await;
+        let client = connect(read_session(path, &proxy, state.vault.get()).await?, proxy.as_deref(), state)?;
+        let validation = retry_backup(&RetryPolicy::GQL, || client.get_campaign()).await;
+        let Err(e) = validation else {
+            return Ok(Some(client));
+        };
+
+        if let Some(proxy) = &proxy && !probe_proxy(state, proxy).await {
+            warn!("Account {login} failed validation through an unreachable proxy, trying another one: {e}");
+            continue;
+        }
+        if let ErrorClass::Retryable | ErrorClass::RateLimited(_) = e.class() {
+            // Twitch is having trouble, not the session. Keep the file so the session watcher picks it up again.
+            warn!("Couldn't validate account {login} right now, skipping it for the moment: {e}");
+            return Ok(None);
+        }
+
+        if !delete_dir.exists() {
+            fs::create_dir_all(&delete_dir).await?;
+        }
+        let new_path = delete_dir.join(path.file_name().unwrap_or_default());
+        fs::rename(&path, &new_path).await?;
+        METRICS.accounts_quarantined.inc();
+        error!("Failed to load client from file {}: {e}", path.display());
+        return Ok(None);
+    }
+    Err(AppError::Proxy(format!("No working proxy left for account {login}")))
+}
+
+/// Adds an account to the registry. If farming is already running, the watch, progress and claim
+/// tasks for the account are spawned right away.
+/// Returns `false` if an account with the same login is already registered.
+pub async fn register_account (client: Arc<dyn DropsBackend>, state: &Arc<AppState>) -> bool {
+    let login = client.login().to_string();
+
+    // Held for the whole registration so `start_farming` can't snapshot the account list in between.
+    let farming = state.farming.lock().await;
+
+    {
+        let mut accounts = state.accounts.lock().await;
+        let accounts = accounts.get_or_insert_with(Vec::new);
+        if accounts.iter().any(|c| c.login() == login) {
+            return false;
+        }
+        accounts.push(client.clone());
+    }
+
+    if let Some(ctx) = &*farming {
+        spawn_account_tasks(client, ctx, state);
+        info!("Account {login} joined the running farm");
+    }
+    true
+}
+
+/// Swaps the client of an already registered account, e.g. after it moved to another proxy.
+/// Its running tasks are cancelled and respawned with the new client.
+pub async fn replace_account (client: Arc<dyn DropsBackend>, state: &Arc<AppState>) -> bool {
+    let login = client.login().to_string();
+    let farming = state.farming.lock().await;
+
+    {
+        let mut accounts = state.accounts.lock().await;
+        let Some(slot) = accounts.iter_mut().flatten().find(|c| c.login() == login) else {
+            return false;
+        };
+        *slot = client.clone();
+    }
+
+    // Respawning under the same task names replaces the old tasks.
+    if let Some(ctx) = &*farming {
+        spawn_account_tasks(client, ctx, state);
+    }
+    true
+}
+
+/// Rebuilds a registered account's client from its session file on its currently assigned proxy.
+pub async fn rebind_account (home_dir: &Path, login: &str, state: &Arc<AppState>) -> Result<(), AppError> {
+    let sealed = home_dir.join(format!("{login}.{SESSION_EXTENSION}"));
+    let path = if sealed.exists() { sealed } else { home_dir.join(format!("{login}.json")) };
+    let proxy = state.proxy_pool.lock().await.proxy_of(login).cloned();
+    let client = connect(read_session(&path, &proxy, state.vault.get()).await?, proxy.as_deref(), state)?;
+    if !replace_account(client, state).await {
+        return Err(AppError::Account(format!("Account {login} is not registered")));
+    }
+    Ok(())
+}
+
+/// Publishes the farming context and spawns tasks for every account registered so far.
+pub async fn start_farming (ctx: FarmingContext, state: &Arc<AppState>) -> Result<(), AppError> {
+    let mut farming = state.farming.lock().await;
+    let clients = state.accounts.lock().await.clone().ok_or_else(|| AppError::Account("Didn't find accounts".to_string()))?;
+    for client in clients {
+        spawn_account_tasks(client, &ctx, state);
+    }
+    *farming = Some(ctx);
+    Ok(())
+}
+
+fn spawn_account_tasks (client: Arc<dyn DropsBackend>, ctx: &FarmingContext, state: &Arc<AppState>) {
+    let login = client.login().to_string();
+
+    let (watch_client, channel_rx, releases, watch_state) = (client.clone(), ctx.channel_rx.clone(), ctx.releases.clone(), state.clone());
+    state.tasks.spawn_supervised(format!("watch_sync:{login}"), RESTART_DELAY, move || {
+        crate::watch_sync(watch_client.clone(), channel_rx.clone(), releases.clone(), watch_state.clone())
+    });
+
+    let (drop_client, drop_ctx, drop_state) = (client.clone(), ctx.clone(), state.clone());
+    state.tasks.spawn_supervised(format!("drop_sync:{login}"), RESTART_DELAY, move || {
+        crate::drop_sync(drop_client.clone(), drop_ctx.clone(), drop_state.clone())
+    });
+
+    let (drop_id_tx, sweep_state) = (ctx.drop_id_tx.clone(), state.clone());
+    state.tasks.spawn_supervised(format!("claim_sweep:{login}"), RESTART_DELAY, move || {
+        crate::claim_sweep(client.clone(), drop_id_tx.clone(), sweep_state.clone())
+    });
+}
+
+/// Picks up session files dropped into `home_dir` while the app is running.
+pub fn watch_session_dir (home_dir: PathBuf, state: Arc<AppState>) {
+    let tasks = state.tasks.clone();
+    tasks.spawn_supervised("session_watcher", RESTART_DELAY, move || session_watcher(home_dir.clone(), state.clone()));
+}
+
+async fn session_watcher (home_dir: PathBuf, state: Arc<AppState>) {
+    let delete_dir = Path::new("delete_accounts");
+    let mut rejected: HashSet<(PathBuf, SystemTime)> = HashSet::new();
+    loop {
+        sleep(Duration::from_secs(SESSION_SCAN_INTERVAL)).await;
+
+        let known: HashSet<String> = {
+            let accounts = state.accounts.lock().await;
+            accounts.iter().flatten().map(|c| c.login().to_string()).collect()
+        };
+
+        let mut entries = match fs::read_dir(&home_dir).await {
+            Ok(entries) => entries,
+            Err(e) => {
+                error!("Failed to scan {} for new sessions: {e}", home_dir.display());
+                continue;
+            }
+        };
+
+        while let Ok(Some(entry)) = entries.next_entry().await {
+            let path = entry.path();
+            if !is_session_file(&path) {
+                continue;
+            }
+            let stem = path.file_stem().unwrap_or_default().to_string_lossy().to_string();
+            if known.contains(&stem) {
+                continue;
+            }
+            let modified = entry.metadata().await.and_then(|m| m.modified()).unwrap_or(SystemTime::UNIX_EPOCH);
+            if rejected.contains(&(path.clone(), modified)) {
+                continue;
+            }
+
+            let path = match state.vault.get() {
+                Some(vault) if path.extension().is_some_and(|s| s == "json") => match vault.migrate_file(&path).await.map_err(|e| e.to_string()) {
+                    Ok(sealed) => {
+                        info!("Encrypted dropped-in session {}", path.display());
+                        sealed
+                    },
+                    Err(e) => {
+                        warn!("Failed to encrypt session file {}: {e}", path.display());
+                        rejected.insert((path, modified));
+                        continue;
+                    }
+                },
+                _ => path,
+            };
+
+            let loaded = load_session(&path, delete_dir, &state).await.map_err(|e| e.to_string());
+            match loaded {
+                Ok(Some(client)) => {
+                    let login = client.login().to_string();
+                    if register_account(client, &state).await {
+                        info!("New session file detected, account {login} added");
+                    } else {
+                        debug!("Session file {} belongs to an already registered account", path.display());
+                        rejected.insert((path, modified));
+                    }
+                },
+                Ok(None) => {},
+                Err(e) => {
+                    warn!("Failed to load session file {}: {e}", path.display());
+                    rejected.insert((path, modified));
+                }
+            }
+        }
+    }
+}
+
+/// Prints every saved session with its proxy and the proxy health, followed by the proxy list.
+/// Nothing is validated or reassigned, so it is safe to run next to a farming instance.
+pub async fn print_accounts (home_dir: &Path, state: &AppState) -> Result<(), AppError> {
+    let mut logins = Vec::new();
+    let mut entries = fs::read_dir(home_dir).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        let path = entry.path();
+        if is_session_file(&path) {
+            logins.push(path.file_stem().unwrap_or_default().to_string_lossy().to_string());
+        }
+    }
+    logins.sort();
+
+    let pool = state.proxy_pool.lock().await;
+    println!("{:<25} {:<35} HEALTH", "ACCOUNT", "PROXY");
+    for login in &logins {
+        let (proxy, health) = match pool.proxy_of(login) {
+            Some(proxy) => (redact(proxy), pool.health(proxy).map(ProxyHealth::label).unwrap_or_else(|| "unchecked".to_string())),
+            None => ("direct".to_string(), "-".to_string()),
+        };
+        println!("{login:<25} {proxy:<35} {health}");
+    }
+    if logins.is_empty() {
+        println!("No saved sessions in {}", home_dir.display());
+    }
+
+    if !pool.proxies().is_empty() {
+        println!("\n{:<35} {:<9} HEALTH", "PROXY", "ACCOUNTS");
+        for proxy in pool.proxies() {
+            let health = pool.health(proxy);
+            let label = health.map(ProxyHealth::label).unwrap_or_else(|| "unchecked".to_string());
+            let error = health.and_then(|h| h.last_error.as_deref()).map(|e| format!(" ({e})")).unwrap_or_default();
+            println!("{:<35} {:<9} {label}{error}", redact(proxy), pool.load_of(proxy));
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,201 @@
+use std::{net::Ipv4Addr, path::PathBuf, sync::Arc};
+
+use axum::{Json, Router, extract::{Path, Request, State}, http::{StatusCode, header::{AUTHORIZATION, CONTENT_TYPE, HOST}}, middleware::{self, Next}, response::{IntoResponse, Response}, routing::{get, post}};
+use serde::Serialize;
+use serde_json::json;
+use tokio::net::TcpListener;
+use tracing::{error, info};
+use twitch_gql_rs::structs::DropCampaigns;
+
+use crate::{config::ApiConfig, error::AppError, login::start_device_login, metrics::METRICS, proxy::redact, r#static::{AccountStatus, AppState, Channel}, streamers::PickReason, tasks::{RESTART_DELAY, TaskStatus}};
+
+type ApiState = State<Arc<AppState>>;
+
+#[derive(Debug, Serialize)]
+struct AccountView {
+    login: String,
+    proxy: Option<String>,
+    proxy_health: Option<String>,
+    paused: bool,
+    #[serde(flatten)]
+    status: AccountStatus,
+}
+
+#[derive(Debug, Serialize)]
+struct CampaignView {
+    #[serde(flatten)]
+    campaign: DropCampaigns,
+    ends_at: Option<String>,
+    benefits: Vec<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct PoolEntry {
+    #[serde(flatten)]
+    channel: Channel,
+    priority: Option<u32>,
+    reason: Option<PickReason>,
+}
+
+/// What every request is checked against before it reaches a route.
+#[derive(Debug)]
+struct Guard {
+    token: String,
+    /// `127.0.0.1:<port>` and `localhost:<port>`. Anything else is a browser that was pointed here
+    /// through another name, e.g. by DNS rebinding.
+    hosts: [String; 2],
+}
+
+/// Starts the local status/control API on 127.0.0.1 in the background.
+/// Accounts logged in through it are saved to `home_dir`.
+pub async fn spawn_api (config: &ApiConfig, home_dir: PathBuf, state: Arc<AppState>) -> Result<(), AppError> {
+    // Without a token any web page the user opens could drive the API with plain cross-origin POSTs.
+    if config.token.is_empty() {
+        return Err(AppError::Config("The control API needs a token, set \"api\": { \"token\": \"...\" } in data/config.json".to_string()));
+    }
+    let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, config.port)).await?;
+    let port = listener.local_addr()?.port();
+    let guard = Arc::new(Guard { token: config.token.clone(), hosts: [format!("127.0.0.1:{port}"), format!("localhost:{port}")] });
+    let app = Router::new()
+        .route("/api/accounts", get(accounts))
+        .route("/api/accounts/login", post(move |state| add_account(state, home_dir.clone())))
+        .route("/api/accounts/{login}/pause", post(pause_account))
+        .route("/api/accounts/{login}/resume", post(resume_account))
+        .route("/api/channels", get(channels))
+        .route("/api/channels/skip", post(skip_channel))
+        .route("/api/campaigns", get(campaigns))
+        .route("/api/campaigns/refresh", post(refresh_campaigns))
+        .route("/api/claims", get(claims))
+        .route("/api/tasks", get(tasks))
+        .route("/metrics", get(metrics))
+        .layer(middleware::from_fn_with_state(guard, require_token))
+        .with_state(state.clone());
+
+    info!("Control API listening on http://{}", listener.local_addr()?);
+    // Every restart serves on a copy of the socket bound above, so the port is never given up.
+    let listener = listener.into_std()?;
+    state.tasks.spawn_supervised("control_api", RESTART_DELAY, move || {
+        let (listener, app) = (listener.try_clone(), app.clone());
+        async move {
+            let served = match listener.and_then(TcpListener::from_std) {
+                Ok(listener) => axum::serve(listener, app).await,
+                Err(e) => Err(e),
+            };
+            if let Err(e) = served {
+                error!("Control API stopped: {e}");
+            }
+        }
+    });
+    Ok(())
+}
+
+async fn require_token (State(guard): State<Arc<Guard>>, request: Request, next: Next) -> Response {
+    let host = request.headers().get(HOST).and_then(|v| v.to_str().ok()).unwrap_or_default();
+    if !guard.hosts.iter().any(|allowed| allowed.eq_ignore_ascii_case(host)) {
+        return api_error(StatusCode::FORBIDDEN, "unexpected Host header");
+    }
+    let provided = request.headers().get(AUTHORIZATION).and_then(|v| v.to_str().ok()).and_then(|v| v.strip_prefix("Bearer "));
+    if !provided.is_some_and(|provided| constant_time_eq(provided.as_bytes(), guard.token.as_bytes())) {
+        return api_error(StatusCode::UNAUTHORIZED, "missing or invalid bearer token");
+    }
+    next.run(request).await
+}
+
+/// Compares without returning early, so the response time doesn't tell how much of a guess was right.
+fn constant_time_eq (a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+fn api_error (status: StatusCode, message: &str) -> Response {
+    (status, Json(json!({ "error": message }))).into_response()
+}
+
+async fn accounts (State(state): ApiState) -> Json<Vec<AccountView>> {
+    let logins: Vec<String> = state.accounts.lock().await.iter().flatten().map(|c| c.login().to_string()).collect();
+    let statuses = state.account_status.lock().await.clone();
+    let paused = state.paused_accounts.lock().await.clone();
+    let pool = state.proxy_pool.lock().await;
+
+    Json(logins.into_iter().map(|login| AccountView {
+        proxy: pool.proxy_of(&login).map(|p| redact(p)),
+        proxy_health: pool.status_label(&login),
+        paused: paused.contains(&login),
+        status: statuses.get(&login).cloned().unwrap_or_default(),
+        login,
+    }).collect())
+}
+
+async fn add_account (State(state): ApiState, home_dir: PathBuf) -> Response {
+    match start_device_login(home_dir, state).await.map_err(|e| e.to_string()) {
+        Ok(pending) => (StatusCode::ACCEPTED, Json(pending)).into_response(),
+        Err(e) => api_error(StatusCode::BAD_GATEWAY, &format!("failed to start the device login: {e}")),
+    }
+}
+
+async fn set_paused (state: &AppState, login: &str, paused: bool) -> Response {
+    if !state.set_paused(login, paused).await {
+        return api_error(StatusCode::NOT_FOUND, "unknown account");
+    }
+    Json(json!({ "login": login, "paused": paused })).into_response()
+}
+
+async fn pause_account (State(state): ApiState, Path(login): Path<String>) -> Response {
+    set_paused(&state, &login, true).await
+}
+
+async fn resume_account (State(state): ApiState, Path(login): Path<String>) -> Response {
+    set_paused(&state, &login, false).await
+}
+
+async fn channels (State(state): ApiState) -> Json<serde_json::Value> {
+    let watching = state.farming.lock().await.as_ref().and_then(|ctx| ctx.channel_rx.borrow().clone());
+    let priorities = state.channel_priority.lock().await.clone();
+    let mut pool: Vec<PoolEntry> = state.channel_pool.lock().await.iter().map(|channel| PoolEntry {
+        priority: priorities.get(&channel.channel_id).map(|rank| rank.priority),
+        reason: priorities.get(&channel.channel_id).map(|rank| rank.reason),
+        channel: channel.clone(),
+    }).collect();
+    pool.sort_by(|a, b| b.priority.cmp(&a.priority).then_with(|| a.channel.channel_login.cmp(&b.channel.channel_login)));
+    Json(json!({ "watching": watching, "pool": pool }))
+}
+
+async fn skip_channel (State(state): ApiState) -> Response {
+    match state.skip_channel().await {
+        Some(watching) => (StatusCode::ACCEPTED, Json(json!({ "skipped": watching }))).into_response(),
+        None => api_error(StatusCode::CONFLICT, "farming is not running"),
+    }
+}
+
+async fn campaigns (State(state): ApiState) -> Json<serde_json::Value> {
+    let farmed: Vec<_> = state.campaigns.lock().await.iter().flatten().cloned().collect();
+    let mut campaigns = Vec::with_capacity(farmed.len());
+    for campaign in farmed {
+        let ends_at = state.catalog.deadline(&campaign.id).await.map(|end| end.to_rfc3339());
+        let benefits: Vec<String> = state.catalog.benefits(&campaign.id).await.into_iter().map(|benefit| benefit.name).collect();
+        campaigns.push(CampaignView { campaign, ends_at, benefits });
+    }
+    let mut pending: Vec<String> = state.pending_drops.lock().await.iter().cloned().collect();
+    pending.sort();
+    Json(json!({ "campaigns": campaigns, "pending_drops": pending }))
+}
+
+async fn refresh_campaigns (State(state): ApiState) -> Response {
+    state.campaign_refresh.notify_one();
+    (StatusCode::ACCEPTED, Json(json!({ "refresh": "requested" }))).into_response()
+}
+
+async fn claims (State(state): ApiState) -> Json<serde_json::Value> {
+    let history: Vec<_> = state.claim_history.lock().await.iter().cloned().collect();
+    Json(json!({ "claims": history }))
+}
+
+async fn tasks (State(state): ApiState) -> Json<Vec<TaskStatus>> {
+    Json(state.tasks.statuses())
+}
+
+async fn metrics (State(state): ApiState) -> Response {
+    match METRICS.render(&state).await {
+        Ok(body) => ([(CONTENT_TYPE, "application/openmetrics-text; version=1.0.0; charset=utf-8")], body).into_response(),
+        Err(e) => api_error(StatusCode::INTERNAL_SERVER_ERROR, &format!("failed to encode metrics: {e}")),
+    }
+}
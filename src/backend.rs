@@ -0,0 +1,225 @@
+use std::{fmt, sync::Arc};
+
+use futures_util::future::BoxFuture;
+use reqwest::{Client, Proxy, header::{HeaderMap, HeaderValue}};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use twitch_gql_rs::{TwitchClient, error::{AvailableDropsError, CampaignDetailsError, ClaimDropError, StreamInfoError, TwitchError}, structs::{AvailableDrops, CampaignDetails, ClaimDrop, CurrentDrop, Drops, GetInventory, StreamInfo}};
+
+use crate::{capture::Capture, error::Classify, metrics::Metered, ratelimit::RateLimit, r#static::AppState};
+
+pub type BackendFuture<'a, T, E> = BoxFuture<'a, Result<T, E>>;
+
+/// A minute-watched beacon for the stream being watched.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Watch {
+    pub channel_login: String,
+    pub channel_id: String,
+    pub broadcast_id: String,
+    pub game_name: String,
+    pub game_id: String,
+}
+
+/// The Twitch operations DropSentry uses, for one account. Every per-account task talks to Twitch
+/// through this, so the real client can be wrapped (see [`Layer`]) or swapped for a fake.
+pub trait DropsBackend: Send + Sync + fmt::Debug {
+    fn login (&self) -> &str;
+    fn user_id (&self) -> &str;
+    /// OAuth token, also sent with PubSub `LISTEN` requests.
+    fn access_token (&self) -> &str;
+
+    fn get_campaign (&self) -> BackendFuture<'_, Drops, TwitchError>;
+    fn get_campaign_details<'a> (&'a self, campaign_id: &'a str) -> BackendFuture<'a, CampaignDetails, CampaignDetailsError>;
+    fn get_stream_info<'a> (&'a self, channel_login: &'a str) -> BackendFuture<'a, StreamInfo, StreamInfoError>;
+    fn get_available_drops_for_channel<'a> (&'a self, channel_id: &'a str) -> BackendFuture<'a, AvailableDrops, AvailableDropsError>;
+    fn get_current_drop_progress_on_channel<'a> (&'a self, channel_login: &'a str) -> BackendFuture<'a, CurrentDrop, TwitchError>;
+    fn get_inventory (&self) -> BackendFuture<'_, GetInventory, TwitchError>;
+    fn claim_drop<'a> (&'a self, drop_instance_id: &'a str) -> BackendFuture<'a, ClaimDrop, ClaimDropError>;
+    fn send_watch<'a> (&'a self, watch: &'a Watch) -> BackendFuture<'a, (), TwitchError>;
+    /// Sends a `DirectoryPage_Game` query and returns the raw response, see `directory::discover_streams`.
+    fn get_game_directory<'a> (&'a self, query: &'a Value) -> BackendFuture<'a, Value, TwitchError>;
+}
+
+/// The backend the farm uses for an account: its session, behind the rate limiter and metrics,
+/// and recorded when running with `--record`.
+pub fn connect (client: TwitchClient, proxy: Option<&str>, state: &AppState) -> Result<Arc<dyn DropsBackend>, TwitchError> {
+    let gql_url = state.endpoints.get().cloned().unwrap_or_default().gql_url;
+    let twitch = TwitchBackend::new(client, proxy, gql_url)?;
+    Ok(match state.capture.get() {
+        Some(Capture::Record(recorder)) => Arc::new(layered(recorder.record(twitch))),
+        _ => Arc::new(layered(twitch)),
+    })
+}
+
+/// `backend` behind the rate limiter and metrics, like every backend the farm uses.
+pub fn layered (backend: impl DropsBackend + 'static) -> Layered<RateLimit> {
+    Layered::new(RateLimit, Layered::new(Metered, backend))
+}
+
+/// Talks to Twitch with a saved session, through the account's proxy.
+pub struct TwitchBackend {
+    client: TwitchClient,
+    /// For the requests `twitch_gql_rs` has no call for, with the same session headers and proxy.
+    http: Client,
+    gql_url: String,
+}
+
+impl TwitchBackend {
+    pub fn new (client: TwitchClient, proxy: Option<&str>, gql_url: String) -> Result<Self, TwitchError> {
+        let http = http_client(&client, proxy)?;
+        Ok(TwitchBackend { client, http, gql_url })
+    }
+}
+
+impl fmt::Debug for TwitchBackend {
+    fn fmt (&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TwitchBackend").field("login", &self.login()).finish_non_exhaustive()
+    }
+}
+
+fn http_client (client: &TwitchClient, proxy: Option<&str>) -> Result<Client, TwitchError> {
+    let header = |value: &str| HeaderValue::from_str(value).map_err(|e| TwitchError::TwitchError(format!("invalid session header: {e}")));
+    let mut headers = HeaderMap::new();
+    headers.insert("Client-Id", header(&client.client_id)?);
+    headers.insert("X-Device-Id", header(&client.device_id)?);
+    headers.insert("User-Agent", header(&client.user_agent)?);
+    if let Some(token) = &client.access_token {
+        headers.insert("Authorization", header(&format!("OAuth {token}"))?);
+    }
+
+    let mut builder = Client::builder().default_headers(headers);
+    if let Some(proxy) = proxy {
+        builder = builder.proxy(Proxy::all(proxy)?);
+    }
+    Ok(builder.build()?)
+}
+
+impl DropsBackend for TwitchBackend {
+    fn login (&self) -> &str {
+        self.client.login.as_deref().unwrap_or_default()
+    }
+
+    fn user_id (&self) -> &str {
+        self.client.user_id.as_deref().unwrap_or_default()
+    }
+
+    fn access_token (&self) -> &str {
+        self.client.access_token.as_deref().unwrap_or_default()
+    }
+
+    fn get_campaign (&self) -> BackendFuture<'_, Drops, TwitchError> {
+        Box::pin(self.client.get_campaign())
+    }
+
+    fn get_campaign_details<'a> (&'a self, campaign_id: &'a str) -> BackendFuture<'a, CampaignDetails, CampaignDetailsError> {
+        Box::pin(self.client.get_campaign_details(campaign_id))
+    }
+
+    fn get_stream_info<'a> (&'a self, channel_login: &'a str) -> BackendFuture<'a, StreamInfo, StreamInfoError> {
+        Box::pin(self.client.get_stream_info(channel_login))
+    }
+
+    fn get_available_drops_for_channel<'a> (&'a self, channel_id: &'a str) -> BackendFuture<'a, AvailableDrops, AvailableDropsError> {
+        Box::pin(self.client.get_available_drops_for_channel(channel_id))
+    }
+
+    fn get_current_drop_progress_on_channel<'a> (&'a self, channel_login: &'a str) -> BackendFuture<'a, CurrentDrop, TwitchError> {
+        Box::pin(self.client.get_current_drop_progress_on_channel(channel_login))
+    }
+
+    fn get_inventory (&self) -> BackendFuture<'_, GetInventory, TwitchError> {
+        Box::pin(self.client.get_inventory())
+    }
+
+    fn claim_drop<'a> (&'a self, drop_instance_id: &'a str) -> BackendFuture<'a, ClaimDrop, ClaimDropError> {
+        Box::pin(self.client.claim_drop(drop_instance_id))
+    }
+
+    fn send_watch<'a> (&'a self, watch: &'a Watch) -> BackendFuture<'a, (), TwitchError> {
+        Box::pin(self.client.send_watch(&watch.channel_login, &watch.broadcast_id, &watch.channel_id, Some(&watch.game_name), Some(&watch.game_id)))
+    }
+
+    fn get_game_directory<'a> (&'a self, query: &'a Value) -> BackendFuture<'a, Value, TwitchError> {
+        Box::pin(async move {
+            let response = self.http.post(&self.gql_url).json(query).send().await?;
+            if !response.status().is_success() {
+                return Err(TwitchError::HttpError(response.status().as_u16()));
+            }
+            let body: Value = response.json().await?;
+            // An overloaded GQL answers 200 with `errors` instead of `data`, e.g. "service timeout".
+            if body.get("data").is_none_or(Value::is_null) && let Some(message) = body.pointer("/errors/0/message").and_then(Value::as_str) {
+                return Err(TwitchError::TwitchError(message.to_string()));
+            }
+            Ok(body)
+        })
+    }
+}
+
+/// Something done around every call of the backend it wraps, such as waiting for the rate limiter.
+pub trait Layer: Send + Sync + fmt::Debug {
+    /// `operation` names the call like the backend method, e.g. `get_inventory`.
+    fn call<'a, T: Send + 'a, E: Classify + Send + 'a> (&'a self, login: &'a str, operation: &'static str, request: BackendFuture<'a, T, E>) -> BackendFuture<'a, T, E>;
+}
+
+/// A backend with a [`Layer`] around each of its calls.
+#[derive(Debug)]
+pub struct Layered<L> {
+    layer: L,
+    inner: Box<dyn DropsBackend>,
+}
+
+impl<L: Layer> Layered<L> {
+    pub fn new (layer: L, inner: impl DropsBackend + 'static) -> Self {
+        Layered { layer, inner: Box::new(inner) }
+    }
+}
+
+impl<L: Layer> DropsBackend for Layered<L> {
+    fn login (&self) -> &str {
+        self.inner.login()
+    }
+
+    fn user_id (&self) -> &str {
+        self.inner.user_id()
+    }
+
+    fn access_token (&self) -> &str {
+        self.inner.access_token()
+    }
+
+    fn get_campaign (&self) -> BackendFuture<'_, Drops, TwitchError> {
+        self.layer.call(self.login(), "get_campaign", self.inner.get_campaign())
+    }
+
+    fn get_campaign_details<'a> (&'a self, campaign_id: &'a str) -> BackendFuture<'a, CampaignDetails, CampaignDetailsError> {
+        self.layer.call(self.login(), "get_campaign_details", self.inner.get_campaign_details(campaign_id))
+    }
+
+    fn get_stream_info<'a> (&'a self, channel_login: &'a str) -> BackendFuture<'a, StreamInfo, StreamInfoError> {
+        self.layer.call(self.login(), "get_stream_info", self.inner.get_stream_info(channel_login))
+    }
+
+    fn get_available_drops_for_channel<'a> (&'a self, channel_id: &'a str) -> BackendFuture<'a, AvailableDrops, AvailableDropsError> {
+        self.layer.call(self.login(), "get_available_drops_for_channel", self.inner.get_available_drops_for_channel(channel_id))
+    }
+
+    fn get_current_drop_progress_on_channel<'a> (&'a self, channel_login: &'a str) -> BackendFuture<'a, CurrentDrop, TwitchError> {
+        self.layer.call(self.login(), "get_current_drop_progress_on_channel", self.inner.get_current_drop_progress_on_channel(channel_login))
+    }
+
+    fn get_inventory (&self) -> BackendFuture<'_, GetInventory, TwitchError> {
+        self.layer.call(self.login(), "get_inventory", self.inner.get_inventory())
+    }
+
+    fn claim_drop<'a> (&'a self, drop_instance_id: &'a str) -> BackendFuture<'a, ClaimDrop, ClaimDropError> {
+        self.layer.call(self.login(), "claim_drop", self.inner.claim_drop(drop_instance_id))
+    }
+
+    fn send_watch<'a> (&'a self, watch: &'a Watch) -> BackendFuture<'a, (), TwitchError> {
+        self.layer.call(self.login(), "send_watch", self.inner.send_watch(watch))
+    }
+
+    fn get_game_directory<'a> (&'a self, query: &'a Value) -> BackendFuture<'a, Value, TwitchError> {
+        self.layer.call(self.login(), "get_game_directory", self.inner.get_game_directory(query))
+    }
+}
@@ -0,0 +1,497 @@
+//! `--record <dir>` writes every GQL call and PubSub frame of a run to `<dir>/capture.jsonl`, one JSON
+//! object per line, with tokens redacted. `--replay <dir>` answers the farm from such a capture instead
+//! of Twitch, so a bug report's scheduling and claiming can be reproduced offline.
+
+use std::{collections::{HashMap, HashSet, VecDeque}, fs::File, io::{self, BufRead, BufReader, LineWriter, Write}, path::Path, sync::{Arc, Mutex, atomic::{AtomicUsize, Ordering}}, time::{Duration, Instant}};
+
+use futures_util::future;
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
+use serde_json::{Value, json};
+use tracing::warn;
+use twitch_gql_rs::{error::{AvailableDropsError, CampaignDetailsError, ClaimDropError, StreamInfoError, TwitchError}, structs::{AvailableDrops, CampaignDetails, ClaimDrop, CurrentDrop, Drops, GetInventory, StreamInfo}};
+
+use crate::{backend::{BackendFuture, DropsBackend, Watch}, error::{Classify, ErrorClass}};
+
+pub const CAPTURE_FILE: &str = "capture.jsonl";
+const REDACTED: &str = "[redacted]";
+/// Object keys whose string values are redacted wherever they appear.
+const SECRET_KEYS: [&str; 3] = ["auth_token", "access_token", "authorization"];
+
+/// `--record` or `--replay`, whichever the run was started with.
+#[derive(Debug, Clone)]
+pub enum Capture {
+    Record(Arc<Recorder>),
+    Replay(Arc<Replay>),
+}
+
+/// One line of a capture.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Entry {
+    /// Wall clock time, RFC 3339.
+    pub at: String,
+    /// Milliseconds since the recording started.
+    pub elapsed_ms: u64,
+    pub login: String,
+    #[serde(flatten)]
+    pub event: Event,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Event {
+    /// An account the farm used, replay gives it back a backend.
+    Account { user_id: String },
+    /// A backend call, `operation` is the method name, e.g. `get_inventory`.
+    Gql { operation: String, request: Value, result: Outcome },
+    Pubsub { direction: Direction, frame: Value },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Outcome {
+    Ok(Value),
+    Err(CapturedError),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Direction {
+    Sent,
+    Received,
+}
+
+/// A backend error as written to a capture, detailed enough to be classified the same way on replay.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum CapturedError {
+    Http { status: u16 },
+    MissingField { field: String },
+    /// A network or decoding problem worth retrying.
+    Transient { message: String },
+    Twitch { message: String },
+    /// The campaign or channel doesn't exist.
+    NotFound,
+    AlreadyClaimed,
+    ClaimFailed { message: String },
+}
+
+/// Backend errors that can be written to a capture and rebuilt from it.
+pub trait Captured: Sized {
+    fn capture (&self) -> CapturedError;
+    fn replay (error: CapturedError) -> Self;
+}
+
+impl Captured for TwitchError {
+    fn capture (&self) -> CapturedError {
+        match self {
+            TwitchError::HttpError(status) => CapturedError::Http { status: *status },
+            TwitchError::ReqwestProblem(e) if e.status().is_some() => CapturedError::Http { status: e.status().map_or(0, |status| status.as_u16()) },
+            TwitchError::MissingField(field) => CapturedError::MissingField { field: field.clone() },
+            TwitchError::TwitchError(message) => CapturedError::Twitch { message: message.clone() },
+            e if e.class() == ErrorClass::Retryable => CapturedError::Transient { message: e.to_string() },
+            e => CapturedError::Twitch { message: e.to_string() },
+        }
+    }
+
+    fn replay (error: CapturedError) -> Self {
+        match error {
+            CapturedError::Http { status } => TwitchError::HttpError(status),
+            CapturedError::MissingField { field } => TwitchError::MissingField(field),
+            // A network error can't be rebuilt, a GQL overload error retries the same way.
+            CapturedError::Transient { .. } => TwitchError::TwitchError("service unavailable".to_string()),
+            CapturedError::Twitch { message } | CapturedError::ClaimFailed { message } => TwitchError::TwitchError(message),
+            CapturedError::NotFound => TwitchError::TwitchError("not found".to_string()),
+            CapturedError::AlreadyClaimed => TwitchError::TwitchError("drop already claimed".to_string()),
+        }
+    }
+}
+
+macro_rules! captured_not_found {
+    ($($error:ident::$not_found:ident),* $(,)?) => {
+        $(impl Captured for $error {
+            fn capture (&self) -> CapturedError {
+                match self {
+                    $error::$not_found => CapturedError::NotFound,
+                    $error::TwitchError(e) => e.capture(),
+                }
+            }
+
+            fn replay (error: CapturedError) -> Self {
+                match error {
+                    CapturedError::NotFound => $error::$not_found,
+                    error => $error::TwitchError(TwitchError::replay(error)),
+                }
+            }
+        })*
+    };
+}
+
+captured_not_found!(
+    CampaignDetailsError::CampaignNotFound,
+    StreamInfoError::ChannelNotFound,
+    AvailableDropsError::ChannelNotFound,
+);
+
+impl Captured for ClaimDropError {
+    fn capture (&self) -> CapturedError {
+        match self {
+            ClaimDropError::FailedClaimDrops(message) => CapturedError::ClaimFailed { message: message.clone() },
+            ClaimDropError::DropAlreadyClaimed => CapturedError::AlreadyClaimed,
+            ClaimDropError::TwitchError(e) => e.capture(),
+        }
+    }
+
+    fn replay (error: CapturedError) -> Self {
+        match error {
+            CapturedError::ClaimFailed { message } => ClaimDropError::FailedClaimDrops(message),
+            CapturedError::AlreadyClaimed => ClaimDropError::DropAlreadyClaimed,
+            error => ClaimDropError::TwitchError(TwitchError::replay(error)),
+        }
+    }
+}
+
+/// Appends entries to a capture file, redacting the tokens of every recorded account.
+#[derive(Debug)]
+pub struct Recorder {
+    started: Instant,
+    file: Mutex<LineWriter<File>>,
+    secrets: Mutex<HashSet<String>>,
+}
+
+impl Recorder {
+    /// Starts a capture in `dir`, which must not hold one already.
+    pub fn create (dir: &Path) -> io::Result<Self> {
+        std::fs::create_dir_all(dir)?;
+        let file = File::create_new(dir.join(CAPTURE_FILE))?;
+        Ok(Recorder { started: Instant::now(), file: Mutex::new(LineWriter::new(file)), secrets: Mutex::default() })
+    }
+
+    /// Wraps `backend` so its calls are recorded, and records the account itself.
+    pub fn record (self: &Arc<Self>, backend: impl DropsBackend + 'static) -> Recording {
+        let token = backend.access_token();
+        if !token.is_empty() {
+            self.secrets.lock().unwrap_or_else(|e| e.into_inner()).insert(token.to_string());
+        }
+        self.write(backend.login(), Event::Account { user_id: backend.user_id().to_string() });
+        Recording { inner: Box::new(backend), recorder: self.clone() }
+    }
+
+    pub fn gql<T: Serialize, E: Captured> (&self, login: &str, operation: &str, request: Value, result: &Result<T, E>) {
+        let result = match result {
+            Ok(response) => Outcome::Ok(serde_json::to_value(response).unwrap_or_else(|e| json!({ "unserializable": e.to_string() }))),
+            Err(e) => Outcome::Err(e.capture()),
+        };
+        self.write(login, Event::Gql { operation: operation.to_string(), request, result });
+    }
+
+    pub fn pubsub (&self, login: &str, direction: Direction, frame: &str) {
+        let frame = serde_json::from_str(frame).unwrap_or_else(|_| Value::String(frame.to_string()));
+        self.write(login, Event::Pubsub { direction, frame });
+    }
+
+    fn write (&self, login: &str, event: Event) {
+        let entry = Entry {
+            at: chrono::Local::now().to_rfc3339(),
+            elapsed_ms: self.started.elapsed().as_millis() as u64,
+            login: login.to_string(),
+            event,
+        };
+        let mut line = serde_json::to_value(&entry).expect("capture entries are plain JSON");
+        redact(&mut line, &self.secrets.lock().unwrap_or_else(|e| e.into_inner()));
+        let mut file = self.file.lock().unwrap_or_else(|e| e.into_inner());
+        if let Err(e) = writeln!(file, "{line}") {
+            warn!("Failed to write to the capture: {e}");
+        }
+    }
+}
+
+/// Blanks the values of [`SECRET_KEYS`] and every occurrence of a known token.
+fn redact (value: &mut Value, secrets: &HashSet<String>) {
+    match value {
+        Value::Object(map) => {
+            for (key, value) in map.iter_mut() {
+                if value.is_string() && SECRET_KEYS.contains(&key.to_lowercase().as_str()) {
+                    *value = Value::String(REDACTED.to_string());
+                } else {
+                    redact(value, secrets);
+                }
+            }
+        },
+        Value::Array(values) => values.iter_mut().for_each(|value| redact(value, secrets)),
+        Value::String(text) => {
+            for secret in secrets {
+                if text.contains(secret.as_str()) {
+                    *text = text.replace(secret.as_str(), REDACTED);
+                }
+            }
+        },
+        _ => {},
+    }
+}
+
+/// A backend whose calls and results are written to a capture.
+#[derive(Debug)]
+pub struct Recording {
+    inner: Box<dyn DropsBackend>,
+    recorder: Arc<Recorder>,
+}
+
+impl Recording {
+    fn record<'a, T: Serialize + Send + 'a, E: Captured + Send + 'a> (&'a self, operation: &'static str, request: Value, call: BackendFuture<'a, T, E>) -> BackendFuture<'a, T, E> {
+        Box::pin(async move {
+            let result = call.await;
+            self.recorder.gql(self.login(), operation, request, &result);
+            result
+        })
+    }
+}
+
+impl DropsBackend for Recording {
+    fn login (&self) -> &str {
+        self.inner.login()
+    }
+
+    fn user_id (&self) -> &str {
+        self.inner.user_id()
+    }
+
+    fn access_token (&self) -> &str {
+        self.inner.access_token()
+    }
+
+    fn get_campaign (&self) -> BackendFuture<'_, Drops, TwitchError> {
+        self.record("get_campaign", json!({}), self.inner.get_campaign())
+    }
+
+    fn get_campaign_details<'a> (&'a self, campaign_id: &'a str) -> BackendFuture<'a, CampaignDetails, CampaignDetailsError> {
+        self.record("get_campaign_details", json!({ "campaign_id": campaign_id }), self.inner.get_campaign_details(campaign_id))
+    }
+
+    fn get_stream_info<'a> (&'a self, channel_login: &'a str) -> BackendFuture<'a, StreamInfo, StreamInfoError> {
+        self.record("get_stream_info", json!({ "channel_login": channel_login }), self.inner.get_stream_info(channel_login))
+    }
+
+    fn get_available_drops_for_channel<'a> (&'a self, channel_id: &'a str) -> BackendFuture<'a, AvailableDrops, AvailableDropsError> {
+        self.record("get_available_drops_for_channel", json!({ "channel_id": channel_id }), self.inner.get_available_drops_for_channel(channel_id))
+    }
+
+    fn get_current_drop_progress_on_channel<'a> (&'a self, channel_login: &'a str) -> BackendFuture<'a, CurrentDrop, TwitchError> {
+        self.record("get_current_drop_progress_on_channel", json!({ "channel_login": channel_login }), self.inner.get_current_drop_progress_on_channel(channel_login))
+    }
+
+    fn get_inventory (&self) -> BackendFuture<'_, GetInventory, TwitchError> {
+        self.record("get_inventory", json!({}), self.inner.get_inventory())
+    }
+
+    fn claim_drop<'a> (&'a self, drop_instance_id: &'a str) -> BackendFuture<'a, ClaimDrop, ClaimDropError> {
+        self.record("claim_drop", json!({ "drop_instance_id": drop_instance_id }), self.inner.claim_drop(drop_instance_id))
+    }
+
+    fn send_watch<'a> (&'a self, watch: &'a Watch) -> BackendFuture<'a, (), TwitchError> {
+        self.record("send_watch", json!(watch), self.inner.send_watch(watch))
+    }
+
+    fn get_game_directory<'a> (&'a self, query: &'a Value) -> BackendFuture<'a, Value, TwitchError> {
+        self.record("get_game_directory", query.clone(), self.inner.get_game_directory(query))
+    }
+}
+
+/// A capture loaded for `--replay`.
+#[derive(Debug)]
+pub struct Replay {
+    started: Instant,
+    accounts: Vec<(String, String)>,
+    /// Recorded results by login, operation and request, oldest first.
+    results: Mutex<HashMap<(String, String, String), VecDeque<Outcome>>>,
+    /// Received PubSub frames with the time they arrived at.
+    frames: Vec<(Duration, String)>,
+    handled_frames: AtomicUsize,
+}
+
+impl Replay {
+    pub fn load (dir: &Path) -> io::Result<Self> {
+        let file = File::open(dir.join(CAPTURE_FILE))?;
+        let mut accounts = Vec::new();
+        let mut results: HashMap<_, VecDeque<Outcome>> = HashMap::new();
+        let mut frames = Vec::new();
+        for (number, line) in BufReader::new(file).lines().enumerate() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let entry: Entry = serde_json::from_str(&line).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("line {}: {e}", number + 1)))?;
+            match entry.event {
+                Event::Account { user_id } => {
+                    if !accounts.iter().any(|(login, _)| *login == entry.login) {
+                        accounts.push((entry.login, user_id));
+                    }
+                },
+                Event::Gql { operation, request, result } => results.entry((entry.login, operation, request.to_string())).or_default().push_back(result),
+                Event::Pubsub { direction: Direction::Received, frame } => {
+                    let frame = match frame {
+                        Value::String(text) => text,
+                        frame => frame.to_string(),
+                    };
+                    frames.push((Duration::from_millis(entry.elapsed_ms), frame));
+                },
+                Event::Pubsub { direction: Direction::Sent, .. } => {},
+            }
+        }
+        Ok(Replay { started: Instant::now(), accounts, results: Mutex::new(results), frames, handled_frames: AtomicUsize::new(0) })
+    }
+
+    /// A backend for every account in the capture, in the order they were recorded.
+    pub fn backends (self: &Arc<Self>) -> Vec<ReplayBackend> {
+        self.accounts.iter().map(|(login, user_id)| ReplayBackend { login: login.clone(), user_id: user_id.clone(), replay: self.clone() }).collect()
+    }
+
+    /// The next received PubSub frame that wasn't handled yet, with when it should be handled to match the recording.
+    pub fn pending_frame (&self) -> Option<(Instant, &str)> {
+        let (elapsed, frame) = self.frames.get(self.handled_frames.load(Ordering::Relaxed))?;
+        Some((self.started + *elapsed, frame.as_str()))
+    }
+
+    /// Marks the frame from [`Replay::pending_frame`] as handled, a restarted PubSub task carries on after it.
+    pub fn frame_handled (&self) {
+        self.handled_frames.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// The next recorded result of a call. The last one is repeated once the recorded ones run out.
+    fn next (&self, login: &str, operation: &str, request: &Value) -> Option<Outcome> {
+        let mut results = self.results.lock().unwrap_or_else(|e| e.into_inner());
+        let queue = results.get_mut(&(login.to_string(), operation.to_string(), request.to_string()))?;
+        if queue.len() > 1 { queue.pop_front() } else { queue.front().cloned() }
+    }
+}
+
+/// Answers an account's calls from a [`Replay`].
+#[derive(Debug)]
+pub struct ReplayBackend {
+    login: String,
+    user_id: String,
+    replay: Arc<Replay>,
+}
+
+impl ReplayBackend {
+    fn answer<'a, T: DeserializeOwned + Send + 'a, E: Captured + Send + 'a> (&'a self, operation: &'static str, request: Value) -> BackendFuture<'a, T, E> {
+        let result = match self.replay.next(&self.login, operation, &request) {
+            Some(Outcome::Ok(response)) => serde_json::from_value(response).map_err(|e| E::replay(CapturedError::Twitch { message: format!("recorded {operation} response doesn't parse: {e}") })),
+            Some(Outcome::Err(error)) => Err(E::replay(error)),
+            None => Err(E::replay(CapturedError::Twitch { message: format!("{operation} {request} is not in the capture") })),
+        };
+        Box::pin(future::ready(result))
+    }
+}
+
+impl DropsBackend for ReplayBackend {
+    fn login (&self) -> &str {
+        &self.login
+    }
+
+    fn user_id (&self) -> &str {
+        &self.user_id
+    }
+
+    fn access_token (&self) -> &str {
+        REDACTED
+    }
+
+    fn get_campaign (&self) -> BackendFuture<'_, Drops, TwitchError> {
+        self.answer("get_campaign", json!({}))
+    }
+
+    fn get_campaign_details<'a> (&'a self, campaign_id: &'a str) -> BackendFuture<'a, CampaignDetails, CampaignDetailsError> {
+        self.answer("get_campaign_details", json!({ "campaign_id": campaign_id }))
+    }
+
+    fn get_stream_info<'a> (&'a self, channel_login: &'a str) -> BackendFuture<'a, StreamInfo, StreamInfoError> {
+        self.answer("get_stream_info", json!({ "channel_login": channel_login }))
+    }
+
+    fn get_available_drops_for_channel<'a> (&'a self, channel_id: &'a str) -> BackendFuture<'a, AvailableDrops, AvailableDropsError> {
+        self.answer("get_available_drops_for_channel", json!({ "channel_id": channel_id }))
+    }
+
+    fn get_current_drop_progress_on_channel<'a> (&'a self, channel_login: &'a str) -> BackendFuture<'a, CurrentDrop, TwitchError> {
+        self.answer("get_current_drop_progress_on_channel", json!({ "channel_login": channel_login }))
+    }
+
+    fn get_inventory (&self) -> BackendFuture<'_, GetInventory, TwitchError> {
+        self.answer("get_inventory", json!({}))
+    }
+
+    fn claim_drop<'a> (&'a self, drop_instance_id: &'a str) -> BackendFuture<'a, ClaimDrop, ClaimDropError> {
+        self.answer("claim_drop", json!({ "drop_instance_id": drop_instance_id }))
+    }
+
+    fn send_watch<'a> (&'a self, watch: &'a Watch) -> BackendFuture<'a, (), TwitchError> {
+        self.answer("send_watch", json!(watch))
+    }
+
+    fn get_game_directory<'a> (&'a self, query: &'a Value) -> BackendFuture<'a, Value, TwitchError> {
+        self.answer("get_game_directory", query.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn capture_dir (name: &str) -> std::path::PathBuf {
+        let dir = std::env::temp_dir().join(format!("dropsentry-capture-{name}-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        dir
+    }
+
+    #[test]
+    fn replays_results_in_recorded_order_and_repeats_the_last () {
+        let dir = capture_dir("order");
+        let recorder = Recorder::create(&dir).unwrap();
+        let request = json!({ "channel_login": "streamer" });
+        recorder.gql::<Value, TwitchError>("viewer", "get_current_drop_progress_on_channel", request.clone(), &Ok(json!({ "currentMinutesWatched": 1 })));
+        recorder.gql::<Value, TwitchError>("viewer", "get_current_drop_progress_on_channel", request.clone(), &Err(TwitchError::HttpError(503)));
+        recorder.gql::<Value, TwitchError>("viewer", "get_current_drop_progress_on_channel", request.clone(), &Ok(json!({ "currentMinutesWatched": 2 })));
+        drop(recorder);
+
+        let replay = Replay::load(&dir).unwrap();
+        let next = || replay.next("viewer", "get_current_drop_progress_on_channel", &request);
+        assert!(matches!(next(), Some(Outcome::Ok(v)) if v["currentMinutesWatched"] == 1));
+        assert!(matches!(next(), Some(Outcome::Err(CapturedError::Http { status: 503 }))));
+        assert!(matches!(next(), Some(Outcome::Ok(v)) if v["currentMinutesWatched"] == 2));
+        assert!(matches!(next(), Some(Outcome::Ok(v)) if v["currentMinutesWatched"] == 2));
+        assert!(replay.next("viewer", "get_current_drop_progress_on_channel", &json!({ "channel_login": "other" })).is_none());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn redacts_tokens () {
+        let dir = capture_dir("redact");
+        let recorder = Recorder::create(&dir).unwrap();
+        recorder.secrets.lock().unwrap().insert("s3cret".to_string());
+        recorder.pubsub("viewer", Direction::Sent, r#"{"type":"LISTEN","data":{"topics":["video-playback-by-id.1"],"auth_token":"other"}}"#);
+        recorder.gql::<Value, TwitchError>("viewer", "get_inventory", json!({}), &Ok(json!({ "note": "OAuth s3cret" })));
+        drop(recorder);
+
+        let capture = std::fs::read_to_string(dir.join(CAPTURE_FILE)).unwrap();
+        assert!(!capture.contains("s3cret") && !capture.contains("\"other\""), "tokens leaked into {capture}");
+        assert_eq!(capture.matches(REDACTED).count(), 2);
+        assert!(Recorder::create(&dir).is_err(), "an existing capture must not be overwritten");
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn replayed_errors_classify_like_the_recorded_ones () {
+        let errors = [
+            TwitchError::HttpError(429),
+            TwitchError::MissingField("data".to_string()),
+            TwitchError::TwitchError("service unavailable".to_string()),
+            TwitchError::JsonError(serde::de::Error::custom("EOF")),
+        ];
+        for error in errors {
+            let replayed = TwitchError::replay(error.capture());
+            assert_eq!(replayed.class(), error.class(), "{error} replayed as {replayed}");
+        }
+        assert!(matches!(ClaimDropError::replay(ClaimDropError::DropAlreadyClaimed.capture()), ClaimDropError::DropAlreadyClaimed));
+        assert!(matches!(StreamInfoError::replay(StreamInfoError::ChannelNotFound.capture()), StreamInfoError::ChannelNotFound));
+    }
+}
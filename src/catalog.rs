@@ -0,0 +1,99 @@
+use std::{collections::{HashMap, HashSet}, sync::Arc};
+
+use chrono::{DateTime, Utc};
+use futures_util::{StreamExt, stream};
+use tokio::sync::Mutex;
+use tracing::{debug, error};
+use twitch_gql_rs::structs::{CampaignDetails, CampaignDetailsBenefits, Channels, DropCampaigns, TimeBasedDropsCampaignDetails};
+
+use crate::{backend::DropsBackend, error::{RetryPolicy, retry_backup}};
+
+/// Campaign details fetched at the same time.
+const DETAILS_CONCURRENCY: usize = 5;
+
+#[derive(Debug)]
+struct CatalogEntry {
+    version: String,
+    details: Arc<CampaignDetails>,
+}
+
+/// Details of the campaigns being farmed, fetched once and shared by discovery, channel ranking and
+/// the pending-drop bookkeeping. A campaign is fetched again only when its status or dates change.
+#[derive(Debug, Default)]
+pub struct CampaignCatalog {
+    entries: Mutex<HashMap<String, CatalogEntry>>,
+}
+
+/// Changes whenever Twitch reschedules, ends or otherwise updates a campaign.
+fn version (campaign: &DropCampaigns) -> String {
+    format!("{}|{}|{}", campaign.status, campaign.startAt, campaign.endAt)
+}
+
+impl CampaignCatalog {
+    /// Brings the catalog in line with `campaigns`: fetches details for new and changed campaigns
+    /// concurrently, and forgets campaigns that are no longer listed.
+    pub async fn sync<'a> (&self, client: &dyn DropsBackend, campaigns: impl IntoIterator<Item = &'a DropCampaigns>) {
+        let campaigns: Vec<&DropCampaigns> = campaigns.into_iter().collect();
+        let stale: Vec<(String, String)> = {
+            let entries = self.entries.lock().await;
+            campaigns.iter()
+                .filter(|c| entries.get(&c.id).is_none_or(|entry| entry.version != version(c)))
+                .map(|c| (c.id.clone(), version(c)))
+                .collect()
+        };
+        debug!("Fetching details for {} of {} campaigns", stale.len(), campaigns.len());
+
+        let fetched: Vec<(String, CatalogEntry)> = stream::iter(stale)
+            .map(|(id, version)| async move {
+                match retry_backup(&RetryPolicy::GQL, || client.get_campaign_details(&id)).await {
+                    Ok(details) => Some((id, CatalogEntry { version, details: Arc::new(details) })),
+                    Err(e) => {
+                        error!(campaign = %id, "Failed to fetch campaign details for {id}: {e}");
+                        None
+                    }
+                }
+            })
+            .buffer_unordered(DETAILS_CONCURRENCY)
+            .filter_map(|entry| async move { entry })
+            .collect().await;
+
+        let listed: HashSet<&str> = campaigns.iter().map(|c| c.id.as_str()).collect();
+        let mut entries = self.entries.lock().await;
+        entries.extend(fetched);
+        entries.retain(|id, _| listed.contains(id.as_str()));
+    }
+
+    pub async fn details (&self, campaign_id: &str) -> Option<Arc<CampaignDetails>> {
+        self.entries.lock().await.get(campaign_id).map(|entry| entry.details.clone())
+    }
+
+    /// The channels a campaign is restricted to, or `None` if any channel streaming the game counts.
+    pub async fn allowed_channels (&self, campaign_id: &str) -> Option<Vec<Channels>> {
+        self.details(campaign_id).await?.allow.channels.clone()
+    }
+
+    /// Allowed channel ids of every restricted campaign, by campaign id.
+    pub async fn allow_lists (&self) -> HashMap<String, HashSet<String>> {
+        self.entries.lock().await.iter()
+            .filter_map(|(id, entry)| {
+                let channels = entry.details.allow.channels.as_ref()?;
+                Some((id.clone(), channels.iter().map(|c| c.id.clone()).collect()))
+            })
+            .collect()
+    }
+
+    pub async fn drops (&self, campaign_id: &str) -> Vec<TimeBasedDropsCampaignDetails> {
+        self.details(campaign_id).await.map(|details| details.timeBasedDrops.clone()).unwrap_or_default()
+    }
+
+    /// When the campaign ends.
+    pub async fn deadline (&self, campaign_id: &str) -> Option<DateTime<Utc>> {
+        let details = self.details(campaign_id).await?;
+        DateTime::parse_from_rfc3339(&details.endAt).ok().map(|end| end.with_timezone(&Utc))
+    }
+
+    /// Rewards of every drop in the campaign.
+    pub async fn benefits (&self, campaign_id: &str) -> Vec<CampaignDetailsBenefits> {
+        self.drops(campaign_id).await.into_iter().flat_map(|drop| drop.benefitEdges.into_iter().map(|edge| edge.benefit)).collect()
+    }
+}
@@ -0,0 +1,68 @@
+use std::{collections::HashMap, time::{Duration, Instant}};
+
+use serde::Serialize;
+
+use crate::config::ChannelCacheConfig;
+
+/// Expired entries are only swept once the cache grows past this many channels.
+const PRUNE_ABOVE: usize = 2000;
+
+/// What discovery last learned about a channel.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ChannelStatus {
+    pub live: bool,
+    /// Game the stream is in, when live.
+    pub game: Option<String>,
+    pub drops_enabled: bool,
+    pub viewers: u64,
+}
+
+#[derive(Debug)]
+struct Entry {
+    status: ChannelStatus,
+    expires: Instant,
+}
+
+/// Channel eligibility checks by channel id, so discovery only asks Twitch about channels
+/// whose last answer has gone stale. PubSub events update or invalidate entries in between.
+#[derive(Debug, Default)]
+pub struct ChannelCache {
+    config: ChannelCacheConfig,
+    entries: HashMap<String, Entry>,
+}
+
+impl ChannelCache {
+    pub fn configure (&mut self, config: ChannelCacheConfig) {
+        self.config = config;
+        self.entries.clear();
+    }
+
+    /// The cached status, unless it has expired.
+    pub fn get (&self, channel_id: &str) -> Option<ChannelStatus> {
+        self.entries.get(channel_id).filter(|entry| entry.expires > Instant::now()).map(|entry| entry.status.clone())
+    }
+
+    pub fn insert (&mut self, channel_id: &str, status: ChannelStatus) {
+        let ttl = match (status.live, status.drops_enabled) {
+            (true, true) => self.config.live_ttl_secs,
+            (true, false) => self.config.no_drops_ttl_secs,
+            (false, _) => self.config.offline_ttl_secs,
+        };
+        let now = Instant::now();
+        if self.entries.len() >= PRUNE_ABOVE {
+            self.entries.retain(|_, entry| entry.expires > now);
+        }
+        self.entries.insert(channel_id.to_string(), Entry { status, expires: now + Duration::from_secs(ttl) });
+    }
+
+    /// Updates the viewer count of a cached channel without extending its lifetime.
+    pub fn set_viewers (&mut self, channel_id: &str, viewers: u64) {
+        if let Some(entry) = self.entries.get_mut(channel_id) {
+            entry.status.viewers = viewers;
+        }
+    }
+
+    pub fn invalidate (&mut self, channel_id: &str) {
+        self.entries.remove(channel_id);
+    }
+}
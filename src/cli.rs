@@ -0,0 +1,103 @@
+use std::{fmt, path::PathBuf, sync::atomic::{AtomicBool, Ordering}};
+
+use crate::error::AppError;
+
+/// Exit code for configuration problems, including input that `--headless` can't prompt for.
+pub const EXIT_CONFIG: u8 = 2;
+/// Exit code for failures while running.
+pub const EXIT_RUNTIME: u8 = 1;
+
+static HEADLESS: AtomicBool = AtomicBool::new(false);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Command {
+    Run,
+    VaultRekey,
+    AccountsList,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogFormat {
+    Json,
+    Logfmt,
+}
+
+#[derive(Debug, Clone)]
+pub struct Cli {
+    pub debug: bool,
+    pub tui: bool,
+    pub headless: bool,
+    pub log_format: LogFormat,
+    /// Capture directory for `--record <dir>`.
+    pub record: Option<PathBuf>,
+    /// Capture directory for `--replay <dir>`.
+    pub replay: Option<PathBuf>,
+    pub command: Command,
+}
+
+impl Cli {
+    pub fn parse () -> Result<Self, AppError> {
+        Self::parse_from(std::env::args().skip(1))
+    }
+
+    fn parse_from (mut args: impl Iterator<Item = String>) -> Result<Self, AppError> {
+        let mut debug = false;
+        let mut tui = false;
+        let mut headless = false;
+        let mut log_format = LogFormat::Json;
+        let mut record = None;
+        let mut replay = None;
+        let mut words = Vec::new();
+
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--debug" => debug = true,
+                "--tui" => tui = true,
+                "--headless" => headless = true,
+                "--log-format=json" => log_format = LogFormat::Json,
+                "--log-format=logfmt" => log_format = LogFormat::Logfmt,
+                "--record" => record = Some(flag_value(&arg, args.next())?),
+                "--replay" => replay = Some(flag_value(&arg, args.next())?),
+                flag if flag.starts_with("--") => return Err(AppError::Config(format!("Unknown option: {flag}"))),
+                _ => words.push(arg),
+            }
+        }
+
+        if headless && tui {
+            return Err(AppError::Config("--headless and --tui can't be used together".to_string()));
+        }
+        if record.is_some() && replay.is_some() {
+            return Err(AppError::Config("--record and --replay can't be used together".to_string()));
+        }
+
+        let command = match words.iter().map(String::as_str).collect::<Vec<_>>().as_slice() {
+            [] => Command::Run,
+            ["vault", "rekey"] => Command::VaultRekey,
+            ["accounts", "list"] => Command::AccountsList,
+            other => return Err(AppError::Config(format!("Unknown command: {}", other.join(" ")))),
+        };
+
+        HEADLESS.store(headless, Ordering::Relaxed);
+        Ok(Cli { debug, tui, headless, log_format, record, replay, command })
+    }
+}
+
+fn flag_value (flag: &str, value: Option<String>) -> Result<PathBuf, AppError> {
+    value.filter(|value| !value.starts_with("--")).map(PathBuf::from).ok_or_else(|| AppError::Config(format!("{flag} needs a directory")))
+}
+
+pub fn config_error (e: impl fmt::Display) -> AppError {
+    AppError::Config(e.to_string())
+}
+
+pub fn is_headless () -> bool {
+    HEADLESS.load(Ordering::Relaxed)
+}
+
+/// Fails with [`AppError::Config`] instead of prompting when running with `--headless`.
+pub fn require_interactive (what: &str) -> Result<(), AppError> {
+    if is_headless() {
+        return Err(AppError::Config(format!("{what} needs interactive input, which isn't available with --headless")));
+    }
+    Ok(())
+}
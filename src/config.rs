@@ -1,18 +1,320 @@
-use std::{collections::VecDeque, env, error::Error, path::Path};
+use std::{collections::{HashMap, VecDeque}, env, path::{Path, PathBuf}};
 
 use auto_launch::AutoLaunchBuilder;
 use serde::{Deserialize, Serialize};
 use tokio::{fs::{self, File}, io::{AsyncBufReadExt, BufReader, Lines}};
 
+use crate::{error::AppError, proxy::{parse_proxy, redact}};
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Config {
     games_path: String,
     autostart: bool,
     proxies_path: String,
     pub discord_webhook_url: String,
+    #[serde(default)]
+    pub vault: VaultConfig,
+    /// Pins an account (by login) to a proxy, bypassing the sticky assignment in `data/proxy_map.json`.
+    #[serde(default)]
+    pub proxy_overrides: HashMap<String, String>,
+    #[serde(default)]
+    pub proxy_health: ProxyHealthConfig,
+    #[serde(default)]
+    pub api: ApiConfig,
+    #[serde(default)]
+    pub log: LogConfig,
+    #[serde(default)]
+    pub rate_limit: RateLimitConfig,
+    #[serde(default)]
+    pub channel_cache: ChannelCacheConfig,
+    #[serde(default)]
+    pub discovery: DiscoveryConfig,
+    #[serde(default)]
+    pub switching: SwitchConfig,
+    #[serde(default)]
+    pub intervals: IntervalsConfig,
+    #[serde(default)]
+    pub endpoints: EndpointsConfig,
+}
+
+/// How channels are found for campaigns that aren't restricted to a list of channels.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DiscoveryConfig {
+    #[serde(flatten)]
+    pub defaults: DiscoverySettings,
+    /// Overrides by game name as written in `games.txt`. Fields left out fall back to the defaults.
+    pub games: HashMap<String, DiscoveryOverride>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DiscoverySettings {
+    /// Directory pages read per game.
+    pub max_pages: u32,
+    pub page_size: u32,
+    /// Broadcaster languages such as `en` or `de`. Empty allows every language.
+    pub languages: Vec<String>,
+    /// Only list streams Twitch reports as having drops enabled.
+    pub drops_enabled_only: bool,
+    /// A stream needs at least one of these tags. Empty allows any.
+    pub required_tags: Vec<String>,
+    pub excluded_tags: Vec<String>,
+    /// Weight of the viewer count in the ranking.
+    pub viewer_weight: f64,
+    /// Weight of how long the stream has been live, when Twitch reports it.
+    pub uptime_weight: f64,
+}
+
+impl Default for DiscoverySettings {
+    fn default () -> Self {
+        DiscoverySettings {
+            max_pages: 3,
+            page_size: 30,
+            languages: Vec::new(),
+            drops_enabled_only: true,
+            required_tags: Vec::new(),
+            excluded_tags: Vec::new(),
+            viewer_weight: 1.0,
+            uptime_weight: 0.0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DiscoveryOverride {
+    pub max_pages: Option<u32>,
+    pub page_size: Option<u32>,
+    pub languages: Option<Vec<String>>,
+    pub drops_enabled_only: Option<bool>,
+    pub required_tags: Option<Vec<String>>,
+    pub excluded_tags: Option<Vec<String>>,
+    pub viewer_weight: Option<f64>,
+    pub uptime_weight: Option<f64>,
+}
+
+impl DiscoveryConfig {
+    /// The settings for `game`, with its override (matched case-insensitively) applied.
+    pub fn settings_for (&self, game: &str) -> DiscoverySettings {
+        let mut settings = self.defaults.clone();
+        let Some(game) = self.games.iter().find(|(name, _)| name.trim().eq_ignore_ascii_case(game.trim())).map(|(_, game)| game.clone()) else {
+            return settings;
+        };
+        if let Some(v) = game.max_pages { settings.max_pages = v; }
+        if let Some(v) = game.page_size { settings.page_size = v; }
+        if let Some(v) = game.languages { settings.languages = v; }
+        if let Some(v) = game.drops_enabled_only { settings.drops_enabled_only = v; }
+        if let Some(v) = game.required_tags { settings.required_tags = v; }
+        if let Some(v) = game.excluded_tags { settings.excluded_tags = v; }
+        if let Some(v) = game.viewer_weight { settings.viewer_weight = v; }
+        if let Some(v) = game.uptime_weight { settings.uptime_weight = v; }
+        settings
+    }
+}
+
+/// How often each account polls Twitch while farming.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct IntervalsConfig {
+    /// Drop progress checks on the channel being watched.
+    pub progress_secs: u64,
+    /// Inventory sweeps that claim every finished drop.
+    pub claim_sweep_secs: u64,
+}
+
+impl Default for IntervalsConfig {
+    fn default () -> Self {
+        IntervalsConfig {
+            progress_secs: 30,
+            claim_sweep_secs: 90,
+        }
+    }
+}
+
+/// Twitch endpoints, only worth changing to point the farm at a local test server.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct EndpointsConfig {
+    /// GQL endpoint of the requests DropSentry builds itself, such as the directory search.
+    /// twitch-gql-rs always posts to `gql.twitch.tv`, route those through a proxy instead.
+    pub gql_url: String,
+    /// PubSub WebSocket, `wss://` or `ws://`.
+    pub pubsub_url: String,
+}
+
+impl Default for EndpointsConfig {
+    fn default () -> Self {
+        EndpointsConfig {
+            gql_url: "https://gql.twitch.tv/gql".to_string(),
+            pubsub_url: "wss://pubsub-edge.twitch.tv/v1".to_string(),
+        }
+    }
+}
+
+/// When the farm may move from the channel being watched to a better ranked one.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SwitchConfig {
+    /// Time on a channel before a better candidate may take over.
+    pub min_dwell_secs: u64,
+    /// How much higher a candidate's priority must be to take over.
+    pub priority_margin: u32,
+    /// Longest wait for every account's minute heartbeat before an upgrade goes ahead anyway.
+    pub heartbeat_wait_secs: u64,
+}
+
+impl Default for SwitchConfig {
+    fn default () -> Self {
+        SwitchConfig {
+            min_dwell_secs: 600,
+            priority_margin: 10,
+            heartbeat_wait_secs: 90,
+        }
+    }
+}
+
+/// How long a channel eligibility check is trusted before discovery asks Twitch again.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ChannelCacheConfig {
+    /// Live with drops enabled.
+    pub live_ttl_secs: u64,
+    /// Live, but without drops for us.
+    pub no_drops_ttl_secs: u64,
+    pub offline_ttl_secs: u64,
+}
+
+impl Default for ChannelCacheConfig {
+    fn default () -> Self {
+        ChannelCacheConfig {
+            live_ttl_secs: 300,
+            no_drops_ttl_secs: 600,
+            offline_ttl_secs: 120,
+        }
+    }
+}
+
+/// GQL request budget per egress (each proxy, plus the direct connection), shared by the accounts behind it.
+/// When Twitch rate limits a request, that budget is halved and then recovers gradually.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RateLimitConfig {
+    pub enabled: bool,
+    /// Stream info, available drops, game directory and campaign lookups.
+    pub discovery_per_minute: u32,
+    /// Watch heartbeats, drop progress and inventory polls.
+    pub progress_per_minute: u32,
+    pub claim_per_minute: u32,
+    /// Requests that may go out back to back before the rate applies.
+    pub burst: u32,
 }
 
-async fn open_lines (path: &str) -> Result<Lines<BufReader<File>>, Box<dyn Error>> {
+impl Default for RateLimitConfig {
+    fn default () -> Self {
+        RateLimitConfig {
+            enabled: true,
+            discovery_per_minute: 120,
+            progress_per_minute: 120,
+            claim_per_minute: 30,
+            burst: 10,
+        }
+    }
+}
+
+/// Where logs are written and how they are rotated.
+/// `filters` takes per-module levels such as `stream=debug,proxy=warn`; `--debug` raises `level` to `debug`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct LogConfig {
+    pub dir: String,
+    pub level: String,
+    pub filters: String,
+    pub rotation: LogRotation,
+    /// Size limit for `"rotation": "size"`.
+    pub max_size_mb: u64,
+    /// Rotated files kept next to the active one.
+    pub keep: usize,
+    /// Also write each account's events to `<dir>/accounts/<login>.log`.
+    pub per_account: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogRotation {
+    Never,
+    Hourly,
+    Daily,
+    Size,
+}
+
+impl Default for LogConfig {
+    fn default () -> Self {
+        LogConfig {
+            dir: ".".to_string(),
+            level: "info".to_string(),
+            filters: String::new(),
+            rotation: LogRotation::Never,
+            max_size_mb: 10,
+            keep: 7,
+            per_account: false,
+        }
+    }
+}
+
+/// Opt-in status and control API. It only ever listens on 127.0.0.1.
+/// It won't start without a `token`, and every request must send `Authorization: Bearer <token>`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ApiConfig {
+    pub enabled: bool,
+    pub port: u16,
+    pub token: String,
+}
+
+impl Default for ApiConfig {
+    fn default () -> Self {
+        ApiConfig { enabled: false, port: 8787, token: String::new() }
+    }
+}
+
+/// How proxies from `proxies.txt` are health-checked. Every check measures the TCP connect time
+/// and then sends a request to `probe_url` through the proxy; any HTTP response counts as a pass.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ProxyHealthConfig {
+    pub probe_url: String,
+    pub interval_secs: u64,
+    pub timeout_secs: u64,
+    /// Consecutive failed checks before a proxy is marked dead.
+    pub dead_after: u32,
+    /// Let accounts fall back to a direct connection when every proxy is dead. Off by default,
+    /// accounts stay on their dead proxy instead of exposing their token on the host IP.
+    pub direct_fallback: bool,
+}
+
+impl Default for ProxyHealthConfig {
+    fn default () -> Self {
+        ProxyHealthConfig {
+            probe_url: "https://gql.twitch.tv/gql".to_string(),
+            interval_secs: 300,
+            timeout_secs: 10,
+            dead_after: 3,
+            direct_fallback: false,
+        }
+    }
+}
+
+/// Optional encryption of saved sessions. The passphrase comes from `DROPSENTRY_VAULT_PASSPHRASE`,
+/// a key file (`key_file` or `DROPSENTRY_VAULT_KEY_FILE`), or an interactive prompt.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct VaultConfig {
+    pub enabled: bool,
+    #[serde(default)]
+    pub key_file: String,
+}
+
+async fn open_lines (path: &str) -> Result<Lines<BufReader<File>>, AppError> {
     let path = Path::new(&path);
 
     let file = match fs::File::open(path).await {
@@ -27,6 +329,10 @@ async fn open_lines (path: &str) -> Result<Lines<BufReader<File>>, Box<dyn Error
     Ok(reader)
 }
 
+fn autostart_error (e: auto_launch::Error) -> AppError {
+    AppError::Config(format!("Failed to configure autostart: {e}"))
+}
+
 fn print_section(title: &str) {
     println!("\n\x1b[90m{}\x1b[0m", "─".repeat(50));
     println!("\x1b[90m{}\x1b[0m", title.to_uppercase());
@@ -35,32 +341,34 @@ fn print_section(title: &str) {
 const ASCII_BANNER: &str = include_str!("../assets/ascii.txt");
 
 impl Config {
-    pub async fn first_time_setup (&mut self) -> Result<(), Box<dyn Error>> {
+    pub async fn first_time_setup (&mut self) -> Result<(), AppError> {
         print_section(ASCII_BANNER);
         print_section("Autostart");
         let autostart_question = dialoguer::Confirm::new().with_prompt("▸ Start automatically on login?").default(false).interact()?;
+        print_section("Session vault");
+        let vault_question = dialoguer::Confirm::new().with_prompt("▸ Encrypt saved sessions with a passphrase?").default(false).interact()?;
         print_section("Discord Webhook");
         let webhook_url: String = dialoguer::Input::new().with_prompt("▸ Webhook URL (optional, Enter to skip)").allow_empty(true).interact_text()?;
         
         //proxies list input
         print_section("Proxies");
-        let mut proxies_vec = Vec::new();
+        let mut proxies_vec: Vec<String> = Vec::new();
         loop {
             let proxy: String = dialoguer::Input::new().with_prompt("▸ Add proxy (Enter to finish)").allow_empty(true).validate_with(|input: &String| {
                 let trimmed = input.trim();
                 if trimmed.is_empty() {
                     return Ok(());
                 }
-                if proxies_vec.contains(&trimmed.to_string()) {
-                    Err("This proxy is already in the list.")
-                } else {
-                    Ok(())
+                match parse_proxy(trimmed) {
+                    Ok(proxy) if proxies_vec.contains(&proxy) => Err("This proxy is already in the list.".to_string()),
+                    Ok(_) => Ok(()),
+                    Err(e) => Err(e),
                 }
             }).interact_text()?;
             if proxy.trim().is_empty() {
                 break;
             }
-            proxies_vec.push(proxy);
+            proxies_vec.push(parse_proxy(&proxy).map_err(AppError::Proxy)?);
         }
 
         //games list input
@@ -96,14 +404,15 @@ impl Config {
         self.save_games_list(&games_vec).await?;
         self.save_proxies_list(&proxies_vec).await?;
         self.autostart = autostart_question;
+        self.vault.enabled = vault_question;
         self.discord_webhook_url = webhook_url;
         Ok(())
     }
 
-    pub fn configure_autostart (&self) -> Result<(), Box<dyn Error>> {
+    pub fn configure_autostart (&self) -> Result<(), AppError> {
         let app_path = {
             let path = env::current_exe()?;
-            path.to_str().ok_or("Unable to convert executable path to string")?.to_string()
+            path.to_str().ok_or_else(|| AppError::Config("Unable to convert executable path to string".to_string()))?.to_string()
         };
         let auto = AutoLaunchBuilder::new()
             .set_app_name("TwitchDropSentry")
@@ -111,19 +420,20 @@ impl Config {
             .set_macos_launch_mode(auto_launch::MacOSLaunchMode::LaunchAgent)
             .set_linux_launch_mode(auto_launch::LinuxLaunchMode::XdgAutostart)
             .set_windows_enable_mode(auto_launch::WindowsEnableMode::Dynamic)
-            .build()?;
+            .build()
+            .map_err(autostart_error)?;
 
         if self.autostart {
-            if !auto.is_enabled()? {
-                auto.enable()?;
+            if !auto.is_enabled().map_err(autostart_error)? {
+                auto.enable().map_err(autostart_error)?;
             }
         } else {
-            auto.disable()?;
+            auto.disable().map_err(autostart_error)?;
         }
         Ok(())
     }
 
-    pub async fn new () -> Result<Self, Box<dyn Error>> {
+    pub async fn new () -> Result<Self, AppError> {
         let lists_path = Path::new("lists");
         if !lists_path.exists() {
             fs::create_dir(&lists_path).await?;
@@ -139,23 +449,41 @@ impl Config {
             fs::write(&proxies_path, "".as_bytes()).await?;
         }
 
+        for list in ["preferred.txt", "blocked.txt"] {
+            let list_path = lists_path.join(list);
+            if !list_path.exists() {
+                fs::write(&list_path, "".as_bytes()).await?;
+            }
+        }
+
         Ok(
             Config { 
                 games_path: "./lists/games.txt".to_string(), 
                 autostart: false, 
                 proxies_path: "./lists/proxies.txt".to_string(),
-                discord_webhook_url: String::new()
+                discord_webhook_url: String::new(),
+                vault: VaultConfig::default(),
+                proxy_overrides: HashMap::new(),
+                proxy_health: ProxyHealthConfig::default(),
+                api: ApiConfig::default(),
+                log: LogConfig::default(),
+                rate_limit: RateLimitConfig::default(),
+                channel_cache: ChannelCacheConfig::default(),
+                discovery: DiscoveryConfig::default(),
+                switching: SwitchConfig::default(),
+                intervals: IntervalsConfig::default(),
+                endpoints: EndpointsConfig::default(),
             }
         )
     }
 
-    pub async fn save (&self, path: &Path) -> Result<(), Box<dyn Error>> {
+    pub async fn save (&self, path: &Path) -> Result<(), AppError> {
         let to_write = serde_json::to_string_pretty(&self)?;
         fs::write(&path, to_write).await?;
         Ok(())
     }
 
-    pub async fn load (path: &Path) -> Result<Self, Box<dyn Error>> {
+    pub async fn load (path: &Path) -> Result<Self, AppError> {
         let config: Config = {
             let read = fs::read_to_string(&path).await?;
             serde_json::from_str(&read)?
@@ -163,33 +491,61 @@ impl Config {
         Ok(config)
     }
 
-    async fn save_games_list (&self, games: &VecDeque<String>) -> Result<(), Box<dyn Error>> {
+    async fn save_games_list (&self, games: &VecDeque<String>) -> Result<(), AppError> {
         let to_write = games.iter().map(|g| format!("{}\n", g)).collect::<String>();
         fs::write(&self.games_path, to_write).await?;
         Ok(())
     }
     
-    async fn save_proxies_list (&self, proxies: &Vec<String>) -> Result<(), Box<dyn Error>> {
+    async fn save_proxies_list (&self, proxies: &[String]) -> Result<(), AppError> {
         let to_write = proxies.iter().map(|p| format!("{}\n", p)).collect::<String>();
         fs::write(&self.proxies_path, to_write).await?;
         Ok(())
     }
 
-    pub async fn load_proxies_list (&self) -> Result<Vec<String>, Box<dyn Error>> {
+    /// Reads `proxies.txt`, normalizing every entry with [`parse_proxy`]. Blank lines and ` #` comments
+    /// are skipped, duplicates are dropped, and invalid entries fail the load with their line numbers.
+    pub async fn load_proxies_list (&self) -> Result<Vec<String>, AppError> {
         let mut reader = open_lines(&self.proxies_path).await?;
         
-        let mut proxies = Vec::new();
+        let mut proxies: Vec<String> = Vec::new();
+        let mut errors = Vec::new();
+        let mut line_no = 0;
 
         while let Some(line) = reader.next_line().await? {
-            let trimmed = line.trim();
-            if !trimmed.is_empty() {
-                proxies.push(line);
+            line_no += 1;
+            let trimmed = line.trim_start_matches("\u{feff}");
+            let trimmed = trimmed.split_once(" #").map_or(trimmed, |(entry, _)| entry).trim();
+            if trimmed.is_empty() || trimmed.starts_with('#') {
+                continue;
             }
+            match parse_proxy(trimmed) {
+                Ok(proxy) if proxies.contains(&proxy) => tracing::warn!("{}:{line_no}: duplicate proxy {} skipped", self.proxies_path, redact(&proxy)),
+                Ok(proxy) => proxies.push(proxy),
+                Err(e) => errors.push(format!("{}:{line_no}: {e}", self.proxies_path)),
+            }
+        }
+
+        if !errors.is_empty() {
+            return Err(AppError::Proxy(format!("Invalid proxy entries:\n{}", errors.join("\n"))));
         }
         Ok(proxies)
     }
 
-    pub async fn loaded_games (&self) -> Result<VecDeque<String>, Box<dyn Error>> {
+    /// `proxy_overrides` with every proxy normalized like the entries in `proxies.txt`.
+    pub fn normalized_proxy_overrides (&self) -> Result<HashMap<String, String>, AppError> {
+        self.proxy_overrides.iter()
+            .map(|(login, proxy)| parse_proxy(proxy).map(|proxy| (login.clone(), proxy)).map_err(|e| AppError::Proxy(format!("proxy_overrides.{login}: {e}"))))
+            .collect()
+    }
+
+    /// `preferred.txt` and `blocked.txt`, kept next to `games.txt`.
+    pub fn streamer_list_paths (&self) -> (PathBuf, PathBuf) {
+        let dir = Path::new(&self.games_path).parent().unwrap_or(Path::new("."));
+        (dir.join("preferred.txt"), dir.join("blocked.txt"))
+    }
+
+    pub async fn loaded_games (&self) -> Result<VecDeque<String>, AppError> {
     let mut reader = open_lines(&self.games_path).await?;
 
         let mut games = VecDeque::new();
@@ -0,0 +1,119 @@
+use chrono::{DateTime, Utc};
+use serde_json::{Value, json};
+use twitch_gql_rs::{error::TwitchError, structs::GameDirectory};
+
+use crate::{backend::DropsBackend, config::DiscoverySettings, error::{RetryPolicy, retry_backup}};
+
+const DIRECTORY_QUERY_HASH: &str = "cb5dc816e139dcb8a118f14b4b677d59abc224a4b016c4bc2bb00a47fe0ddec4";
+/// Largest rank bonus, kept below the gap between two games' priorities.
+pub const MAX_RANK_BONUS: u32 = 9;
+
+/// A live stream from a game's directory, with its discovery score in `0.0..=1.0`.
+#[derive(Debug, Clone)]
+pub struct RankedStream {
+    pub stream: GameDirectory,
+    pub score: f64,
+}
+
+impl RankedStream {
+    /// The score as a small priority bonus, so higher ranked streams are watched first within a game.
+    pub fn bonus (&self) -> u32 {
+        (self.score * MAX_RANK_BONUS as f64).round() as u32
+    }
+}
+
+struct Candidate {
+    stream: GameDirectory,
+    tags: Vec<String>,
+    started_at: Option<DateTime<Utc>>,
+}
+
+/// Walks the game's directory page by page up to `settings.max_pages`, drops streams that fail the
+/// tag filters and returns the rest, best first. Languages and the drops-enabled filter are applied by Twitch.
+pub async fn discover_streams (client: &dyn DropsBackend, slug: &str, settings: &DiscoverySettings) -> Result<Vec<RankedStream>, TwitchError> {
+    let mut candidates = Vec::new();
+    let mut cursor: Option<String> = None;
+    for _ in 0..settings.max_pages.max(1) {
+        let page = retry_backup(&RetryPolicy::GQL, || directory_page(client, slug, settings, cursor.as_deref())).await?;
+        candidates.extend(page.streams);
+        match page.next_cursor {
+            Some(next) => cursor = Some(next),
+            None => break,
+        }
+    }
+
+    let wanted = |tags: &[String]| {
+        let has = |tag: &String| tags.iter().any(|t| t.eq_ignore_ascii_case(tag));
+        (settings.required_tags.is_empty() || settings.required_tags.iter().any(has)) && !settings.excluded_tags.iter().any(has)
+    };
+    candidates.retain(|c| wanted(&c.tags));
+    Ok(rank(candidates, settings))
+}
+
+fn rank (candidates: Vec<Candidate>, settings: &DiscoverySettings) -> Vec<RankedStream> {
+    let now = Utc::now();
+    let uptime = |c: &Candidate| c.started_at.map_or(0.0, |start| (now - start).num_seconds().max(0) as f64);
+    let max_viewers = candidates.iter().map(|c| c.stream.viewersCount).max().unwrap_or(0).max(1) as f64;
+    let max_uptime = candidates.iter().map(uptime).fold(0.0, f64::max).max(1.0);
+    let total_weight = (settings.viewer_weight + settings.uptime_weight).max(f64::EPSILON);
+
+    let mut ranked: Vec<RankedStream> = candidates.into_iter()
+        .map(|c| {
+            let score = (settings.viewer_weight * c.stream.viewersCount as f64 / max_viewers + settings.uptime_weight * uptime(&c) / max_uptime) / total_weight;
+            RankedStream { stream: c.stream, score: score.clamp(0.0, 1.0) }
+        })
+        .collect();
+    ranked.sort_by(|a, b| b.score.total_cmp(&a.score));
+    ranked
+}
+
+struct DirectoryPage {
+    streams: Vec<Candidate>,
+    next_cursor: Option<String>,
+}
+
+/// `twitch_gql_rs` only fetches the first page without language or tag options, so the directory query is built here.
+async fn directory_page (client: &dyn DropsBackend, slug: &str, settings: &DiscoverySettings, cursor: Option<&str>) -> Result<DirectoryPage, TwitchError> {
+    let languages: Vec<String> = settings.languages.iter().map(|l| l.to_uppercase()).collect();
+    let system_filters: Vec<&str> = if settings.drops_enabled_only { vec!["DROPS_ENABLED"] } else { vec![] };
+    let body = json!({
+        "operationName": "DirectoryPage_Game",
+        "extensions": { "persistedQuery": { "version": 1, "sha256Hash": DIRECTORY_QUERY_HASH } },
+        "variables": {
+            "limit": settings.page_size.max(1),
+            "slug": slug,
+            "cursor": cursor,
+            "imageWidth": 50,
+            "includeCostreaming": false,
+            "options": {
+                "broadcasterLanguages": languages,
+                "freeformTags": null,
+                "includeRestricted": ["SUB_ONLY_LIVE"],
+                "recommendationsContext": { "platform": "web" },
+                "sort": "RELEVANCE",
+                "systemFilters": system_filters,
+                "tags": [],
+                "requestID": "JIRA-VXP-2397",
+            },
+            "sortTypeIsRecency": false,
+        },
+    });
+
+    let response = client.get_game_directory(&body).await?;
+    let streams = response.pointer("/data/game/streams").ok_or_else(|| TwitchError::MissingField("streams".to_string()))?;
+    let edges = streams.get("edges").and_then(Value::as_array).ok_or_else(|| TwitchError::MissingField("edges".to_string()))?;
+
+    let mut page = Vec::with_capacity(edges.len());
+    for edge in edges {
+        let node = edge.get("node").ok_or_else(|| TwitchError::MissingField("node".to_string()))?;
+        let tags = node.get("freeformTags").and_then(Value::as_array).map(|tags| {
+            tags.iter().filter_map(|tag| tag.get("name").and_then(Value::as_str)).map(str::to_string).collect()
+        }).unwrap_or_default();
+        let started_at = node.get("createdAt").and_then(Value::as_str).and_then(|at| DateTime::parse_from_rfc3339(at).ok()).map(|at| at.with_timezone(&Utc));
+        page.push(Candidate { stream: serde_json::from_value(node.clone())?, tags, started_at });
+    }
+
+    let has_next = streams.pointer("/pageInfo/hasNextPage").and_then(Value::as_bool).unwrap_or(false);
+    let next_cursor = edges.last().and_then(|edge| edge.get("cursor")).and_then(Value::as_str).filter(|c| has_next && !c.is_empty()).map(str::to_string);
+    Ok(DirectoryPage { streams: page, next_cursor })
+}
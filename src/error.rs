@@ -0,0 +1,376 @@
+use std::{error::Error, fmt, io, time::Duration};
+
+use rand::{RngExt, rng};
+use tokio::time::sleep;
+use tracing::warn;
+use twitch_gql_rs::error::{AuthError, AvailableDropsError, CampaignDetailsError, ClaimDropError, GameDirectoryError, SlugError, StreamInfoError, SystemError, TwitchError};
+
+/// How long to back off when Twitch rate limits us and doesn't say for how long.
+const RATE_LIMIT_DELAY: Duration = Duration::from_secs(60);
+
+/// GQL `errors` messages Twitch sends when it's overloaded. Compared case-insensitively, as a whole.
+const RETRYABLE_GQL_ERRORS: [&str; 4] = ["service timeout", "service unavailable", "service error", "internal server error"];
+const RATE_LIMIT_GQL_ERRORS: [&str; 2] = ["rate limit exceeded", "too many requests"];
+/// What Twitch answers when it no longer accepts the OAuth token.
+const AUTH_GQL_ERRORS: [&str; 2] = ["invalid access token", "the \"authorization\" token is invalid."];
+
+/// What a caller should do about an error.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorClass {
+    /// A network blip or a Twitch hiccup, worth trying again.
+    Retryable,
+    /// Twitch asked us to slow down. Try again, but not before the delay.
+    RateLimited(Duration),
+    /// The session's token is no longer accepted, retrying won't help until the account logs in again.
+    AuthFatal,
+    /// Bad input or a request Twitch will never accept.
+    Permanent,
+}
+
+/// Errors that know whether they're worth retrying.
+pub trait Classify {
+    fn class (&self) -> ErrorClass;
+}
+
+/// Crate-level error: the `twitch_gql_rs` errors, I/O, JSON, config, proxy, vault and account errors.
+#[derive(Debug)]
+pub enum AppError {
+    Twitch(TwitchError),
+    Auth(AuthError),
+    Claim(ClaimDropError),
+    CampaignDetails(CampaignDetailsError),
+    StreamInfo(StreamInfoError),
+    GameDirectory(GameDirectoryError),
+    AvailableDrops(AvailableDropsError),
+    Slug(SlugError),
+    System(SystemError),
+    Io(io::Error),
+    Json(serde_json::Error),
+    /// Configuration or missing input, including input that `--headless` can't prompt for.
+    Config(String),
+    /// An invalid proxy entry or a proxy that refused to carry a request.
+    Proxy(String),
+    /// A vault or sealed session that can't be opened: wrong passphrase, corrupted or truncated files.
+    Vault(String),
+    /// An account that is missing, not registered, or whose session doesn't say who it belongs to.
+    Account(String),
+}
+
+impl AppError {
+    /// Whether the error comes from configuration rather than something going wrong while running.
+    pub fn is_config (&self) -> bool {
+        matches!(self, AppError::Config(_) | AppError::Proxy(_))
+    }
+}
+
+impl fmt::Display for AppError {
+    fn fmt (&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AppError::Twitch(e) => e.fmt(f),
+            AppError::Auth(e) => e.fmt(f),
+            AppError::Claim(e) => e.fmt(f),
+            AppError::CampaignDetails(e) => e.fmt(f),
+            AppError::StreamInfo(e) => e.fmt(f),
+            AppError::GameDirectory(e) => e.fmt(f),
+            AppError::AvailableDrops(e) => e.fmt(f),
+            AppError::Slug(e) => e.fmt(f),
+            AppError::System(e) => e.fmt(f),
+            AppError::Io(e) => e.fmt(f),
+            AppError::Json(e) => e.fmt(f),
+            AppError::Config(message) | AppError::Proxy(message) | AppError::Vault(message) | AppError::Account(message) => f.write_str(message),
+        }
+    }
+}
+
+impl Error for AppError {
+    fn source (&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            AppError::Twitch(e) => Some(e),
+            AppError::Auth(e) => Some(e),
+            AppError::Claim(e) => Some(e),
+            AppError::CampaignDetails(e) => Some(e),
+            AppError::StreamInfo(e) => Some(e),
+            AppError::GameDirectory(e) => Some(e),
+            AppError::AvailableDrops(e) => Some(e),
+            AppError::Slug(e) => Some(e),
+            AppError::System(e) => Some(e),
+            AppError::Io(e) => Some(e),
+            AppError::Json(e) => Some(e),
+            AppError::Config(_) | AppError::Proxy(_) | AppError::Vault(_) | AppError::Account(_) => None,
+        }
+    }
+}
+
+impl Classify for AppError {
+    fn class (&self) -> ErrorClass {
+        match self {
+            AppError::Twitch(e) => e.class(),
+            AppError::Auth(e) => e.class(),
+            AppError::Claim(e) => e.class(),
+            AppError::CampaignDetails(e) => e.class(),
+            AppError::StreamInfo(e) => e.class(),
+            AppError::GameDirectory(e) => e.class(),
+            AppError::AvailableDrops(e) => e.class(),
+            AppError::Slug(e) => e.class(),
+            AppError::System(e) => e.class(),
+            AppError::Io(e) => e.class(),
+            AppError::Json(_) | AppError::Config(_) | AppError::Proxy(_) | AppError::Vault(_) | AppError::Account(_) => ErrorClass::Permanent,
+        }
+    }
+}
+
+macro_rules! from_error {
+    ($($variant:ident($error:ty)),* $(,)?) => {
+        $(impl From<$error> for AppError {
+            fn from (e: $error) -> Self {
+                AppError::$variant(e)
+            }
+        })*
+    };
+}
+
+from_error!(
+    Twitch(TwitchError),
+    Auth(AuthError),
+    Claim(ClaimDropError),
+    CampaignDetails(CampaignDetailsError),
+    StreamInfo(StreamInfoError),
+    GameDirectory(GameDirectoryError),
+    AvailableDrops(AvailableDropsError),
+    Slug(SlugError),
+    System(SystemError),
+    Io(io::Error),
+    Json(serde_json::Error),
+);
+
+impl From<dialoguer::Error> for AppError {
+    fn from (e: dialoguer::Error) -> Self {
+        match e {
+            dialoguer::Error::IO(e) => AppError::Io(e),
+        }
+    }
+}
+
+fn http_status_class (status: u16) -> ErrorClass {
+    match status {
+        429 => ErrorClass::RateLimited(RATE_LIMIT_DELAY),
+        401 | 403 => ErrorClass::AuthFatal,
+        408 | 500..=599 => ErrorClass::Retryable,
+        _ => ErrorClass::Permanent,
+    }
+}
+
+impl Classify for TwitchError {
+    fn class (&self) -> ErrorClass {
+        match self {
+            TwitchError::HttpError(status) => http_status_class(*status),
+            TwitchError::ReqwestProblem(e) => match e.status() {
+                Some(status) => http_status_class(status.as_u16()),
+                None if e.is_decode() || e.is_builder() => ErrorClass::Permanent,
+                None => ErrorClass::Retryable,
+            },
+            // `twitch_gql_rs` drops the `errors` array of a GQL answer without `data`, like the one Twitch
+            // sends for "service timeout", so a missing top-level `data` is the only trace of it.
+            TwitchError::MissingField(field) if field == "data" => ErrorClass::Retryable,
+            // Any other shape mismatch comes back the same on every try.
+            TwitchError::MissingField(_) | TwitchError::JsonError(_) => ErrorClass::Permanent,
+            TwitchError::TwitchError(message) => {
+                let message = message.trim();
+                let is = |known: &[&str]| known.iter().any(|known| message.eq_ignore_ascii_case(known));
+                if is(&RETRYABLE_GQL_ERRORS) {
+                    ErrorClass::Retryable
+                } else if is(&RATE_LIMIT_GQL_ERRORS) {
+                    ErrorClass::RateLimited(RATE_LIMIT_DELAY)
+                } else if is(&AUTH_GQL_ERRORS) {
+                    ErrorClass::AuthFatal
+                } else {
+                    ErrorClass::Permanent
+                }
+            },
+        }
+    }
+}
+
+impl Classify for AuthError {
+    fn class (&self) -> ErrorClass {
+        match self {
+            AuthError::TwitchError(e) => e.class(),
+            AuthError::DeviceTokenExpired => ErrorClass::AuthFatal,
+        }
+    }
+}
+
+impl Classify for ClaimDropError {
+    fn class (&self) -> ErrorClass {
+        match self {
+            ClaimDropError::TwitchError(e) => e.class(),
+            ClaimDropError::FailedClaimDrops(_) | ClaimDropError::DropAlreadyClaimed => ErrorClass::Permanent,
+        }
+    }
+}
+
+impl Classify for CampaignDetailsError {
+    fn class (&self) -> ErrorClass {
+        match self {
+            CampaignDetailsError::TwitchError(e) => e.class(),
+            CampaignDetailsError::CampaignNotFound => ErrorClass::Permanent,
+        }
+    }
+}
+
+impl Classify for StreamInfoError {
+    fn class (&self) -> ErrorClass {
+        match self {
+            StreamInfoError::TwitchError(e) => e.class(),
+            StreamInfoError::ChannelNotFound => ErrorClass::Permanent,
+        }
+    }
+}
+
+impl Classify for GameDirectoryError {
+    fn class (&self) -> ErrorClass {
+        match self {
+            GameDirectoryError::TwitchError(e) => e.class(),
+            GameDirectoryError::NoStreamsFound(_) => ErrorClass::Permanent,
+        }
+    }
+}
+
+impl Classify for AvailableDropsError {
+    fn class (&self) -> ErrorClass {
+        match self {
+            AvailableDropsError::TwitchError(e) => e.class(),
+            AvailableDropsError::ChannelNotFound => ErrorClass::Permanent,
+        }
+    }
+}
+
+impl Classify for SlugError {
+    fn class (&self) -> ErrorClass {
+        match self {
+            SlugError::TwitchError(e) => e.class(),
+            SlugError::GameSlugParsingFailed => ErrorClass::Permanent,
+        }
+    }
+}
+
+impl Classify for SystemError {
+    fn class (&self) -> ErrorClass {
+        match self {
+            // Both come from talking to Twitch while building the client.
+            SystemError::HeadersError(_) | SystemError::ClientBuilderError(_) => ErrorClass::Retryable,
+            SystemError::IoError(e) => e.class(),
+            _ => ErrorClass::Permanent,
+        }
+    }
+}
+
+impl Classify for io::Error {
+    fn class (&self) -> ErrorClass {
+        match self.kind() {
+            io::ErrorKind::TimedOut
+            | io::ErrorKind::Interrupted
+            | io::ErrorKind::WouldBlock
+            | io::ErrorKind::UnexpectedEof
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionRefused
+            | io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::NotConnected
+            | io::ErrorKind::BrokenPipe => ErrorClass::Retryable,
+            _ => ErrorClass::Permanent,
+        }
+    }
+}
+
+/// How often and how patiently [`retry_backup`] retries.
+#[derive(Debug, Clone, Copy)]
+pub struct RetryPolicy {
+    /// Total attempts, including the first one.
+    pub max_attempts: u32,
+    pub base_delay: Duration,
+    pub max_delay: Duration,
+}
+
+impl RetryPolicy {
+    /// For GQL requests: 4 attempts, backing off from about 2 s up to a minute.
+    pub const GQL: RetryPolicy = RetryPolicy { max_attempts: 4, base_delay: Duration::from_secs(2), max_delay: Duration::from_secs(60) };
+
+    /// Exponential backoff for the given failed attempt (1-based), with "equal jitter":
+    /// a random delay between half and all of the exponential step.
+    pub fn backoff (&self, attempt: u32) -> Duration {
+        let step = self.base_delay.saturating_mul(1 << attempt.saturating_sub(1).min(16)).min(self.max_delay);
+        let half = step / 2;
+        half + half.mul_f64(rng().random_range(0.0..=1.0))
+    }
+}
+
+impl Default for RetryPolicy {
+    fn default () -> Self {
+        RetryPolicy::GQL
+    }
+}
+
+/// Runs `f` until it succeeds, its error isn't worth retrying, or `policy` runs out of attempts.
+/// Rate-limited errors wait at least as long as the limit asks for.
+pub async fn retry_backup<F, Fut, T, E> (policy: &RetryPolicy, mut f: F) -> Result<T, E>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, E>>,
+    E: Classify + fmt::Display,
+{
+    let mut attempt = 0;
+    loop {
+        attempt += 1;
+        let e = match f().await {
+            Ok(t) => return Ok(t),
+            Err(e) => e,
+        };
+        let delay = match e.class() {
+            ErrorClass::AuthFatal | ErrorClass::Permanent => return Err(e),
+            _ if attempt >= policy.max_attempts => return Err(e),
+            ErrorClass::Retryable => policy.backoff(attempt),
+            ErrorClass::RateLimited(wait) => wait.max(policy.backoff(attempt)),
+        };
+        warn!("Attempt {attempt}/{} failed: {e}. Retrying in {:.1}s", policy.max_attempts, delay.as_secs_f64());
+        sleep(delay).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn twitch (message: &str) -> ErrorClass {
+        TwitchError::TwitchError(message.to_string()).class()
+    }
+
+    #[test]
+    fn only_known_gql_messages_are_retried_or_fatal () {
+        assert_eq!(twitch("service timeout"), ErrorClass::Retryable);
+        assert_eq!(twitch("Service Unavailable"), ErrorClass::Retryable);
+        assert_eq!(twitch("rate limit exceeded"), ErrorClass::RateLimited(RATE_LIMIT_DELAY));
+        assert_eq!(twitch("invalid access token"), ErrorClass::AuthFatal);
+        assert_eq!(twitch("The \"Authorization\" token is invalid."), ErrorClass::AuthFatal);
+
+        // Messages that merely mention one of these words stay permanent instead of quarantining the account.
+        assert_eq!(twitch("failed to authenticate the spade event"), ErrorClass::Permanent);
+        assert_eq!(twitch("Error while sending watch event"), ErrorClass::Permanent);
+        assert_eq!(twitch("Not found user_id"), ErrorClass::Permanent);
+        assert_eq!(twitch("upstream service timeout while loading the drop"), ErrorClass::Permanent);
+    }
+
+    #[test]
+    fn shape_mismatches_are_permanent_except_a_missing_data_field () {
+        assert_eq!(TwitchError::MissingField("data".to_string()).class(), ErrorClass::Retryable);
+        assert_eq!(TwitchError::MissingField("edges".to_string()).class(), ErrorClass::Permanent);
+        assert_eq!(TwitchError::JsonError(serde::de::Error::custom("missing field `id`")).class(), ErrorClass::Permanent);
+    }
+
+    #[test]
+    fn http_statuses () {
+        assert_eq!(TwitchError::HttpError(429).class(), ErrorClass::RateLimited(RATE_LIMIT_DELAY));
+        assert_eq!(TwitchError::HttpError(401).class(), ErrorClass::AuthFatal);
+        assert_eq!(TwitchError::HttpError(503).class(), ErrorClass::Retryable);
+        assert_eq!(TwitchError::HttpError(404).class(), ErrorClass::Permanent);
+    }
+}
@@ -0,0 +1,205 @@
+use std::{collections::HashMap, fmt::Write as _, fs::{self, File, OpenOptions}, io::{self, Write}, path::{Path, PathBuf}, sync::Mutex};
+
+use chrono::Local;
+use tracing::{Event, Span, Subscriber, field::{Field, Visit}, info_span, level_filters::LevelFilter, span::{Attributes, Id}};
+use tracing_appender::rolling::{RollingFileAppender, Rotation};
+use tracing_subscriber::{Layer, Registry, filter::Targets, fmt::{self, time::ChronoLocal}, layer::{Context, SubscriberExt}, registry::LookupSpan, util::SubscriberInitExt};
+
+use crate::{backend::DropsBackend, cli::{Cli, LogFormat, config_error}, config::{LogConfig, LogRotation}, error::AppError, tui::LogTail};
+
+type BoxedLayer = Box<dyn Layer<Registry> + Send + Sync>;
+type LogWriter = Box<dyn Write + Send>;
+
+const TIMESTAMP: &str = "%Y-%m-%d %H:%M:%S%.3f";
+
+/// Sets up logging to `app.log` in the configured directory, plus structured stdout output with `--headless`
+/// or the dashboard's log panel with `--tui`. Returns the panel's buffer.
+pub fn init_logging (cli: &Cli, config: &LogConfig) -> Result<Option<LogTail>, AppError> {
+    let filter = level_filter(cli, config)?;
+    let dir = Path::new(&config.dir);
+    fs::create_dir_all(dir).map_err(|e| config_error(format!("Failed to create log directory {}: {e}", dir.display())))?;
+
+    let mut layers: Vec<BoxedLayer> = Vec::new();
+    let file = log_file(dir, "app", config)?;
+    layers.push(fmt::layer().with_writer(Mutex::new(file)).with_ansi(false).with_timer(ChronoLocal::new(TIMESTAMP.into())).with_filter(filter.clone()).boxed());
+
+    if config.per_account {
+        let accounts = AccountLogs { dir: dir.join("accounts"), config: config.clone(), files: Mutex::default() };
+        layers.push(accounts.with_filter(filter.clone()).boxed());
+    }
+
+    if cli.headless {
+        let stdout = match cli.log_format {
+            LogFormat::Json => fmt::layer().json().flatten_event(true).with_current_span(true).with_span_list(false).with_writer(io::stdout).boxed(),
+            LogFormat::Logfmt => tracing_logfmt::builder().with_span_path(false).layer().with_writer(io::stdout).boxed(),
+        };
+        layers.push(stdout.with_filter(filter.clone()).boxed());
+    }
+
+    let log_tail = cli.tui.then(LogTail::default);
+    if let Some(tail) = &log_tail {
+        layers.push(fmt::layer().with_writer(tail.clone()).with_ansi(false).with_target(false).with_timer(ChronoLocal::new("%H:%M:%S".into())).with_filter(filter).boxed());
+    }
+
+    tracing_subscriber::registry().with(layers).init();
+    Ok(log_tail)
+}
+
+/// Span that tags every event from an account's tasks with `account=<login>`.
+pub fn account_span (client: &dyn DropsBackend) -> Span {
+    info_span!("account", account = client.login())
+}
+
+/// Builds the level filter from `level` and the `module=level` list in `filters`.
+/// Bare module names like `stream` also match this crate's module of that name.
+fn level_filter (cli: &Cli, config: &LogConfig) -> Result<Targets, AppError> {
+    let parse = |level: &str| level.trim().parse::<LevelFilter>().map_err(|_| config_error(format!("Invalid log level '{}'", level.trim())));
+    let default = if cli.debug { LevelFilter::DEBUG } else { parse(&config.level)? };
+
+    let mut targets = Targets::new().with_default(default);
+    for directive in config.filters.split(',').map(str::trim).filter(|d| !d.is_empty()) {
+        let (module, level) = directive.split_once('=').ok_or_else(|| config_error(format!("Invalid log filter '{directive}', expected module=level")))?;
+        let (module, level) = (module.trim(), parse(level)?);
+        if !module.contains("::") {
+            targets = targets.with_target(format!("{}::{module}", env!("CARGO_CRATE_NAME")), level);
+        }
+        targets = targets.with_target(module, level);
+    }
+    Ok(targets)
+}
+
+fn log_file (dir: &Path, name: &str, config: &LogConfig) -> Result<LogWriter, AppError> {
+    let rotation = match config.rotation {
+        LogRotation::Size => {
+            let file = SizeRotatingFile::open(dir.join(format!("{name}.log")), config.max_size_mb.max(1) * 1024 * 1024, config.keep)?;
+            return Ok(Box::new(file));
+        },
+        LogRotation::Never => Rotation::NEVER,
+        LogRotation::Hourly => Rotation::HOURLY,
+        LogRotation::Daily => Rotation::DAILY,
+    };
+    let appender = RollingFileAppender::builder()
+        .rotation(rotation)
+        .filename_prefix(name)
+        .filename_suffix("log")
+        .max_log_files(config.keep + 1)
+        .build(dir)
+        .map_err(|e| config_error(format!("Failed to open {name}.log in {}: {e}", dir.display())))?;
+    Ok(Box::new(appender))
+}
+
+/// `app.log` that moves to `app.log.1`, `app.log.2`, ... once it grows past `max_bytes`, keeping `keep` old files.
+struct SizeRotatingFile {
+    path: PathBuf,
+    max_bytes: u64,
+    keep: usize,
+    file: File,
+    size: u64,
+}
+
+impl SizeRotatingFile {
+    fn open (path: PathBuf, max_bytes: u64, keep: usize) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let size = file.metadata()?.len();
+        Ok(SizeRotatingFile { path, max_bytes, keep, file, size })
+    }
+
+    fn rotated (&self, index: usize) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(format!(".{index}"));
+        path.into()
+    }
+
+    fn rotate (&mut self) -> io::Result<()> {
+        if self.keep > 0 {
+            let _ = fs::remove_file(self.rotated(self.keep));
+            for index in (1..self.keep).rev() {
+                let _ = fs::rename(self.rotated(index), self.rotated(index + 1));
+            }
+            fs::rename(&self.path, self.rotated(1))?;
+        }
+        self.file = OpenOptions::new().create(true).write(true).truncate(true).open(&self.path)?;
+        self.size = 0;
+        Ok(())
+    }
+}
+
+impl Write for SizeRotatingFile {
+    fn write (&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.size > 0 && self.size + buf.len() as u64 > self.max_bytes {
+            self.rotate()?;
+        }
+        let written = self.file.write(buf)?;
+        self.size += written as u64;
+        Ok(written)
+    }
+
+    fn flush (&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+/// Writes events that happen inside an [`account_span`] to that account's own log file.
+struct AccountLogs {
+    dir: PathBuf,
+    config: LogConfig,
+    files: Mutex<HashMap<String, LogWriter>>,
+}
+
+struct AccountLogin(String);
+
+#[derive(Default)]
+struct FieldVisitor {
+    message: String,
+    fields: String,
+    account: Option<String>,
+}
+
+impl Visit for FieldVisitor {
+    fn record_str (&mut self, field: &Field, value: &str) {
+        match field.name() {
+            "message" => self.message = value.to_string(),
+            "account" => self.account = Some(value.to_string()),
+            name => { let _ = write!(self.fields, " {name}={value}"); },
+        }
+    }
+
+    fn record_debug (&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+        match field.name() {
+            "message" => self.message = format!("{value:?}"),
+            name => { let _ = write!(self.fields, " {name}={value:?}"); },
+        }
+    }
+}
+
+impl<S: Subscriber + for<'a> LookupSpan<'a>> Layer<S> for AccountLogs {
+    fn on_new_span (&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        let mut visitor = FieldVisitor::default();
+        attrs.record(&mut visitor);
+        if let Some(login) = visitor.account && let Some(span) = ctx.span(id) {
+            span.extensions_mut().insert(AccountLogin(login));
+        }
+    }
+
+    fn on_event (&self, event: &Event<'_>, ctx: Context<'_, S>) {
+        let Some(login) = ctx.event_scope(event).and_then(|scope| scope.from_root().find_map(|span| span.extensions().get::<AccountLogin>().map(|a| a.0.clone()))) else {
+            return;
+        };
+        let mut visitor = FieldVisitor::default();
+        event.record(&mut visitor);
+        let meta = event.metadata();
+        let line = format!("{} {:>5} {}: {}{}\n", Local::now().format(TIMESTAMP), meta.level(), meta.target(), visitor.message, visitor.fields);
+
+        let Ok(mut files) = self.files.lock() else { return };
+        if !files.contains_key(&login) {
+            let name: String = login.chars().filter(|c| c.is_ascii_alphanumeric() || *c == '_').collect();
+            let Ok(file) = fs::create_dir_all(&self.dir).map_err(Into::into).and_then(|_| log_file(&self.dir, &name, &self.config)) else {
+                return;
+            };
+            files.insert(login.clone(), file);
+        }
+        if let Some(file) = files.get_mut(&login) {
+            let _ = file.write_all(line.as_bytes());
+        }
+    }
+}
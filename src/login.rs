@@ -0,0 +1,151 @@
+use std::{path::{Path, PathBuf}, sync::Arc, time::Duration};
+
+use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
+use qrcode::{QrCode, render::unicode::Dense1x2};
+use serde::Serialize;
+use tracing::{error, info, warn};
+use twitch_gql_rs::{TwitchClient, client_type::ClientType, error::{AuthError, TwitchError}};
+
+use crate::{accounts::{register_account, write_session}, backend::connect, error::AppError, proxy::assign_proxy, ratelimit::LIMITER, r#static::AppState};
+
+const MAX_ATTEMPTS: u32 = 3;
+
+/// Runs several device-code logins at once. Every pending login gets its own status line,
+/// and each session is saved as soon as Twitch reports it approved.
+pub async fn login_accounts (home_dir: &Path, state: &Arc<AppState>) -> Result<(), AppError> {
+    let count: usize = dialoguer::Input::new().with_prompt("▸ How many accounts do you want to log in?").default(1).interact_text()?;
+    if count == 0 {
+        return Ok(());
+    }
+
+    let bars = MultiProgress::new();
+    let style = ProgressStyle::with_template("{spinner} #{prefix} {msg}").expect("Failed to create progress bar style");
+    let mut handles = Vec::with_capacity(count);
+    // None of these logins is assigned before it's approved, so spread them over the proxies here.
+    let mut handed_out = Vec::with_capacity(count);
+
+    for slot in 1..=count {
+        let bar = bars.add(ProgressBar::new_spinner());
+        bar.set_style(style.clone());
+        bar.set_prefix(slot.to_string());
+        bar.enable_steady_tick(Duration::from_millis(120));
+
+        let proxy = state.proxy_pool.lock().await.least_loaded_besides(&handed_out);
+        handed_out.extend(proxy.clone());
+        let home_dir = home_dir.to_path_buf();
+        let bars = bars.clone();
+        let state = state.clone();
+        handles.push(tokio::spawn(async move {
+            let result = device_login(slot, &home_dir, proxy, &bar, &bars, &state).await.map_err(|e| e.to_string());
+            match result {
+                Ok(Some(login)) => {
+                    bar.finish_with_message(format!("✅ {login} saved"));
+                    true
+                },
+                Ok(None) => false,
+                Err(e) => {
+                    error!("Device login #{slot} failed: {e}");
+                    bar.abandon_with_message(format!("❌ {e}"));
+                    false
+                }
+            }
+        }));
+    }
+
+    let mut added = 0;
+    for handle in handles {
+        if let Ok(true) = handle.await {
+            added += 1;
+        }
+    }
+    info!("Device login finished: {added}/{count} accounts added");
+    println!("Logged in {added}/{count} accounts");
+    Ok(())
+}
+
+async fn device_login (slot: usize, home_dir: &Path, proxy: Option<String>, bar: &ProgressBar, bars: &MultiProgress, state: &Arc<AppState>) -> Result<Option<String>, AppError> {
+    let client_type = ClientType::android_app();
+    let mut client = TwitchClient::new(&client_type, &proxy).await?;
+
+    for attempt in 1..=MAX_ATTEMPTS {
+        bar.set_message(format!("requesting device code (attempt {attempt}/{MAX_ATTEMPTS})..."));
+        info!("Starting Twitch device authentication #{slot} (attempt {attempt}/{MAX_ATTEMPTS})");
+        let get_auth = client.request_device_auth().await?;
+        bars.println(activation_card(slot, &get_auth.verification_uri, &get_auth.user_code))?;
+        bar.set_message(format!("{} • waiting for approval (attempt {attempt}/{MAX_ATTEMPTS})", get_auth.user_code));
+
+        match client.auth(get_auth).await {
+            Ok(_) => {
+                bar.set_message("approved, saving session...");
+                return save_session(client, home_dir, &proxy, state).await.map(Some);
+            },
+            Err(AuthError::DeviceTokenExpired) => {
+                warn!("Device authentication token #{slot} expired (attempt {attempt}/{MAX_ATTEMPTS})");
+                continue;
+            },
+            Err(e @ AuthError::TwitchError(_)) => return Err(e.into()),
+        }
+    }
+
+    warn!("Device authentication #{slot} failed: maximum retry attempts ({MAX_ATTEMPTS}) reached.");
+    bar.abandon_with_message(format!("⌛ code expired {MAX_ATTEMPTS} times, giving up"));
+    Ok(None)
+}
+
+/// The code to approve a device login started with [`start_device_login`].
+#[derive(Debug, Clone, Serialize)]
+pub struct PendingLogin {
+    pub verification_uri: String,
+    pub user_code: String,
+}
+
+/// Starts a device-code login without the terminal, for the control API. Once the code is approved
+/// in the background, the session is saved and the account registered, joining the farm if it is running.
+pub async fn start_device_login (home_dir: PathBuf, state: Arc<AppState>) -> Result<PendingLogin, AppError> {
+    let proxy = state.proxy_pool.lock().await.least_loaded();
+    let mut client = TwitchClient::new(&ClientType::android_app(), &proxy).await?;
+    let get_auth = client.request_device_auth().await?;
+    let pending = PendingLogin { verification_uri: get_auth.verification_uri.clone(), user_code: get_auth.user_code.clone() };
+    info!("Device login {} started through the control API", pending.user_code);
+
+    let user_code = pending.user_code.clone();
+    tokio::spawn(async move {
+        let result = match client.auth(get_auth).await {
+            Ok(_) => save_session(client, &home_dir, &proxy, &state).await.map_err(|e| e.to_string()),
+            Err(e) => Err(e.to_string()),
+        };
+        match result {
+            Ok(login) => info!("Account {login} logged in with device code {user_code}"),
+            Err(e) => error!("Device login {user_code} failed: {e}"),
+        }
+    });
+    Ok(pending)
+}
+
+async fn save_session (client: TwitchClient, home_dir: &Path, proxy: &Option<String>, state: &Arc<AppState>) -> Result<String, AppError> {
+    let login = client.login.clone().ok_or_else(|| TwitchError::MissingField("login".to_string()))?;
+    let path = write_session(&client, home_dir, state.vault.get()).await?;
+    let proxy = assign_proxy(state, &login, proxy.as_deref()).await;
+    let client = match state.vault.get() {
+        Some(vault) => vault.load_client(&path, &proxy).await?,
+        None => TwitchClient::load_from_file(&path, &proxy).await?,
+    };
+    LIMITER.set_egress(&login, proxy.as_deref());
+
+    if !register_account(connect(client, proxy.as_deref(), state)?, state).await {
+        warn!("Account {login} has already been added");
+    }
+    Ok(login)
+}
+
+fn activation_card (slot: usize, verification_uri: &str, user_code: &str) -> String {
+    let activation_url = format!("{verification_uri}?device-code={user_code}");
+    let qr = match QrCode::new(activation_url.as_bytes()) {
+        Ok(code) => code.render::<Dense1x2>().dark_color(Dense1x2::Light).light_color(Dense1x2::Dark).build(),
+        Err(e) => {
+            warn!("Failed to render QR code for {activation_url}: {e}");
+            String::new()
+        }
+    };
+    format!("\n#{slot} Open {verification_uri} and enter the code \x1b[1m{user_code}\x1b[0m, or scan:\n{qr}")
+}
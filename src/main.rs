@@ -1,77 +1,40 @@
-use std::{collections::{BTreeMap, HashMap, HashSet, VecDeque}, error::Error, path::Path, sync::Arc, time::Duration};
+use std::{collections::{BTreeMap, HashMap, HashSet, VecDeque}, path::Path, process::ExitCode, sync::Arc, time::{Duration, Instant}};
 
-use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
-use rand::{RngExt, SeedableRng, rng, rngs::SmallRng, seq::{IndexedRandom, SliceRandom}};
-use tokio::{fs::{self}, sync::{Mutex, Notify, mpsc::{self, UnboundedSender}}, time::sleep};
-use tracing::{debug, info, error, warn, level_filters::LevelFilter};
-use tracing_appender::rolling;
-use tracing_subscriber::fmt::{time::ChronoLocal, writer::BoxMakeWriter};
-use twitch_gql_rs::{TwitchClient, client_type::ClientType, error::ClaimDropError, structs::{DropCampaigns}};
+use indicatif::{MultiProgress, ProgressBar, ProgressDrawTarget, ProgressStyle};
+use rand::{RngExt, SeedableRng, rng, rngs::SmallRng};
+use tokio::{fs::{self}, sync::mpsc::{self, UnboundedSender}, time::sleep};
+use tracing::{Instrument, debug, info, error, warn};
+use twitch_gql_rs::{error::ClaimDropError, structs::{DropCampaigns, TimeBasedDropsCampaignDetails}};
 
 mod r#static;
 mod stream;
 mod config;
 mod webhook;
-
-use crate::{config::*, r#static::*, stream::*, webhook::{WebhookSendFormat, webhook_message_worker}};
+mod accounts;
+mod login;
+mod cli;
+mod vault;
+mod proxy;
+mod tunnel;
+mod api;
+mod metrics;
+mod tui;
+mod logging;
+mod shutdown;
+mod tasks;
+mod error;
+mod ratelimit;
+mod channel_cache;
+mod catalog;
+mod directory;
+mod streamers;
+mod scheduler;
+mod backend;
+mod capture;
+
+use crate::{accounts::*, api::spawn_api, backend::{DropsBackend, Watch, layered}, capture::{CAPTURE_FILE, Capture, Recorder, Replay}, cli::{Cli, Command, EXIT_CONFIG, EXIT_RUNTIME, config_error, is_headless, require_interactive}, config::*, error::{AppError, RetryPolicy, retry_backup}, logging::{account_span, init_logging}, login::login_accounts, metrics::{AccountGameLabels, METRICS}, proxy::{ProxyPool, check_all, spawn_proxy_monitor}, ratelimit::LIMITER, r#static::*, scheduler::{Heartbeat, ReleaseReason}, shutdown::spawn_shutdown_handler, stream::*, streamers::watch_streamer_lists, tasks::RESTART_DELAY, tui::spawn_tui, vault::Vault, webhook::{WebhookSendFormat, webhook_message_worker}};
 
 const STREAM_SLEEP: u64 = 59;
-const MAX_COUNT: u64 = 3;
-
-async fn create_client (home_dir: &Path, proxies: &[String], state: &AppState) -> Result<(), Box<dyn Error>> {
-    let random_proxy = proxies.choose(&mut rng()).cloned();
-
-    let client_type = ClientType::android_app();
-    let mut client = TwitchClient::new(&client_type, &random_proxy).await?;
-    let mut count = 0;
-
-    loop {
-        count += 1;
-        if count >= MAX_COUNT {
-            warn!("Authentication failed: maximum retry attempts ({MAX_COUNT}) reached.");
-            return Ok(());
-        }
-        info!("Starting Twitch device authentication (attempt {count}/{MAX_COUNT})");
-        let get_auth = client.request_device_auth().await?;
-        println!("To authenticate, open the following URL in your browser:\n{}", get_auth.verification_uri);
-        match client.auth(get_auth).await {
-            Ok(_) => break,
-            Err(twitch_gql_rs::error::AuthError::DeviceTokenExpired) => {
-                warn!("Device authentication token expired. Requesting a new one (attempt {count}/{MAX_COUNT})...");
-                continue
-            },
-            Err(twitch_gql_rs::error::AuthError::TwitchError(e)) => {
-                error!("Twitch returned an error during authentication: {e}");
-                return Ok(());
-            }
-        }
-    }
-    let path = home_dir.join(format!("{}.json", client.login.clone().expect("Login is required")));
-    let path = Path::new(&path);
-    if !path.exists() {
-        client.save_file(&path).await?;
-    }
-    let client = TwitchClient::load_from_file(&path, &random_proxy).await?;
-    let login = client.login.clone().expect("Login is required");
-
-    let mut accounts = state.accounts.lock().await;
-    let already_exists = if let Some(accs) = &*accounts {
-        accs.iter().any(|c| c.login.as_ref().map_or(false, |l| l == &login))
-    } else {
-        false
-    };
-
-    if already_exists {
-        println!("Account {} has already been added", login);
-        return Ok(());
-    }
-
-    match & mut *accounts {
-        Some(account) => account.push(Arc::new(client.clone())),
-        None => *accounts = Some(vec![Arc::new(client.clone())])
-    }
-    Ok(())
-}
 
 fn group_campaigns (campaign: Vec<DropCampaigns>, current_id_to_index: &mut HashMap<String, usize>, current_grouped: &mut BTreeMap<usize, VecDeque<DropCampaigns>>) {
     let mut next_index: usize = 0;
@@ -90,70 +53,140 @@ fn group_campaigns (campaign: Vec<DropCampaigns>, current_id_to_index: &mut Hash
 }
 
 #[tokio::main]
-async fn main () -> Result<(), Box<dyn Error>> {
-    let debug = std::env::args().any(|a| a == "--debug");
-    let level = if debug { LevelFilter::DEBUG } else { LevelFilter::INFO };
+async fn main () -> ExitCode {
+    let global_state = Arc::new(AppState::default());
+    let shutdown = spawn_shutdown_handler(global_state.clone());
+    let result = tokio::select! {
+        result = run(global_state.clone()) => result,
+        _ = shutdown.cancelled() => Ok(()),
+    };
+    // A signal also interrupts a blocking prompt, let the shutdown finish instead of reporting that.
+    if let Err(e) = &result && is_interrupted(e) {
+        shutdown.cancelled().await;
+        return ExitCode::SUCCESS;
+    }
+
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            error!("{e}");
+            eprintln!("Error: {e}");
+            ExitCode::from(if e.is_config() { EXIT_CONFIG } else { EXIT_RUNTIME })
+        }
+    }
+}
 
-    let file_appender = rolling::never(".", "app.log");
-    tracing_subscriber::fmt().with_writer(BoxMakeWriter::new(file_appender)).with_max_level(level).with_ansi(false).with_timer(ChronoLocal::new("%Y-%m-%d %H:%M:%S%.3f".into())).init();
+fn is_interrupted (e: &AppError) -> bool {
+    matches!(e, AppError::Io(io) if io.kind() == std::io::ErrorKind::Interrupted)
+}
+
+async fn run (global_state: Arc<AppState>) -> Result<(), AppError> {
+    let cli = Cli::parse()?;
     let home_dir = Path::new("data");
     if !home_dir.exists() {
         fs::create_dir_all(&home_dir).await?;
     }
 
     let config_path = home_dir.join("config.json");
+    // Logging starts before the config is fully validated, a broken config is reported by the load below.
+    let log_config = if config_path.exists() { Config::load(&config_path).await.map(|c| c.log).unwrap_or_default() } else { LogConfig::default() };
+    let log_tail = init_logging(&cli, &log_config)?;
     let config = if !config_path.exists() {
+        require_interactive("First-time setup (data/config.json is missing)")?;
         let mut config = Config::new().await?;
         config.first_time_setup().await?;
         config.save(&config_path).await?;
         config
     } else {
-        Config::load(&config_path).await?
+        Config::load(&config_path).await.map_err(|e| config_error(format!("Failed to load {}: {e}", config_path.display())))?
     };
 
-    config.configure_autostart()?;
+    let delete_dir = Path::new("delete_accounts");
+    if cli.command == Command::VaultRekey {
+        let count = Vault::rekey(home_dir, &[home_dir, delete_dir], &config.vault).await?;
+        println!("Vault passphrase changed, {count} sessions re-encrypted");
+        return Ok(());
+    }
 
-    let mut proxies = config.load_proxies_list().await?;
+    if let Some(tail) = log_tail {
+        let _ = global_state.log_tail.set(tail);
+    }
+    LIMITER.configure(&config.rate_limit);
+    global_state.channel_cache.lock().await.configure(config.channel_cache.clone());
+    let _ = global_state.discovery.set(config.discovery.clone());
+    let _ = global_state.switching.set(config.switching.clone());
+    let _ = global_state.intervals.set(config.intervals.clone());
+    let _ = global_state.endpoints.set(config.endpoints.clone());
+    if let Some(dir) = &cli.record {
+        let recorder = Recorder::create(dir).map_err(|e| config_error(format!("Failed to start a capture in {}: {e}", dir.display())))?;
+        info!("Recording GQL calls and PubSub frames to {}", dir.join(CAPTURE_FILE).display());
+        let _ = global_state.capture.set(Capture::Record(Arc::new(recorder)));
+    }
+    if let Some(dir) = &cli.replay {
+        let replay = Replay::load(dir).map_err(|e| config_error(format!("Failed to load the capture in {}: {e}", dir.display())))?;
+        info!("Replaying {} instead of talking to Twitch", dir.join(CAPTURE_FILE).display());
+        let _ = global_state.capture.set(Capture::Replay(Arc::new(replay)));
+    }
+    let proxies = config.load_proxies_list().await.map_err(config_error)?;
+    *global_state.proxy_pool.lock().await = ProxyPool::load(home_dir, proxies.clone(), config.normalized_proxy_overrides().map_err(config_error)?, config.proxy_health.clone()).await?;
+    check_all(&global_state).await;
 
-    let mut rng = rng();
-    proxies.shuffle(&mut rng);
+    if cli.command == Command::AccountsList {
+        print_accounts(home_dir, &global_state).await?;
+        return Ok(());
+    }
 
-    let mut proxy_pool = proxies.iter().cycle();
+    config.configure_autostart()?;
 
-    let delete_dir = Path::new("delete_accounts");
-    let mut loaded_clients = Vec::new();
-    let mut entries = fs::read_dir(&home_dir).await?;
-    while let Some(entry) = entries.next_entry().await? {
-        let path = entry.path();
-        if path.is_file() && path.extension().map_or(false, |s| s == "json" ) && path.file_name().unwrap_or_default() != "cache.json" && path.file_name().unwrap_or_default() != "config.json"  && path.file_name().unwrap_or_default() != "cash.json" {
-            let selected_proxy = proxy_pool.next();
-            let client = TwitchClient::load_from_file(&path, &selected_proxy.cloned()).await?;
-            if let Err(e) = client.get_campaign().await {
-                if !delete_dir.exists() {
-                    fs::create_dir_all(&delete_dir).await?;
-                }
-                let new_path = delete_dir.join(path.file_name().unwrap_or_default());
-                client.save_file(&new_path).await?;
-                fs::remove_file(&path).await?;
-                error!("Failed to load client from file {}: {e}", path.display());
+    // A replay doesn't touch the saved sessions, so it doesn't need the passphrase either.
+    if config.vault.enabled && cli.replay.is_none() {
+        let vault = Vault::open(home_dir, &[home_dir, delete_dir], &config.vault).await.map_err(config_error)?;
+        let migrated = vault.migrate(&[home_dir, delete_dir]).await?;
+        if migrated > 0 {
+            info!("Moved {migrated} plaintext sessions into the vault");
+        }
+        let _ = global_state.vault.set(vault);
+    }
+
+    if let Some(Capture::Replay(replay)) = global_state.capture.get() {
+        let backends = replay.backends();
+        if backends.is_empty() {
+            return Err(config_error("The capture has no accounts to replay"));
+        }
+        for backend in backends {
+            register_account(Arc::new(layered(backend)), &global_state).await;
+        }
+    } else {
+        let mut entries = fs::read_dir(&home_dir).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            let path = entry.path();
+            if !is_session_file(&path) {
                 continue;
             }
-            loaded_clients.push(Arc::new(client));
+            // One broken account mustn't keep the others from farming, the session watcher tries it again later.
+            match load_session(&path, delete_dir, &global_state).await {
+                Ok(Some(client)) => {
+                    register_account(client, &global_state).await;
+                },
+                Ok(None) => {},
+                Err(e) => warn!("Failed to load session file {}: {e}", path.display()),
+            }
         }
+        watch_session_dir(home_dir.to_path_buf(), global_state.clone());
     }
-
-    let global_state = Arc::new(AppState::default());
-    if !loaded_clients.is_empty() {
-        let mut accounts_lock = global_state.accounts.lock().await;
-        *accounts_lock = Some(loaded_clients);
+    let (preferred_path, blocked_path) = config.streamer_list_paths();
+    watch_streamer_lists(preferred_path, blocked_path, global_state.clone()).await;
+    if config.api.enabled {
+        spawn_api(&config.api, home_dir.to_path_buf(), global_state.clone()).await?;
     }
+    spawn_proxy_monitor(home_dir.to_path_buf(), global_state.clone());
 
-    let games = config.loaded_games().await?;
+    let games = config.loaded_games().await.map_err(config_error)?;
 
     let items = vec!["Add account", "Start farming"];
     loop {
         let accounts_lock = global_state.accounts.lock().await;
-        let has_accounts = accounts_lock.as_ref().map_or(false, |accs| !accs.is_empty());
+        let has_accounts = accounts_lock.as_ref().is_some_and(|accs| !accs.is_empty());
         drop(accounts_lock);
 
         let select = if !games.is_empty() && has_accounts {
@@ -161,23 +194,25 @@ async fn main () -> Result<(), Box<dyn Error>> {
         } else if !has_accounts {
             0
         } else {
+            require_interactive("Choosing what to do (lists/games.txt is empty)")?;
             dialoguer::Select::new().with_prompt("Select option").items(&items).default(0).interact()?
         };
 
         match select {
             0 => {
-                create_client(&home_dir, &proxies, &global_state).await?
+                require_interactive("Logging in accounts (no saved sessions in data/)")?;
+                login_accounts(home_dir, &global_state).await?
             },
             1 => {
                 let clients = global_state.accounts.lock().await;
                 let client = if let Some(accounts) = clients.clone() {
-                    accounts.first().cloned().ok_or("No accounts found")?
+                    accounts.first().cloned().ok_or_else(|| AppError::Account("No accounts found".to_string()))?
                 } else {
-                    return Err("Didn't find accounts")?;
+                    return Err(AppError::Account("Didn't find accounts".to_string()));
                 };
                 drop(clients);
 
-                let campaign = client.get_campaign().await?;
+                let campaign = retry_backup(&RetryPolicy::GQL, || client.get_campaign()).await?;
                 let campaign = campaign.dropCampaigns;
 
                 let mut id_to_index = HashMap::new();
@@ -191,7 +226,7 @@ async fn main () -> Result<(), Box<dyn Error>> {
     }
 }
 
-async fn main_logic (client: Arc<TwitchClient> ,grouped: BTreeMap<usize, VecDeque<DropCampaigns>>, home_dir: &Path, games: &VecDeque<String>, webhook_url: String, proxies: &[String], global_state: Arc<AppState>) -> Result<(), Box<dyn Error>> {
+async fn main_logic (client: Arc<dyn DropsBackend> ,grouped: BTreeMap<usize, VecDeque<DropCampaigns>>, home_dir: &Path, games: &VecDeque<String>, webhook_url: String, proxies: &[String], global_state: Arc<AppState>) -> Result<(), AppError> {
     let query_games: VecDeque<VecDeque<DropCampaigns>> = if !games.is_empty() {
         games.iter().filter_map(|game_name| {
             let campaigns_for_game: VecDeque<DropCampaigns> = grouped.values().flat_map(|campaigns_vec| {
@@ -230,159 +265,54 @@ async fn main_logic (client: Arc<TwitchClient> ,grouped: BTreeMap<usize, VecDequ
     let (webhook_tx, webhook_rx) = mpsc::channel(10);
     let (drop_id_tx, mut drop_id_rx) = mpsc::unbounded_channel::<String>();
     let (channel_tx, channel_rx) = tokio::sync::watch::channel(Option::<Channel>::None);
-    let channel_rx2 = channel_tx.subscribe();
 
-    let notify = Arc::new(Notify::new());
-    let drop_campaigns = Arc::new(Mutex::new(current_campaigns.clone()));
+    let releases = Arc::new(ReleaseQueue::default());
+    *global_state.campaigns.lock().await = current_campaigns.clone();
+    global_state.catalog.sync(client.as_ref(), current_campaigns.iter().flatten()).await;
 
-    let clients = global_state.accounts.lock().await;
-    let clients = if let Some(accounts) = clients.clone() {
-        accounts
-    } else {
-        return Err("Didn't find accounts")?;
-    };
-
-    let weebhook_is_active = if !webhook_url.is_empty() {
-        webhook_message_worker(webhook_url, webhook_rx, proxies).await;
+    let webhook_is_active = if !webhook_url.is_empty() {
+        let worker = webhook_message_worker(webhook_url, webhook_rx, proxies).await;
+        *global_state.webhook_worker.lock().await = Some(worker);
         true
     } else {
         false
     };
-    watch_sync(clients.clone(), channel_rx, notify.clone()).await;
-    info!("Watch synchronization task has been successfully initiated");
-    drop_sync(clients.clone(), home_dir, channel_rx2, notify.clone(), webhook_tx, weebhook_is_active, global_state.clone()).await;
-    info!("Drop progress tracker is active");
-    sweep_claim_all(clients.clone(), drop_id_tx, global_state.clone()).await;
-    filter_streams(client.clone(), drop_campaigns.clone(), global_state.clone()).await;
+    load_drop_cache(home_dir, &global_state).await;
+
+    let ctx = FarmingContext {
+        channel_rx,
+        releases: releases.clone(),
+        webhook_tx,
+        webhook_is_active,
+        drop_id_tx,
+        bars: Arc::new(if is_headless() || global_state.log_tail.get().is_some() {
+            MultiProgress::with_draw_target(ProgressDrawTarget::hidden())
+        } else {
+            MultiProgress::new()
+        }),
+    };
+    start_farming(ctx, &global_state).await?;
+    let _tui = global_state.log_tail.get().map(|logs| spawn_tui(global_state.clone(), logs.clone()));
+    info!("Watch synchronization, drop progress and claim sweep tasks have been successfully initiated");
+    filter_streams(client.clone(), global_state.clone()).await;
     info!("Stream filtering has begun");
-    update_stream(channel_tx, notify, global_state.clone()).await;
+    update_stream(channel_tx, releases, global_state.clone());
     info!("Stream priority updated");
 
-    let pending_drops = Arc::new(Mutex::new(HashSet::new()));
-    {
-        let cache = global_state.drop_cache.lock().await.clone();
-        let mut pd_lock = pending_drops.lock().await;
-
-        for game_campaign in current_campaigns {
-            for campaign in game_campaign {
-                let mut campaign_details = match client.get_campaign_details(&campaign.id).await {
-                    Ok(details) => details,
-                    Err(e) => {
-                        error!("Failed to fetch campaign details for {} ({}): {e}", campaign.id, campaign.game.displayName);
-                        continue;
-                    }
-                };
-                for (_, claimed_drops) in &cache {
-                    for drop_id_cache in claimed_drops {
-                        if let Some(pos) = campaign_details.timeBasedDrops.iter().position(|d| d.id == *drop_id_cache) {
-                            campaign_details.timeBasedDrops.remove(pos);
-                        }
-                    }
-                }
-                for drop in campaign_details.timeBasedDrops {
-                    pd_lock.insert(drop.id);
-                }
-        }
-        }
-    }
+    let pending = pending_drops(&global_state, &current_campaigns).await;
+    *global_state.pending_drops.lock().await = pending;
 
     if !games.is_empty() {
-        let games_clone = games.clone();
-        let pending_drops_clone = pending_drops.clone();
-        let global_state_clone = global_state.clone();
-
-        let mut rng = rng();
-        let mut rng = SmallRng::from_rng(&mut rng);
-        tokio::spawn(async move {
-            loop {
-                let jitter = rng.random_range(15..=30);
-                sleep(Duration::from_secs(jitter * 60)).await;
-                info!("Refreshing campaign data...");
-                let campaign_res = match client.get_campaign().await {
-                    Ok(c) => c,
-                    Err(e) => {
-                        error!("Failed to refresh campaign data: {e}");
-                        continue;
-                    }
-                };
-
-                let campaign = campaign_res.dropCampaigns;
-                let mut id_to_index = HashMap::new();
-                let mut grouped: BTreeMap<usize, VecDeque<DropCampaigns>> = BTreeMap::new();
-                group_campaigns(campaign, &mut id_to_index, &mut grouped);
-
-                let new_query_games: VecDeque<VecDeque<DropCampaigns>> = games_clone.iter().filter_map(|game_name| {
-                    let campaigns_for_game: VecDeque<DropCampaigns> = grouped.values().flat_map(|campaigns_vec| {
-                        campaigns_vec.iter().filter(|campaign| campaign.game.displayName.to_lowercase().trim() == game_name.to_lowercase().trim()).cloned()
-                    }).collect();
-                
-                    if campaigns_for_game.is_empty() {
-                        None
-                    } else {
-                        Some(campaigns_for_game)
-                    }
-                }).collect();
-
-                if new_query_games.is_empty() {
-                    info!("No active campaigns found for the configured games. Will check again in 15-30 minutes...");
-                    continue;
-                }
-
-                let mut priority_map = HashMap::new();
-                for (game_idx, campaign_queue) in new_query_games.iter().enumerate() {
-                    let base_prio = ((new_query_games.len() - game_idx) * 10) as u32;
-                    for camp in campaign_queue {
-                        priority_map.insert(camp.id.clone(), base_prio);
-                    }
-                }
-
-                {
-                    let mut lock = global_state_clone.campaign_priority.lock().await;
-                    *lock = priority_map;
-                }
-
-                {
-                    let mut lock = drop_campaigns.lock().await;
-                    *lock = new_query_games.clone();
-                }
-
-                let cache = global_state_clone.drop_cache.lock().await.clone();
-                let mut new_pending = HashSet::new();
-
-                for game_campaign in new_query_games {
-                    for camp in game_campaign {
-                        if let Ok(mut campaign_details) = client.get_campaign_details(&camp.id).await {
-                            if let Some(allow) = &campaign_details.allow.channels {
-                                let mut allow_lock = global_state_clone.allow_channels.lock().await;
-                                let allow_set = allow.clone().into_iter().collect();
-                                allow_lock.insert(camp.id.clone(), allow_set);
-                            }
-
-                            for (_, claimed_drops) in &cache {
-                                for drop_id_cache in claimed_drops {
-                                    if let Some(pos) = campaign_details.timeBasedDrops.iter().position(|d| d.id == *drop_id_cache) {
-                                        campaign_details.timeBasedDrops.remove(pos);
-                                    }
-                                }
-                            }
-                            for drop in campaign_details.timeBasedDrops {
-                                new_pending.insert(drop.id);
-                            }
-                        }
-                    }
-                }
-
-                let mut pd_lock = pending_drops_clone.lock().await;
-                pd_lock.extend(new_pending);
-            }
-        });
+        let games = games.clone();
+        let state = global_state.clone();
+        global_state.tasks.spawn_supervised("campaign_refresh", RESTART_DELAY, move || refresh_campaigns(client.clone(), games.clone(), state.clone()));
     }
 
     let mut empty_notified = false;
     while let Some(drop_id) = drop_id_rx.recv().await {
-        let mut pd_lock = pending_drops.lock().await;
+        let mut pd_lock = global_state.pending_drops.lock().await;
         if pd_lock.remove(&drop_id) {
-            info!("Drop {} processed (remaining: {})", drop_id, pd_lock.len());
+            info!(drop = %drop_id, "Drop {} processed (remaining: {})", drop_id, pd_lock.len());
             empty_notified = false;
         }
 
@@ -399,91 +329,178 @@ async fn main_logic (client: Arc<TwitchClient> ,grouped: BTreeMap<usize, VecDequ
     Ok(())
 }
 
-async fn watch_sync (clients: Vec<Arc<TwitchClient>>, rx: tokio::sync::watch::Receiver<Option<Channel>>, notify: Arc<Notify>) {
-    for client in clients {
-        let notify = notify.clone();
-        let mut watch_rx = rx.clone();
-        tokio::spawn(async move {
-            let mut old_stream_name = String::new();
-            let mut now_watching_stream: Option<(String, String, String)> = None;
-            loop {
-                let watching = {
-                    let current = watch_rx.borrow();
-                    current.clone()
-                };
+/// Re-fetches campaigns every 15-30 minutes (or when asked to) and updates priorities and pending drops.
+async fn refresh_campaigns (client: Arc<dyn DropsBackend>, games: VecDeque<String>, state: Arc<AppState>) {
+    let mut rng = SmallRng::from_rng(&mut rng());
+    loop {
+        let jitter = rng.random_range(15..=30);
+        tokio::select! {
+            _ = sleep(Duration::from_secs(jitter * 60)) => info!("Refreshing campaign data..."),
+            _ = state.campaign_refresh.notified() => info!("Campaign refresh requested, refreshing campaign data..."),
+        }
+        let campaign_res = match retry_backup(&RetryPolicy::GQL, || client.get_campaign()).await {
+            Ok(c) => c,
+            Err(e) => {
+                error!("Failed to refresh campaign data: {e}");
+                continue;
+            }
+        };
 
-                let watching = match watching {
-                    Some(w) => w,
-                    None => {
-                        tokio::select! {
-                            _ = watch_rx.changed() => {},
-                            _ = sleep(Duration::from_secs(STREAM_SLEEP)) => {},
-                        };
-                        continue;
-                    }
-                };
+        let campaign = campaign_res.dropCampaigns;
+        let mut id_to_index = HashMap::new();
+        let mut grouped: BTreeMap<usize, VecDeque<DropCampaigns>> = BTreeMap::new();
+        group_campaigns(campaign, &mut id_to_index, &mut grouped);
+
+        let new_query_games: VecDeque<VecDeque<DropCampaigns>> = games.iter().filter_map(|game_name| {
+            let campaigns_for_game: VecDeque<DropCampaigns> = grouped.values().flat_map(|campaigns_vec| {
+                campaigns_vec.iter().filter(|campaign| campaign.game.displayName.to_lowercase().trim() == game_name.to_lowercase().trim()).cloned()
+            }).collect();
+        
+            if campaigns_for_game.is_empty() {
+                None
+            } else {
+                Some(campaigns_for_game)
+            }
+        }).collect();
+
+        if new_query_games.is_empty() {
+            info!("No active campaigns found for the configured games. Will check again in 15-30 minutes...");
+            continue;
+        }
+
+        let mut priority_map = HashMap::new();
+        for (game_idx, campaign_queue) in new_query_games.iter().enumerate() {
+            let base_prio = ((new_query_games.len() - game_idx) * 10) as u32;
+            for camp in campaign_queue {
+                priority_map.insert(camp.id.clone(), base_prio);
+            }
+        }
+
+        {
+            let mut lock = state.campaign_priority.lock().await;
+            *lock = priority_map;
+        }
 
-                if old_stream_name.is_empty() || old_stream_name != watching.channel_login {
-                    info!("Now actively watching channel {}", watching.channel_login);
-                    old_stream_name = watching.channel_login.clone();
-                    now_watching_stream = None;
+        {
+            let mut lock = state.campaigns.lock().await;
+            *lock = new_query_games.clone();
+        }
+
+        state.catalog.sync(client.as_ref(), new_query_games.iter().flatten()).await;
+        let new_pending = pending_drops(&state, &new_query_games).await;
+        state.pending_drops.lock().await.extend(new_pending);
+    }
+}
+
+pub fn watch_sync (client: Arc<dyn DropsBackend>, mut watch_rx: tokio::sync::watch::Receiver<Option<Channel>>, releases: Arc<ReleaseQueue>, state: Arc<AppState>) -> impl Future<Output = ()> {
+    let span = account_span(client.as_ref());
+    async move {
+        let mut old_stream_name = String::new();
+        let mut now_watching_stream: Option<(String, String, String)> = None;
+        loop {
+            let watching = {
+                let current = watch_rx.borrow();
+                current.clone()
+            };
+
+            let watching = match watching {
+                Some(w) => w,
+                None => {
+                    tokio::select! {
+                        _ = watch_rx.changed() => {},
+                        _ = sleep(Duration::from_secs(STREAM_SLEEP)) => {},
+                    };
+                    continue;
                 }
+            };
 
-                let (stream_id, game_name, game_id) = match &now_watching_stream {
-                    Some(s) => s.clone(),
-                    None => {
-                        let stream_info = match client.get_stream_info(&watching.channel_login).await {
-                            Ok(info) => info,
-                            Err(e) => {
-                                error!("Failed to fetch stream info for {}: {e}", watching.channel_login);
-                                continue;
-                            }
-                        };
+            if old_stream_name.is_empty() || old_stream_name != watching.channel_login {
+                let reason = state.channel_priority.lock().await.get(&watching.channel_id).map_or("unranked", |rank| rank.reason.as_str());
+                info!(channel = %watching.channel_login, reason, "Now actively watching channel {} ({reason})", watching.channel_login);
+                old_stream_name = watching.channel_login.clone();
+                now_watching_stream = None;
+            }
 
-                        if let Some(stream) = stream_info.stream {
-                            let data = (stream.id, stream_info.broadcastSettings.game.name, stream_info.broadcastSettings.game.id);
-                            now_watching_stream = Some(data.clone());
-                            data
-                        } else {
-                            debug!("Stream is not live: {}", watching.channel_login);
-                            notify.notify_one();
-                            tokio::select! {
-                                _ = watch_rx.changed() => {},
-                                _ = sleep(Duration::from_secs(STREAM_SLEEP)) => {},
-                            };
+            let (stream_id, game_name, game_id) = match &now_watching_stream {
+                Some(s) => s.clone(),
+                None => {
+                    let stream_info = match client.get_stream_info(&watching.channel_login).await {
+                        Ok(info) => info,
+                        Err(e) => {
+                            error!(channel = %watching.channel_login, "Failed to fetch stream info for {}: {e}", watching.channel_login);
                             continue;
                         }
-                    }
-                };
+                    };
 
-                match client.send_watch(&watching.channel_login, &stream_id, &watching.channel_id, Some(&game_name), Some(&game_id)).await {
-                    Ok(_) => {
-                        tokio::select! {
-                            _ = watch_rx.changed() => {},
-                            _ = sleep(Duration::from_secs(STREAM_SLEEP)) => {},
-                        };
-                    },
-                    Err(e) => {
-                        error!("Failed to send watch heartbeat for {}: {e}", watching.channel_login);
+                    if let Some(stream) = stream_info.stream {
+                        let data = (stream.id, stream_info.broadcastSettings.game.name, stream_info.broadcastSettings.game.id);
+                        now_watching_stream = Some(data.clone());
+                        data
+                    } else {
+                        debug!("Stream is not live: {}", watching.channel_login);
+                        releases.release(&watching, ReleaseReason::NotLive);
                         tokio::select! {
                             _ = watch_rx.changed() => {},
                             _ = sleep(Duration::from_secs(STREAM_SLEEP)) => {},
                         };
+                        continue;
                     }
                 }
+            };
+
+            if state.shutdown.is_cancelled() || state.paused_accounts.lock().await.contains(&client.login().to_string()) {
+                tokio::select! {
+                    _ = watch_rx.changed() => {},
+                    _ = sleep(Duration::from_secs(STREAM_SLEEP)) => {},
+                };
+                continue;
             }
-        });
+
+            let watch = Watch { channel_login: watching.channel_login.clone(), channel_id: watching.channel_id.clone(), broadcast_id: stream_id, game_name, game_id };
+            match client.send_watch(&watch).await {
+                Ok(_) => {
+                    state.heartbeats.lock().await.insert(client.login().to_string(), Heartbeat { channel_id: watching.channel_id.clone(), at: Instant::now() });
+                    tokio::select! {
+                        _ = watch_rx.changed() => {},
+                        _ = sleep(Duration::from_secs(STREAM_SLEEP)) => {},
+                    };
+                },
+                Err(e) => {
+                    error!(channel = %watching.channel_login, "Failed to send watch heartbeat for {}: {e}", watching.channel_login);
+                    tokio::select! {
+                        _ = watch_rx.changed() => {},
+                        _ = sleep(Duration::from_secs(STREAM_SLEEP)) => {},
+                    };
+                }
+            }
+        }
+    }.instrument(span)
+}
+
+/// Drops of `campaigns` that no account has claimed yet, read from the campaign catalog.
+async fn pending_drops (state: &AppState, campaigns: &VecDeque<VecDeque<DropCampaigns>>) -> HashSet<String> {
+    let cache = state.drop_cache.lock().await.clone();
+    let claimed: HashSet<&String> = cache.values().flatten().collect();
+    let mut pending = HashSet::new();
+    for campaign in campaigns.iter().flatten() {
+        let drops = state.catalog.drops(&campaign.id).await;
+        remember_campaign_drops(state, &campaign.id, &drops).await;
+        pending.extend(drops.into_iter().map(|drop| drop.id).filter(|id| !claimed.contains(id)));
     }
+    pending
+}
+
+async fn remember_campaign_drops (state: &AppState, campaign_id: &str, drops: &[TimeBasedDropsCampaignDetails]) {
+    state.campaign_drops.lock().await.insert(campaign_id.to_string(), drops.iter().map(|d| d.id.clone()).collect());
+    state.drop_names.lock().await.extend(drops.iter().map(|d| (d.id.clone(), d.name.clone())));
 }
 
-async fn drop_sync(clients: Vec<Arc<TwitchClient>>, home_dir: &Path, rx_watch: tokio::sync::watch::Receiver<Option<Channel>>, notify: Arc<Notify>, webhook_tx: mpsc::Sender<WebhookSendFormat>, webhook_is_active: bool, state: Arc<AppState>) {
+async fn load_drop_cache (home_dir: &Path, state: &AppState) {
     let cache_path = home_dir.join("cache.json");
     let old_cache_path = home_dir.join("cash.json");
 
-    if old_cache_path.exists() {
-        if let Err(e) = fs::rename(&old_cache_path, &cache_path).await {
-            error!("Failed to rename cache file: {e}");
-        }
+    if old_cache_path.exists() && let Err(e) = fs::rename(&old_cache_path, &cache_path).await {
+        error!("Failed to rename cache file: {e}");
     }
 
     if !cache_path.exists() {
@@ -507,183 +524,206 @@ async fn drop_sync(clients: Vec<Arc<TwitchClient>>, home_dir: &Path, rx_watch: t
         }
     }
     let _ = state.cache_path.set(cache_path);
+}
 
-    let bars = Arc::new(MultiProgress::new());
-
-    for client in clients {
-        let notify = notify.clone();
-        let webhook_tx = webhook_tx.clone();
-        let bars = bars.clone();
-        let state_clone = state.clone();
-        let mut rx_watch_clone = rx_watch.clone();
-
-        tokio::spawn(async move {
-            //bar
-            let bar = bars.add(ProgressBar::new(1));
-            bar.set_style(ProgressStyle::with_template("[{bar:40.cyan/blue}] {percent:.1}% ({pos}/{len} min) {msg}").expect("Failed to create progress bar style"));
-            bar.set_message("Initialization...");
-            bar.enable_steady_tick(Duration::from_millis(500));
-
-            let mut last_message = String::new();
-            let mut last_drop_id = String::new();
-
-            loop {
-                let watching = {
-                    let current = rx_watch_clone.borrow();
-                    current.clone()
-                };
-
-                let watching = match watching {
-                    Some(w) => w,
-                    None => {
-                        tokio::select! {
-                            _ = rx_watch_clone.changed() => {},
-                            _ = sleep(Duration::from_secs(30)) => {},
-                        };
-                        continue;
-                    }
-                };
-
-                let drop_progress = match client.get_current_drop_progress_on_channel(&watching.channel_login).await {
-                    Ok(progress) => progress,
-                    Err(e) => {
-                        error!("Failed to get drop progress for channel {}: {e}", watching.channel_login);
-                        tokio::select! {
-                            _ = rx_watch_clone.changed() => {},
-                            _ = sleep(Duration::from_secs(30)) => {},
-                        };
-                        continue;
-                    }
-                };
-                debug!("{:?}", drop_progress);
+pub fn drop_sync (client: Arc<dyn DropsBackend>, ctx: FarmingContext, state_clone: Arc<AppState>) -> impl Future<Output = ()> {
+    let FarmingContext { channel_rx: mut rx_watch_clone, releases, webhook_tx, webhook_is_active, bars, .. } = ctx;
+
+    let span = account_span(client.as_ref());
+    let progress_interval = Duration::from_secs(state_clone.intervals.get().cloned().unwrap_or_default().progress_secs);
+    async move {
+        //bar
+        let bar = bars.add(ProgressBar::new(1));
+        bar.set_style(ProgressStyle::with_template("[{bar:40.cyan/blue}] {percent:.1}% ({pos}/{len} min) {msg}").expect("Failed to create progress bar style"));
+        bar.set_message("Initialization...");
+        bar.enable_steady_tick(Duration::from_millis(500));
+
+        let mut last_message = String::new();
+        let mut last_drop_id = String::new();
+        let mut watched_minutes: Option<(String, u64)> = None;
+
+        loop {
+            let watching = {
+                let current = rx_watch_clone.borrow();
+                current.clone()
+            };
+
+            let watching = match watching {
+                Some(w) => w,
+                None => {
+                    tokio::select! {
+                        _ = rx_watch_clone.changed() => {},
+                        _ = sleep(progress_interval) => {},
+                    };
+                    continue;
+                }
+            };
 
-                let current_minutes = drop_progress.currentMinutesWatched;
-                let required_minutes = drop_progress.requiredMinutesWatched;
-                let has_active_drop = !drop_progress.dropID.is_empty();
-                let tracked_drop_id = drop_progress.dropID.clone();
+            let drop_progress = match client.get_current_drop_progress_on_channel(&watching.channel_login).await {
+                Ok(progress) => progress,
+                Err(e) => {
+                    error!(channel = %watching.channel_login, "Failed to get drop progress for channel {}: {e}", watching.channel_login);
+                    tokio::select! {
+                        _ = rx_watch_clone.changed() => {},
+                        _ = sleep(progress_interval) => {},
+                    };
+                    continue;
+                }
+            };
+            debug!("{:?}", drop_progress);
+
+            let current_minutes = drop_progress.currentMinutesWatched;
+            let required_minutes = drop_progress.requiredMinutesWatched;
+            let has_active_drop = !drop_progress.dropID.is_empty();
+            let tracked_drop_id = drop_progress.dropID.clone();
+
+            let leave_channel = !has_active_drop;
+            let login = client.login().to_string();
+            let paused = state_clone.paused_accounts.lock().await.contains(&login);
+
+            let message = if paused {
+                "⏸ Paused"
+            } else if leave_channel {
+                "No active drop • waiting..."
+            } else if required_minutes > 0 && current_minutes >= required_minutes {
+                "✅ Ready to claim!"
+            } else {
+                "Watching"
+            };
 
-                let leave_channel = !has_active_drop;
+            if has_active_drop {
+                if let Some((drop_id, minutes)) = &watched_minutes && *drop_id == tracked_drop_id && current_minutes > *minutes {
+                    let game = drop_progress.game.as_ref().map(|game| game.displayName.clone()).unwrap_or_else(|| "Unknown".to_string());
+                    METRICS.minutes_watched.get_or_create(&AccountGameLabels { account: login.clone(), game }).inc_by(current_minutes - minutes);
+                }
+                watched_minutes = Some((tracked_drop_id.clone(), current_minutes));
+            }
 
-                let message = if leave_channel {
-                    "No active drop • waiting..."
-                } else if required_minutes > 0 && current_minutes >= required_minutes {
-                    "✅ Ready to claim!"
+            state_clone.account_status.lock().await.insert(login.clone(), AccountStatus {
+                channel: Some(watching.clone()),
+                drop_id: has_active_drop.then(|| tracked_drop_id.clone()),
+                drop_name: state_clone.drop_names.lock().await.get(&tracked_drop_id).cloned(),
+                game: drop_progress.game.as_ref().map(|game| game.displayName.clone()),
+                current_minutes,
+                required_minutes,
+                status: message.to_string(),
+            });
+
+            if webhook_is_active && last_message != message {
+                let progress_percent = if required_minutes > 0 {
+                    ((current_minutes as f64 / required_minutes as f64) * 100.0) as u8
+                } else { 0 };
+
+                let progress_text = format!("{}m / {}m", current_minutes, required_minutes);
+
+                let (game_name, game_avatar_url) = if drop_progress.dropID.is_empty() {
+                    ("None".to_string(), "None".to_string())
                 } else {
-                    "Watching"
-                };
-
-                if webhook_is_active && last_message != message {
-                    let progress_percent = if required_minutes > 0 {
-                        ((current_minutes as f64 / required_minutes as f64) * 100.0) as u8
-                    } else { 0 };
-
-                    let progress_text = format!("{}m / {}m", current_minutes, required_minutes);
-
-                    let (game_name, game_avatar_url) = if drop_progress.dropID.is_empty() {
-                        ("None".to_string(), "None".to_string())
-                    } else {
-                        let inv = match client.get_inventory().await {
-                            Ok(i) => i,
-                            Err(e) => {
-                                error!("Failed to fetch inventory for {}: {e}", client.login.clone().unwrap_or_default());
-                                continue;
-                            }
-                        };
-                        if let Some(found) = inv.inventory.dropCampaignsInProgress.as_ref().and_then(|campaigns| {
-                            campaigns.iter().find(|campaign| {
-                                campaign.timeBasedDrops.iter().any(|time_based| {
-                                    time_based.id == drop_progress.dropID
-                                })
-                            })
-                        }) {
-                            (found.game.name.clone(), found.imageURL.clone())   
-                        } else {
-                            (drop_progress.game.map(|game| game.displayName).unwrap_or_else(|| "Unknown".to_string()), "None".to_string())
+                    let inv = match client.get_inventory().await {
+                        Ok(i) => i,
+                        Err(e) => {
+                            error!("Failed to fetch inventory for {}: {e}", client.login().to_string());
+                            continue;
                         }
                     };
+                    if let Some(found) = inv.inventory.dropCampaignsInProgress.as_ref().and_then(|campaigns| {
+                        campaigns.iter().find(|campaign| {
+                            campaign.timeBasedDrops.iter().any(|time_based| {
+                                time_based.id == drop_progress.dropID
+                            })
+                        })
+                    }) {
+                        (found.game.name.clone(), found.imageURL.clone())   
+                    } else {
+                        (drop_progress.game.map(|game| game.displayName).unwrap_or_else(|| "Unknown".to_string()), "None".to_string())
+                    }
+                };
 
-                    let payload = WebhookSendFormat {
-                        twitch_name: client.login.clone().unwrap_or("undefined".to_string()),
-                        game_name,
-                        game_avatar_url,
-                        streamer_name: watching.channel_login.clone(),
-                        progress_percent,
-                        progress_text,
-                        status: message.to_string()
-                    };
-                    let _ = webhook_tx.send(payload).await;
-                }
-
-                last_message = message.to_string();
+                let payload = WebhookSendFormat {
+                    twitch_name: client.login().to_string(),
+                    game_name,
+                    game_avatar_url,
+                    streamer_name: watching.channel_login.clone(),
+                    progress_percent,
+                    progress_text,
+                    status: message.to_string()
+                };
+                let _ = webhook_tx.send(payload).await;
+            }
 
-                let message = format!("{} | {}", client.login.clone().unwrap_or_default(), message);
-            
-                if leave_channel || tracked_drop_id.is_empty() {
-                    last_drop_id = String::new()
-                } else if tracked_drop_id != last_drop_id {
-                    last_drop_id = tracked_drop_id.clone();
-                    bar.set_position(0);
-                    bar.set_length(required_minutes.max(1));
-                    bar.set_message(message);
-                } else {
-                    bar.set_message(message);
-                }
-            
+            last_message = message.to_string();
+
+            let message = match state_clone.proxy_pool.lock().await.status_label(&login) {
+                Some(proxy_status) => format!("{login} [proxy {proxy_status}] | {message}"),
+                None => format!("{login} | {message}"),
+            };
+        
+            if leave_channel || tracked_drop_id.is_empty() {
+                last_drop_id = String::new()
+            } else if tracked_drop_id != last_drop_id {
+                last_drop_id = tracked_drop_id.clone();
+                let campaign = state_clone.campaign_drops.lock().await.iter().find(|(_, drops)| drops.contains(&tracked_drop_id)).map(|(id, _)| id.clone()).unwrap_or_default();
+                let drop_name = state_clone.drop_names.lock().await.get(&tracked_drop_id).cloned().unwrap_or_default();
+                info!(channel = %watching.channel_login, campaign = %campaign, drop = %tracked_drop_id, current_minutes, required_minutes, "Farming drop {drop_name} on {}", watching.channel_login);
+                bar.set_position(0);
                 bar.set_length(required_minutes.max(1));
-                bar.set_position(current_minutes);
-            
-                if leave_channel {
-                    debug!("No active drop on {}, releasing channel from pool", watching.channel_login);
-                    notify.notify_one();
-                    let mut channel_pool_lock = state_clone.channel_pool.lock().await;
-                    channel_pool_lock.retain(|c| c.channel_id != watching.channel_id);
-                }
+                bar.set_message(message);
+            } else {
+                bar.set_message(message);
+            }
+        
+            bar.set_length(required_minutes.max(1));
+            bar.set_position(current_minutes);
+        
+            if leave_channel {
+                debug!("No active drop on {}, releasing channel from pool", watching.channel_login);
+                releases.release(&watching, ReleaseReason::NoActiveDrop);
+                let mut channel_pool_lock = state_clone.channel_pool.lock().await;
+                channel_pool_lock.retain(|c| c.channel_id != watching.channel_id);
+            }
 
-                tokio::select! {
-                    _ = rx_watch_clone.changed() => {},
-                    _ = sleep(Duration::from_secs(30)) => {},
-                }
+            tokio::select! {
+                _ = rx_watch_clone.changed() => {},
+                _ = sleep(progress_interval) => {},
             }
-        });
-    }
+        }
+    }.instrument(span)
 }
 
-async fn sweep_claim_all(clients: Vec<Arc<TwitchClient>>, drop_id_tx: UnboundedSender<String>, state: Arc<AppState>) {
-    for client in clients {
-        let drop_id_tx = drop_id_tx.clone();
-        let state = state.clone();
-        tokio::spawn(async move {
-            loop {
-                sleep(Duration::from_secs(90)).await;
-
-                let inv = match client.get_inventory().await {
-                    Ok(inv) => inv,
-                    Err(e) => {
-                        error!("Sweep: Failed to fetch inventory for {}: {e}", client.login.clone().unwrap_or_default());
-                        continue;
-                    }
-                };
-                if let Some(campaigns) = inv.inventory.dropCampaignsInProgress {
-                    for campaign in campaigns {
-                        for drop in campaign.timeBasedDrops {
-                            let ready = drop.requiredMinutesWatched > 0 && drop.self_drop.currentMinutesWatched >= drop.requiredMinutesWatched;
-                            if !ready || drop.self_drop.isClaimed { continue; }
-                            if let Some(instance_id) = drop.self_drop.dropInstanceID {
-                                claim_now_or_queue(client.clone(), drop.id.clone(), instance_id, &drop_id_tx, &state).await;
-                            } else { 
-                                continue 
-                            };
-                        }
+pub fn claim_sweep (client: Arc<dyn DropsBackend>, drop_id_tx: UnboundedSender<String>, state: Arc<AppState>) -> impl Future<Output = ()> {
+    let span = account_span(client.as_ref());
+    let sweep_interval = Duration::from_secs(state.intervals.get().cloned().unwrap_or_default().claim_sweep_secs);
+    async move {
+        loop {
+            sleep(sweep_interval).await;
+
+            let inv = match client.get_inventory().await {
+                Ok(inv) => inv,
+                Err(e) => {
+                    error!("Sweep: Failed to fetch inventory for {}: {e}", client.login().to_string());
+                    continue;
+                }
+            };
+            if let Some(campaigns) = inv.inventory.dropCampaignsInProgress {
+                for campaign in campaigns {
+                    for drop in campaign.timeBasedDrops {
+                        let ready = drop.requiredMinutesWatched > 0 && drop.self_drop.currentMinutesWatched >= drop.requiredMinutesWatched;
+                        if !ready || drop.self_drop.isClaimed { continue; }
+                        if let Some(instance_id) = drop.self_drop.dropInstanceID {
+                            claim_now_or_queue(client.clone(), drop.id.clone(), instance_id, &campaign.game.name, &drop_id_tx, &state).await;
+                        } else { 
+                            continue 
+                        };
                     }
-                } else { 
-                    continue 
-                };
-            }
-        });
-    }
+                }
+            } else { 
+                continue 
+            };
+        }
+    }.instrument(span)
 }
 
-async fn persist_drop_cache(state: &AppState) {
+pub async fn persist_drop_cache(state: &AppState) {
     if let Some(path) = state.cache_path.get() {
         let json_str = {
             let cache = state.drop_cache.lock().await;
@@ -695,18 +735,27 @@ async fn persist_drop_cache(state: &AppState) {
                 }
             }
         };
-        if let Err(e) = fs::write(path, json_str).await {
+        // Write then rename, so a kill mid-write can't leave a truncated cache behind.
+        let staged = path.with_extension("json.tmp");
+        if let Err(e) = async { fs::write(&staged, json_str).await?; fs::rename(&staged, path).await }.await {
             error!("Failed to write cache to file {}: {e}", path.display());
         }
     }
 }
 
-async fn claim_now_or_queue(client: Arc<TwitchClient>, drop_id: String, instance_id: String, drop_id_tx: &UnboundedSender<String>, state: &AppState) {
-    let user_id = client.user_id.clone().unwrap_or_default();
+async fn claim_now_or_queue(client: Arc<dyn DropsBackend>, drop_id: String, instance_id: String, game: &str, drop_id_tx: &UnboundedSender<String>, state: &AppState) {
+    if state.shutdown.is_cancelled() {
+        return;
+    }
+    let user_id = client.user_id().to_string();
+
+    let result = state.claims.track_future(retry_backup(&RetryPolicy::GQL, || client.claim_drop(&instance_id))).await;
+    record_claim(state, client.login().to_string(), drop_id.clone(), &result).await;
 
-    match client.claim_drop(&instance_id).await {
+    match result {
         Ok(_) | Err(ClaimDropError::DropAlreadyClaimed) => {
-            info!("✅ Claimed drop {drop_id} ({})", client.login.clone().unwrap_or_default());
+            info!(drop = %drop_id, "✅ Claimed drop {drop_id} ({})", client.login().to_string());
+            METRICS.drops_claimed.get_or_create(&AccountGameLabels { account: client.login().to_string(), game: game.to_string() }).inc();
 
             let mut cache = state.drop_cache.lock().await;
             cache.entry(user_id).or_default().insert(drop_id.clone());
@@ -717,7 +766,20 @@ async fn claim_now_or_queue(client: Arc<TwitchClient>, drop_id: String, instance
             let _ = drop_id_tx.send(drop_id);
         },
         Err(e) => {
-            error!("Claim attempt failed for drop {drop_id} ({}): {e}", client.login.clone().unwrap_or_default());
+            METRICS.claim_failed(&e);
+            error!(drop = %drop_id, "Claim attempt failed for drop {drop_id} ({}): {e}", client.login().to_string());
         }
     }
-}
\ No newline at end of file
+}
+
+async fn record_claim<T> (state: &AppState, login: String, drop_id: String, result: &Result<T, ClaimDropError>) {
+    let (success, error) = match result {
+        Ok(_) | Err(ClaimDropError::DropAlreadyClaimed) => (true, None),
+        Err(e) => (false, Some(e.to_string())),
+    };
+    let mut history = state.claim_history.lock().await;
+    if history.len() >= CLAIM_HISTORY_LIMIT {
+        history.pop_front();
+    }
+    history.push_back(ClaimRecord { login, drop_id, claimed_at: chrono::Local::now().to_rfc3339(), success, error });
+}
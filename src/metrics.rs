@@ -0,0 +1,164 @@
+use std::{sync::LazyLock, time::Instant};
+
+use prometheus_client::{encoding::{EncodeLabelSet, text::encode}, metrics::{counter::Counter, family::Family, gauge::Gauge, histogram::{Histogram, exponential_buckets}}, registry::Registry};
+use twitch_gql_rs::error::{ClaimDropError, TwitchError};
+
+use crate::{backend::{BackendFuture, Layer}, error::Classify, r#static::AppState};
+
+const ACCOUNT_STATES: [&str; 4] = ["farming", "idle", "paused", "proxy_dead"];
+
+pub static METRICS: LazyLock<Metrics> = LazyLock::new(Metrics::new);
+
+#[derive(Debug, Clone, Hash, PartialEq, Eq, EncodeLabelSet)]
+pub struct StateLabels {
+    pub state: String,
+}
+
+#[derive(Debug, Clone, Hash, PartialEq, Eq, EncodeLabelSet)]
+pub struct AccountGameLabels {
+    pub account: String,
+    pub game: String,
+}
+
+#[derive(Debug, Clone, Hash, PartialEq, Eq, EncodeLabelSet)]
+pub struct KindLabels {
+    pub kind: String,
+}
+
+#[derive(Debug, Clone, Hash, PartialEq, Eq, EncodeLabelSet)]
+pub struct ClassLabels {
+    pub class: String,
+}
+
+#[derive(Debug, Clone, Hash, PartialEq, Eq, EncodeLabelSet)]
+pub struct OperationLabels {
+    pub operation: String,
+}
+
+#[derive(Debug, Clone, Hash, PartialEq, Eq, EncodeLabelSet)]
+pub struct OperationOutcomeLabels {
+    pub operation: String,
+    pub outcome: String,
+}
+
+/// Prometheus metrics served on `/metrics`. Counters are fed from the call sites;
+/// gauges that mirror `AppState` are refreshed on every scrape.
+#[derive(Debug)]
+pub struct Metrics {
+    registry: Registry,
+    pub accounts: Family<StateLabels, Gauge>,
+    pub accounts_quarantined: Counter,
+    pub minutes_watched: Family<AccountGameLabels, Counter>,
+    pub drops_claimed: Family<AccountGameLabels, Counter>,
+    pub claim_failures: Family<KindLabels, Counter>,
+    pub gql_requests: Family<OperationOutcomeLabels, Counter>,
+    pub gql_latency: Family<OperationLabels, Histogram, fn() -> Histogram>,
+    pub gql_throttled: Family<ClassLabels, Counter>,
+    pub pubsub_connected: Gauge,
+    pub pubsub_reconnects: Counter,
+    pub channel_pool_size: Gauge,
+    pub webhook_failures: Counter,
+}
+
+fn gql_histogram () -> Histogram {
+    Histogram::new(exponential_buckets(0.05, 2.0, 10))
+}
+
+impl Metrics {
+    fn new () -> Self {
+        let mut metrics = Metrics {
+            registry: Registry::with_prefix("dropsentry"),
+            accounts: Family::default(),
+            accounts_quarantined: Counter::default(),
+            minutes_watched: Family::default(),
+            drops_claimed: Family::default(),
+            claim_failures: Family::default(),
+            gql_requests: Family::default(),
+            gql_latency: Family::new_with_constructor(gql_histogram),
+            gql_throttled: Family::default(),
+            pubsub_connected: Gauge::default(),
+            pubsub_reconnects: Counter::default(),
+            channel_pool_size: Gauge::default(),
+            webhook_failures: Counter::default(),
+        };
+
+        let registry = &mut metrics.registry;
+        registry.register("accounts", "Registered accounts by health state", metrics.accounts.clone());
+        registry.register("accounts_quarantined", "Sessions moved to delete_accounts/ after failing validation", metrics.accounts_quarantined.clone());
+        registry.register("minutes_watched", "Drop minutes watched per account and game", metrics.minutes_watched.clone());
+        registry.register("drops_claimed", "Drops claimed per account and game", metrics.drops_claimed.clone());
+        registry.register("claim_failures", "Failed drop claims by error kind", metrics.claim_failures.clone());
+        registry.register("gql_requests", "Twitch GQL requests by operation and outcome", metrics.gql_requests.clone());
+        registry.register("gql_request_duration_seconds", "Twitch GQL request latency by operation", metrics.gql_latency.clone());
+        registry.register("gql_throttled", "GQL requests rate limited by Twitch, by request class", metrics.gql_throttled.clone());
+        registry.register("pubsub_connected", "Whether the PubSub WebSocket is connected", metrics.pubsub_connected.clone());
+        registry.register("pubsub_reconnects", "PubSub WebSocket reconnects", metrics.pubsub_reconnects.clone());
+        registry.register("channel_pool_size", "Channels currently in the channel pool", metrics.channel_pool_size.clone());
+        registry.register("webhook_failures", "Discord webhook deliveries that failed", metrics.webhook_failures.clone());
+        metrics
+    }
+
+    /// Refreshes the state-derived gauges and renders the registry in the Prometheus text format.
+    pub async fn render (&self, state: &AppState) -> Result<String, std::fmt::Error> {
+        let logins: Vec<String> = state.accounts.lock().await.iter().flatten().map(|c| c.login().to_string()).collect();
+        let statuses = state.account_status.lock().await.clone();
+        let paused = state.paused_accounts.lock().await.clone();
+        let pool = state.proxy_pool.lock().await;
+
+        let mut counts = ACCOUNT_STATES.map(|s| (s, 0));
+        for login in &logins {
+            let proxy_dead = pool.proxy_of(login).and_then(|p| pool.health(p)).is_some_and(|h| h.dead);
+            let state = if proxy_dead {
+                "proxy_dead"
+            } else if paused.contains(login) {
+                "paused"
+            } else if statuses.get(login).is_some_and(|s| s.drop_id.is_some()) {
+                "farming"
+            } else {
+                "idle"
+            };
+            if let Some((_, count)) = counts.iter_mut().find(|(s, _)| *s == state) {
+                *count += 1;
+            }
+        }
+        drop(pool);
+        for (state, count) in counts {
+            self.accounts.get_or_create(&StateLabels { state: state.to_string() }).set(count);
+        }
+        self.channel_pool_size.set(state.channel_pool.lock().await.len() as i64);
+
+        let mut body = String::new();
+        encode(&mut body, &self.registry)?;
+        Ok(body)
+    }
+
+    pub fn claim_failed (&self, error: &ClaimDropError) {
+        let kind = match error {
+            ClaimDropError::FailedClaimDrops(_) => "rejected",
+            ClaimDropError::DropAlreadyClaimed => "already_claimed",
+            ClaimDropError::TwitchError(TwitchError::HttpError(_)) => "http",
+            ClaimDropError::TwitchError(TwitchError::ReqwestProblem(_)) => "network",
+            ClaimDropError::TwitchError(TwitchError::JsonError(_) | TwitchError::MissingField(_)) => "bad_response",
+            ClaimDropError::TwitchError(TwitchError::TwitchError(_)) => "twitch",
+        };
+        self.claim_failures.get_or_create(&KindLabels { kind: kind.to_string() }).inc();
+    }
+}
+
+/// Times every backend call and counts it by operation and outcome.
+#[derive(Debug)]
+pub struct Metered;
+
+impl Layer for Metered {
+    fn call<'a, T: Send + 'a, E: Classify + Send + 'a> (&'a self, _login: &'a str, operation: &'static str, request: BackendFuture<'a, T, E>) -> BackendFuture<'a, T, E> {
+        Box::pin(async move {
+            let started = Instant::now();
+            let result = request.await;
+            let labels = OperationLabels { operation: operation.to_string() };
+            METRICS.gql_latency.get_or_create(&labels).observe(started.elapsed().as_secs_f64());
+            let outcome = if result.is_ok() { "ok" } else { "error" };
+            METRICS.gql_requests.get_or_create(&OperationOutcomeLabels { operation: labels.operation, outcome: outcome.to_string() }).inc();
+            result
+        })
+    }
+}
@@ -0,0 +1,378 @@
+use std::{cmp::Reverse, collections::{BTreeMap, HashMap}, path::{Path, PathBuf}, sync::Arc, time::{Duration, Instant}};
+
+use futures_util::future::join_all;
+use reqwest::{Client, Proxy, StatusCode, Url};
+use tokio::{fs, net::TcpStream, time::{sleep, timeout}};
+use tracing::{error, info, warn};
+
+use crate::{accounts::rebind_account, config::ProxyHealthConfig, error::AppError, r#static::AppState, tasks::RESTART_DELAY};
+
+pub const PROXY_MAP_FILE: &str = "proxy_map.json";
+
+/// Result of the latest health checks for a single proxy.
+/// `score` is a moving average (0-100) of recent checks, where slow answers score lower.
+#[derive(Debug, Clone, Default)]
+pub struct ProxyHealth {
+    pub score: u8,
+    pub latency_ms: Option<u64>,
+    pub consecutive_failures: u32,
+    pub dead: bool,
+    pub last_error: Option<String>,
+    checks: u32,
+}
+
+impl ProxyHealth {
+    pub fn label (&self) -> String {
+        if self.dead {
+            "dead".to_string()
+        } else if self.checks == 0 {
+            "unchecked".to_string()
+        } else if self.consecutive_failures > 0 {
+            format!("{}% · failing ({}x)", self.score, self.consecutive_failures)
+        } else {
+            format!("{}% · {} ms", self.score, self.latency_ms.unwrap_or_default())
+        }
+    }
+}
+
+/// Sticky account→proxy assignment persisted in `data/proxy_map.json`.
+/// An account keeps its proxy across restarts; it is only moved when that proxy
+/// disappears from the list or is marked dead. Overrides from config always win.
+#[derive(Debug, Default)]
+pub struct ProxyPool {
+    proxies: Vec<String>,
+    overrides: HashMap<String, String>,
+    assignments: BTreeMap<String, String>,
+    health: HashMap<String, ProxyHealth>,
+    health_config: ProxyHealthConfig,
+    path: PathBuf,
+}
+
+impl ProxyPool {
+    pub async fn load (home_dir: &Path, proxies: Vec<String>, overrides: HashMap<String, String>, health_config: ProxyHealthConfig) -> Result<Self, AppError> {
+        let path = home_dir.join(PROXY_MAP_FILE);
+        let assignments = if path.exists() {
+            match serde_json::from_str::<BTreeMap<String, String>>(&fs::read_to_string(&path).await?) {
+                // Maps written before entries were normalized still point at the raw list lines.
+                Ok(map) => map.into_iter().map(|(login, proxy)| (login, parse_proxy(&proxy).unwrap_or(proxy))).collect(),
+                Err(e) => {
+                    error!("Proxy map at {} is corrupted, starting with an empty map: {e}", path.display());
+                    BTreeMap::new()
+                }
+            }
+        } else {
+            BTreeMap::new()
+        };
+        Ok(ProxyPool { proxies, overrides, assignments, health: HashMap::new(), health_config, path })
+    }
+
+    pub fn proxies (&self) -> &[String] {
+        &self.proxies
+    }
+
+    pub fn health (&self, proxy: &str) -> Option<&ProxyHealth> {
+        self.health.get(proxy)
+    }
+
+    fn is_dead (&self, proxy: &str) -> bool {
+        self.health.get(proxy).is_some_and(|h| h.dead)
+    }
+
+    fn is_usable (&self, proxy: &str) -> bool {
+        self.proxies.iter().any(|p| p == proxy) && !self.is_dead(proxy)
+    }
+
+    /// The current proxy of `login` without assigning one.
+    pub fn proxy_of (&self, login: &str) -> Option<&String> {
+        self.overrides.get(login).or_else(|| self.assignments.get(login))
+    }
+
+    /// Number of accounts assigned to `proxy`, overrides excluded.
+    pub fn load_of (&self, proxy: &str) -> usize {
+        self.assignments.values().filter(|p| *p == proxy).count()
+    }
+
+    /// Short proxy status for the progress bars, `None` for direct connections.
+    pub fn status_label (&self, login: &str) -> Option<String> {
+        let proxy = self.proxy_of(login)?;
+        Some(self.health.get(proxy).map(ProxyHealth::label).unwrap_or_else(|| "unchecked".to_string()))
+    }
+
+    /// The healthy proxy with the fewest accounts assigned. Ties go to the better score, then list order.
+    pub fn least_loaded (&self) -> Option<String> {
+        self.least_loaded_besides(&[])
+    }
+
+    /// Like [`least_loaded`](Self::least_loaded), counting `pending` as assigned too,
+    /// for proxies handed out to logins that don't have an assignment yet.
+    pub fn least_loaded_besides (&self, pending: &[String]) -> Option<String> {
+        let mut load: HashMap<&str, usize> = HashMap::new();
+        for proxy in self.assignments.values().chain(pending) {
+            *load.entry(proxy.as_str()).or_default() += 1;
+        }
+        self.proxies.iter()
+            .filter(|p| !self.is_dead(p))
+            .min_by_key(|p| (load.get(p.as_str()).copied().unwrap_or(0), Reverse(self.health.get(*p).map(|h| h.score).unwrap_or(0))))
+            .cloned()
+    }
+
+    /// Returns the proxy for `login`, assigning one if needed.
+    /// `preferred` is used for accounts without an assignment (e.g. the proxy a fresh login went through).
+    /// The second value is `true` when the persisted map changed.
+    pub fn assign (&mut self, login: &str, preferred: Option<&str>) -> (Option<String>, bool) {
+        if let Some(proxy) = self.overrides.get(login) {
+            if self.is_dead(proxy) {
+                warn!("Pinned proxy for account {login} is dead, keeping it because of proxy_overrides");
+            }
+            return (Some(proxy.clone()), false);
+        }
+
+        if let Some(current) = self.assignments.get(login) {
+            if self.is_usable(current) {
+                return (Some(current.clone()), false);
+            }
+            let reason = if self.is_dead(current) { "marked dead" } else { "removed from the list" };
+            warn!("Proxy for account {login} was {reason}, reassigning");
+        }
+
+        let mut next = preferred.filter(|p| self.is_usable(p)).map(str::to_string).or_else(|| self.least_loaded());
+        // Without any proxies in the list a direct connection is what's configured, not a fallback.
+        if next.is_none() && !self.proxies.is_empty() && !self.health_config.direct_fallback {
+            next = self.assignments.get(login).or_else(|| self.proxies.first()).cloned();
+            if let Some(stay) = &next {
+                warn!("Every proxy is dead, keeping account {login} behind {} until one recovers", redact(stay));
+            }
+        }
+        let changed = match &next {
+            Some(proxy) => self.assignments.insert(login.to_string(), proxy.clone()).as_ref() != Some(proxy),
+            None => self.assignments.remove(login).is_some(),
+        };
+        if changed {
+            info!("Account {login} assigned to {}", if next.is_some() { "a new proxy" } else { "a direct connection" });
+        }
+        (next, changed)
+    }
+
+    /// Folds a health check result into the proxy score. Returns `true` if the proxy just died.
+    pub fn record (&mut self, proxy: &str, outcome: Result<Duration, String>) -> bool {
+        let dead_after = self.health_config.dead_after.max(1);
+        let health = self.health.entry(proxy.to_string()).or_default();
+        let sample = match outcome {
+            Ok(latency) => {
+                let latency_ms = latency.as_millis() as u64;
+                if health.dead {
+                    info!("Proxy {} is reachable again", redact(proxy));
+                }
+                health.latency_ms = Some(latency_ms);
+                health.consecutive_failures = 0;
+                health.last_error = None;
+                health.dead = false;
+                100 - latency_ms.min(2500) / 50
+            },
+            Err(e) => {
+                health.consecutive_failures += 1;
+                health.last_error = Some(e);
+                0
+            }
+        };
+        health.score = if health.checks == 0 { sample as u8 } else { ((health.score as u64 * 3 + sample) / 4) as u8 };
+        health.checks += 1;
+
+        if !health.dead && health.consecutive_failures >= dead_after {
+            health.dead = true;
+            warn!("Proxy {} marked dead after {} failed checks: {}", redact(proxy), health.consecutive_failures, health.last_error.as_deref().unwrap_or_default());
+            return true;
+        }
+        false
+    }
+
+    /// Marks a proxy dead right away, e.g. when a login through it just failed and a probe confirmed it.
+    pub fn mark_dead (&mut self, proxy: &str, reason: String) {
+        let health = self.health.entry(proxy.to_string()).or_default();
+        if !health.dead {
+            warn!("Proxy {} marked dead: {reason}", redact(proxy));
+        }
+        health.dead = true;
+        health.score = 0;
+        health.checks += 1;
+        health.consecutive_failures = health.consecutive_failures.max(1);
+        health.last_error = Some(reason);
+    }
+
+    pub async fn save (&self) -> Result<(), AppError> {
+        fs::write(&self.path, serde_json::to_string_pretty(&self.assignments)?).await?;
+        Ok(())
+    }
+}
+
+/// Resolves the sticky proxy for `login` and persists the map if it changed.
+pub async fn assign_proxy (state: &AppState, login: &str, preferred: Option<&str>) -> Option<String> {
+    assign_proxy_changed(state, login, preferred).await.0
+}
+
+async fn assign_proxy_changed (state: &AppState, login: &str, preferred: Option<&str>) -> (Option<String>, bool) {
+    let mut pool = state.proxy_pool.lock().await;
+    let (proxy, changed) = pool.assign(login, preferred);
+    if changed && let Err(e) = pool.save().await {
+        error!("Failed to save proxy map: {e}");
+    }
+    (proxy, changed)
+}
+
+const PROXY_SCHEMES: [&str; 4] = ["http", "https", "socks5", "socks5h"];
+
+/// Normalizes one `proxies.txt` entry into a proxy URL. Accepted forms:
+/// `scheme://[user:pass@]host:port`, `user:pass@host:port`, `host:port:user:pass` and `host:port`.
+/// The scheme defaults to `http`; any form can be annotated with `socks5://` or a leading `socks5 `.
+pub fn parse_proxy (entry: &str) -> Result<String, String> {
+    let entry = entry.trim();
+    let (scheme, rest) = if let Some((scheme, rest)) = entry.split_once("://") {
+        (scheme.to_lowercase(), rest)
+    } else if let Some((scheme, rest)) = entry.split_once(char::is_whitespace) && PROXY_SCHEMES.contains(&scheme.to_lowercase().as_str()) {
+        (scheme.to_lowercase(), rest.trim())
+    } else {
+        ("http".to_string(), entry)
+    };
+    if !PROXY_SCHEMES.contains(&scheme.as_str()) {
+        return Err(format!("unsupported proxy scheme '{scheme}'"));
+    }
+
+    let rest = rest.trim_end_matches('/');
+    let (credentials, address) = match rest.rsplit_once('@') {
+        Some((credentials, address)) => (Some(credentials.split_once(':').unwrap_or((credentials, ""))), address),
+        None if rest.starts_with('[') => (None, rest),
+        None => match rest.split(':').collect::<Vec<_>>().as_slice() {
+            [host, port] => (None, &rest[..host.len() + port.len() + 1]),
+            [host, port, user, pass] => (Some((*user, *pass)), &rest[..host.len() + port.len() + 1]),
+            _ => return Err("expected host:port, host:port:user:pass or user:pass@host:port".to_string()),
+        },
+    };
+
+    let (host, port) = address.rsplit_once(':').ok_or("missing port")?;
+    if host.is_empty() {
+        return Err("missing host".to_string());
+    }
+    let port: u16 = port.parse().ok().filter(|p| *p != 0).ok_or_else(|| format!("invalid port '{port}'"))?;
+
+    let mut url = Url::parse(&format!("{scheme}://{host}:{port}")).map_err(|e| format!("invalid proxy address: {e}"))?;
+    if let Some((user, pass)) = credentials {
+        if user.is_empty() {
+            return Err("empty proxy username".to_string());
+        }
+        url.set_username(user).map_err(|_| "invalid proxy username")?;
+        url.set_password((!pass.is_empty()).then_some(pass)).map_err(|_| "invalid proxy password")?;
+    }
+    Ok(url.as_str().trim_end_matches('/').to_string())
+}
+
+fn proxy_url (proxy: &str) -> Result<Url, String> {
+    let proxy = proxy.trim();
+    let url = if proxy.contains("://") { Url::parse(proxy) } else { Url::parse(&format!("http://{proxy}")) };
+    url.map_err(|e| format!("invalid proxy URL: {e}"))
+}
+
+/// The proxy URL with credentials masked, for logs and listings.
+pub fn redact (proxy: &str) -> String {
+    match proxy_url(proxy) {
+        Ok(mut url) => {
+            if !url.username().is_empty() || url.password().is_some() {
+                let _ = url.set_username("***");
+                let _ = url.set_password(None);
+            }
+            url.as_str().trim_end_matches('/').to_string()
+        },
+        Err(_) => "<invalid proxy>".to_string(),
+    }
+}
+
+/// Measures the TCP connect time to the proxy, then sends a request through it to the probe URL.
+pub async fn check_proxy (proxy: &str, config: &ProxyHealthConfig) -> Result<Duration, String> {
+    let url = proxy_url(proxy)?;
+    let host = url.host_str().ok_or("proxy URL has no host")?;
+    let port = url.port_or_known_default().unwrap_or(1080);
+    let limit = Duration::from_secs(config.timeout_secs.max(1));
+
+    let started = Instant::now();
+    match timeout(limit, TcpStream::connect(format!("{host}:{port}"))).await {
+        Ok(Ok(_)) => {},
+        Ok(Err(e)) => return Err(format!("connect failed: {e}")),
+        Err(_) => return Err("connect timed out".to_string()),
+    }
+    let latency = started.elapsed();
+
+    let client = Proxy::all(proxy.trim())
+        .and_then(|p| Client::builder().proxy(p).timeout(limit).build())
+        .map_err(|e| format!("failed to build probe client: {e}"))?;
+    match client.get(&config.probe_url).send().await {
+        Ok(response) if response.status() == StatusCode::PROXY_AUTHENTICATION_REQUIRED => Err("proxy rejected the credentials".to_string()),
+        Ok(_) => Ok(latency),
+        Err(e) => Err(format!("probe request failed: {e}")),
+    }
+}
+
+/// Checks a single proxy right now and marks it dead if the check fails. Returns whether it is healthy.
+pub async fn probe_proxy (state: &AppState, proxy: &str) -> bool {
+    let config = state.proxy_pool.lock().await.health_config.clone();
+    let outcome = check_proxy(proxy, &config).await;
+    let mut pool = state.proxy_pool.lock().await;
+    match outcome {
+        Ok(latency) => {
+            pool.record(proxy, Ok(latency));
+            true
+        },
+        Err(e) => {
+            pool.mark_dead(proxy, e);
+            false
+        }
+    }
+}
+
+/// Checks every proxy in the list concurrently and records the results.
+pub async fn check_all (state: &AppState) {
+    let (proxies, config) = {
+        let pool = state.proxy_pool.lock().await;
+        (pool.proxies.clone(), pool.health_config.clone())
+    };
+    if proxies.is_empty() {
+        return;
+    }
+
+    let outcomes = join_all(proxies.iter().map(|proxy| check_proxy(proxy, &config))).await;
+    let mut pool = state.proxy_pool.lock().await;
+    for (proxy, outcome) in proxies.iter().zip(outcomes) {
+        pool.record(proxy, outcome);
+    }
+    let healthy = proxies.iter().filter(|p| !pool.is_dead(p)).count();
+    info!("Proxy health check finished: {healthy}/{} healthy", proxies.len());
+}
+
+/// Re-checks proxies every `interval_secs` and moves accounts off dead proxies.
+/// Accounts that ended up on a direct connection are moved back once a proxy recovers.
+pub fn spawn_proxy_monitor (home_dir: PathBuf, state: Arc<AppState>) {
+    let tasks = state.tasks.clone();
+    tasks.spawn_supervised("proxy_monitor", RESTART_DELAY, move || proxy_monitor(home_dir.clone(), state.clone()));
+}
+
+async fn proxy_monitor (home_dir: PathBuf, state: Arc<AppState>) {
+    loop {
+        let interval = state.proxy_pool.lock().await.health_config.interval_secs.max(30);
+        sleep(Duration::from_secs(interval)).await;
+        check_all(&state).await;
+
+        let logins: Vec<String> = {
+            let accounts = state.accounts.lock().await;
+            accounts.iter().flatten().map(|c| c.login().to_string()).collect()
+        };
+        for login in logins {
+            let (_, changed) = assign_proxy_changed(&state, &login, None).await;
+            if !changed {
+                continue;
+            }
+            let result = rebind_account(&home_dir, &login, &state).await.map_err(|e| e.to_string());
+            match result {
+                Ok(()) => info!("Account {login} failed over to a new proxy"),
+                Err(e) => error!("Failed to move account {login} to a new proxy: {e}"),
+            }
+        }
+    }
+}
@@ -0,0 +1,190 @@
+use std::{collections::HashMap, sync::{LazyLock, Mutex, MutexGuard}, time::{Duration, Instant}};
+
+use tokio::time::sleep;
+use tracing::{debug, warn};
+
+use crate::{backend::{BackendFuture, Layer}, config::RateLimitConfig, error::{Classify, ErrorClass}, metrics::{ClassLabels, METRICS}, proxy::redact};
+
+pub static LIMITER: LazyLock<RateLimiter> = LazyLock::new(RateLimiter::default);
+
+const DIRECT: &str = "direct";
+/// A throttled bucket never drops below this share of its configured rate.
+const MIN_RATE_SHARE: f64 = 0.1;
+/// Share of the configured rate won back with every successful request after a throttle.
+const RECOVERY_SHARE: f64 = 0.05;
+
+/// Requests are limited separately per class, so a burst of channel discovery can't starve
+/// progress polling or claims going out through the same egress.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum OpClass {
+    Discovery,
+    Progress,
+    Claim,
+}
+
+impl OpClass {
+    pub fn of (operation: &str) -> Self {
+        match operation {
+            "claim_drop" => OpClass::Claim,
+            "send_watch" | "get_current_drop_progress_on_channel" | "get_inventory" => OpClass::Progress,
+            _ => OpClass::Discovery,
+        }
+    }
+
+    pub fn as_str (&self) -> &'static str {
+        match self {
+            OpClass::Discovery => "discovery",
+            OpClass::Progress => "progress",
+            OpClass::Claim => "claim",
+        }
+    }
+
+    fn per_minute (&self, config: &RateLimitConfig) -> f64 {
+        let per_minute = match self {
+            OpClass::Discovery => config.discovery_per_minute,
+            OpClass::Progress => config.progress_per_minute,
+            OpClass::Claim => config.claim_per_minute,
+        };
+        per_minute.max(1) as f64
+    }
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    /// Current refill rate in tokens per second, lowered while Twitch is throttling us.
+    rate: f64,
+    max_rate: f64,
+    burst: f64,
+    refilled: Instant,
+    paused_until: Option<Instant>,
+}
+
+impl Bucket {
+    fn new (config: &RateLimitConfig, class: OpClass) -> Self {
+        let rate = class.per_minute(config) / 60.0;
+        let burst = config.burst.max(1) as f64;
+        Bucket { tokens: burst, rate, max_rate: rate, burst, refilled: Instant::now(), paused_until: None }
+    }
+
+    fn refill (&mut self, now: Instant) {
+        self.tokens = (self.tokens + now.duration_since(self.refilled).as_secs_f64() * self.rate).min(self.burst);
+        self.refilled = now;
+    }
+
+    /// Takes a token, or returns how long to wait for one.
+    fn take (&mut self, now: Instant) -> Result<(), Duration> {
+        if let Some(until) = self.paused_until {
+            if until > now {
+                return Err(until - now);
+            }
+            self.paused_until = None;
+        }
+        self.refill(now);
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1.0 - self.tokens) / self.rate))
+        }
+    }
+}
+
+/// Holds every backend call until [`LIMITER`] lets it out, and slows down every account behind
+/// the same egress when Twitch rate limits one of them.
+#[derive(Debug)]
+pub struct RateLimit;
+
+impl Layer for RateLimit {
+    fn call<'a, T: Send + 'a, E: Classify + Send + 'a> (&'a self, login: &'a str, operation: &'static str, request: BackendFuture<'a, T, E>) -> BackendFuture<'a, T, E> {
+        Box::pin(async move {
+            let class = OpClass::of(operation);
+            LIMITER.acquire(Some(login), class).await;
+            let result = request.await;
+            match &result {
+                Ok(_) => LIMITER.succeeded(Some(login), class),
+                Err(e) => if let ErrorClass::RateLimited(delay) = e.class() {
+                    METRICS.gql_throttled.get_or_create(&ClassLabels { class: class.as_str().to_string() }).inc();
+                    LIMITER.throttled(Some(login), class, delay);
+                },
+            }
+            result
+        })
+    }
+}
+
+/// Token buckets for GQL requests, keyed by egress (the account's proxy, or the direct connection)
+/// and [`OpClass`]. Accounts behind the same proxy share a bucket.
+#[derive(Debug, Default)]
+pub struct RateLimiter {
+    config: Mutex<RateLimitConfig>,
+    egress: Mutex<HashMap<String, String>>,
+    buckets: Mutex<HashMap<(String, OpClass), Bucket>>,
+}
+
+impl RateLimiter {
+    pub fn configure (&self, config: &RateLimitConfig) {
+        *lock(&self.config) = config.clone();
+        lock(&self.buckets).clear();
+    }
+
+    /// Records which proxy an account's requests leave through.
+    pub fn set_egress (&self, login: &str, proxy: Option<&str>) {
+        lock(&self.egress).insert(login.to_string(), proxy.unwrap_or(DIRECT).to_string());
+    }
+
+    fn egress_of (&self, login: Option<&str>) -> String {
+        login.and_then(|login| lock(&self.egress).get(login).cloned()).unwrap_or_else(|| DIRECT.to_string())
+    }
+
+    fn with_bucket<R> (&self, egress: &str, class: OpClass, f: impl FnOnce(&mut Bucket) -> R) -> R {
+        let config = lock(&self.config).clone();
+        let mut buckets = lock(&self.buckets);
+        let bucket = buckets.entry((egress.to_string(), class)).or_insert_with(|| Bucket::new(&config, class));
+        f(bucket)
+    }
+
+    /// Waits until a request of `class` may go out for `login`.
+    pub async fn acquire (&self, login: Option<&str>, class: OpClass) {
+        if !lock(&self.config).enabled {
+            return;
+        }
+        let egress = self.egress_of(login);
+        while let Err(wait) = self.with_bucket(&egress, class, |bucket| bucket.take(Instant::now())) {
+            sleep(wait).await;
+        }
+    }
+
+    /// Twitch rate limited a request: pause the bucket for `delay` and halve its rate.
+    pub fn throttled (&self, login: Option<&str>, class: OpClass, delay: Duration) {
+        let egress = self.egress_of(login);
+        let rate = self.with_bucket(&egress, class, |bucket| {
+            bucket.rate = (bucket.rate / 2.0).max(bucket.max_rate * MIN_RATE_SHARE);
+            bucket.tokens = 0.0;
+            bucket.paused_until = Some(Instant::now() + delay);
+            bucket.rate
+        });
+        warn!("Rate limited by Twitch on {} ({}), slowing down to {:.1} requests/min", shown(&egress), class.as_str(), rate * 60.0);
+    }
+
+    /// A request went through, so let a throttled bucket speed back up.
+    pub fn succeeded (&self, login: Option<&str>, class: OpClass) {
+        let egress = self.egress_of(login);
+        self.with_bucket(&egress, class, |bucket| {
+            if bucket.rate < bucket.max_rate {
+                bucket.rate = (bucket.rate + bucket.max_rate * RECOVERY_SHARE).min(bucket.max_rate);
+                if bucket.rate == bucket.max_rate {
+                    debug!("Request rate for {} ({}) back to normal", shown(&egress), class.as_str());
+                }
+            }
+        });
+    }
+}
+
+fn shown (egress: &str) -> String {
+    if egress == DIRECT { DIRECT.to_string() } else { redact(egress) }
+}
+
+fn lock<T> (mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|e| e.into_inner())
+}
@@ -0,0 +1,410 @@
+use std::{collections::{HashMap, HashSet}, time::{Duration, Instant}};
+
+use crate::{config::SwitchConfig, r#static::{Channel, ChannelRank}, streamers::{PickReason, StreamerLists}};
+
+const PREFERRED_TIER: u32 = 20_000;
+const ALLOW_TIER: u32 = 10_000;
+const DEFAULT_TIER: u32 = 0;
+/// Ranking passes in a row without a candidate before the scheduler reports it is starved.
+const STARVED_AFTER: u32 = 3;
+/// An account without a heartbeat for this long is not in the middle of a watch minute.
+const HEARTBEAT_STALE: Duration = Duration::from_secs(120);
+
+/// Why the farm stops watching a channel.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReleaseReason {
+    NoActiveDrop,
+    NotLive,
+    Skipped,
+}
+
+impl ReleaseReason {
+    pub fn as_str (&self) -> &'static str {
+        match self {
+            ReleaseReason::NoActiveDrop => "no active drop",
+            ReleaseReason::NotLive => "stream went offline",
+            ReleaseReason::Skipped => "skipped",
+        }
+    }
+}
+
+/// Everything the ranking needs, copied out of the shared state.
+#[derive(Debug, Default, Clone)]
+pub struct PoolSnapshot {
+    /// Live channels with drops.
+    pub channels: HashSet<Channel>,
+    /// Allowed channel ids of every restricted campaign, by campaign id.
+    pub allow_lists: HashMap<String, HashSet<String>>,
+    /// Discovered channel ids with their rank bonus, by campaign id.
+    pub discovered: HashMap<String, HashMap<String, u32>>,
+    pub campaign_priority: HashMap<String, u32>,
+    /// Game name of every campaign, by campaign id.
+    pub campaign_games: HashMap<String, String>,
+    pub lists: StreamerLists,
+}
+
+/// An account's last successful watch heartbeat.
+#[derive(Debug, Clone)]
+pub struct Heartbeat {
+    pub channel_id: String,
+    pub at: Instant,
+}
+
+#[derive(Debug)]
+pub enum Event {
+    /// A ranking pass over a fresh pool snapshot.
+    Pool(Box<PoolSnapshot>),
+    /// An account or the control API gave up a channel.
+    Released(Channel, ReleaseReason),
+    /// Time passed, with every account's latest heartbeat by login.
+    Tick(HashMap<String, Heartbeat>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Action {
+    /// Every account should watch `channel` from now on.
+    Watch { channel: Channel, reason: String },
+    /// The channel being watched was given up.
+    Left { channel: Channel, reason: ReleaseReason },
+    /// The latest ranking, by channel id, for the status output.
+    Ranked(HashMap<String, ChannelRank>),
+    /// No candidate for several ranking passes in a row.
+    Starved,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Candidate {
+    channel: Channel,
+    rank: ChannelRank,
+}
+
+#[derive(Debug)]
+struct Watching {
+    channel: Channel,
+    priority: u32,
+    since: Instant,
+    /// When a better candidate first cleared the margin, while waiting for the minute to finish.
+    upgrade_since: Option<Instant>,
+}
+
+/// Picks the channel every account watches. It holds no handles and does no I/O: the update task
+/// feeds it [`Event`]s with the current time and carries out the [`Action`]s it returns.
+#[derive(Debug)]
+pub struct Scheduler {
+    config: SwitchConfig,
+    ranking: Vec<Candidate>,
+    /// Released channels, skipped until they go offline or every candidate has been released.
+    watched: HashSet<Channel>,
+    previous_pool: HashSet<Channel>,
+    empty_passes: u32,
+    current: Option<Watching>,
+    heartbeats: HashMap<String, Heartbeat>,
+}
+
+impl Scheduler {
+    pub fn new (config: SwitchConfig) -> Self {
+        Scheduler {
+            config,
+            ranking: Vec::new(),
+            watched: HashSet::new(),
+            previous_pool: HashSet::new(),
+            empty_passes: 0,
+            current: None,
+            heartbeats: HashMap::new(),
+        }
+    }
+
+    pub fn handle (&mut self, event: Event, now: Instant) -> Vec<Action> {
+        let mut actions = Vec::new();
+        let fresh = match event {
+            Event::Pool(snapshot) => {
+                self.rank(&snapshot, &mut actions);
+                true
+            },
+            Event::Released(channel, reason) => {
+                if self.current.as_ref().is_some_and(|current| current.channel == channel) {
+                    self.current = None;
+                    self.watched.insert(channel.clone());
+                    actions.push(Action::Left { channel, reason });
+                }
+                false
+            },
+            Event::Tick(heartbeats) => {
+                self.heartbeats = heartbeats;
+                false
+            },
+        };
+        if let Some((channel, priority, reason)) = self.decide(fresh, now) {
+            self.current = Some(Watching { channel: channel.clone(), priority, since: now, upgrade_since: None });
+            actions.push(Action::Watch { channel, reason });
+        }
+        actions
+    }
+
+    fn rank (&mut self, snapshot: &PoolSnapshot, actions: &mut Vec<Action>) {
+        for channel in self.previous_pool.difference(&snapshot.channels) {
+            self.watched.remove(channel);
+        }
+
+        self.ranking = snapshot.channels.iter()
+            .filter(|channel| !self.watched.contains(*channel))
+            .filter_map(|channel| rank_channel(channel, snapshot).map(|rank| Candidate { channel: channel.clone(), rank }))
+            .collect();
+        // Higher priority first, ties broken by channel id so the order is stable.
+        self.ranking.sort_by(|a, b| b.rank.priority.cmp(&a.rank.priority).then_with(|| b.channel.channel_id.cmp(&a.channel.channel_id)));
+
+        if self.ranking.is_empty() {
+            // Every live channel has been watched, give them all another chance.
+            self.watched.clear();
+            self.empty_passes += 1;
+            if self.empty_passes >= STARVED_AFTER {
+                self.empty_passes = 0;
+                actions.push(Action::Starved);
+            }
+            return;
+        }
+        self.empty_passes = 0;
+        self.previous_pool = snapshot.channels.clone();
+        actions.push(Action::Ranked(self.ranking.iter().map(|c| (c.channel.channel_id.clone(), c.rank)).collect()));
+    }
+
+    fn best (&self) -> Option<&Candidate> {
+        self.ranking.iter().find(|c| !self.watched.contains(&c.channel))
+    }
+
+    /// The channel to switch to now, with the reason to log, if any.
+    fn decide (&mut self, fresh: bool, now: Instant) -> Option<(Channel, u32, String)> {
+        let best = self.best()?.clone();
+        let Some(current) = &mut self.current else {
+            return Some((best.channel, best.rank.priority, format!("best candidate, {} priority {}", best.rank.reason.as_str(), best.rank.priority)));
+        };
+        if best.channel == current.channel {
+            current.priority = best.rank.priority;
+            current.upgrade_since = None;
+            return None;
+        }
+
+        match self.ranking.iter().find(|c| c.channel == current.channel) {
+            Some(c) => current.priority = c.rank.priority,
+            None if fresh => return Some((best.channel, best.rank.priority, format!("{} left the channel pool", current.channel.channel_login))),
+            None => {},
+        }
+        if best.rank.priority < current.priority.saturating_add(self.config.priority_margin) {
+            current.upgrade_since = None;
+            return None;
+        }
+        if now.saturating_duration_since(current.since) < Duration::from_secs(self.config.min_dwell_secs) {
+            return None;
+        }
+
+        // Wait for every account on the channel to finish its watch minute, so no partial minute is lost.
+        let upgrade_since = *current.upgrade_since.get_or_insert(now);
+        let minute_done = self.heartbeats.values()
+            .filter(|beat| beat.channel_id == current.channel.channel_id && upgrade_since.saturating_duration_since(beat.at) < HEARTBEAT_STALE)
+            .all(|beat| beat.at >= upgrade_since);
+        if !minute_done && now.saturating_duration_since(upgrade_since) < Duration::from_secs(self.config.heartbeat_wait_secs) {
+            return None;
+        }
+        Some((best.channel, best.rank.priority, format!("{} priority {} beats {} on {}", best.rank.reason.as_str(), best.rank.priority, current.priority, current.channel.channel_login)))
+    }
+}
+
+/// The channel's best rank over every campaign it counts for, or `None` if it counts for none.
+/// A preferred streamer outranks every other channel, within the order of the games.
+fn rank_channel (channel: &Channel, snapshot: &PoolSnapshot) -> Option<ChannelRank> {
+    if snapshot.lists.is_blocked(&channel.channel_login) {
+        return None;
+    }
+    let consider = |camp_id: &String, tier: u32, bonus: u32, reason: PickReason| {
+        let base = *snapshot.campaign_priority.get(camp_id).unwrap_or(&0);
+        let game = snapshot.campaign_games.get(camp_id).map(String::as_str).unwrap_or_default();
+        if snapshot.lists.is_preferred(game, &channel.channel_login) {
+            ChannelRank { priority: PREFERRED_TIER + base, reason: PickReason::Preferred }
+        } else {
+            ChannelRank { priority: tier + base + bonus, reason }
+        }
+    };
+
+    let allowed = snapshot.allow_lists.iter()
+        .filter(|(_, allow_set)| allow_set.contains(&channel.channel_id))
+        .map(|(camp_id, _)| consider(camp_id, ALLOW_TIER, 0, PickReason::AllowList));
+    let discovered = snapshot.discovered.iter()
+        .filter_map(|(camp_id, def_set)| def_set.get(&channel.channel_id).map(|bonus| consider(camp_id, DEFAULT_TIER, *bonus, PickReason::Directory)));
+    allowed.chain(discovered).filter(|rank| rank.priority > 0).max_by_key(|rank| rank.priority)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn channel (id: &str) -> Channel {
+        Channel { channel_id: id.to_string(), channel_login: format!("login_{id}") }
+    }
+
+    fn config () -> SwitchConfig {
+        SwitchConfig { min_dwell_secs: 600, priority_margin: 10, heartbeat_wait_secs: 90 }
+    }
+
+    /// Campaign `a` of the top game lists channels `1` and `2`, campaign `b` of the second game
+    /// found `3` and `4` in its directory.
+    fn snapshot (live: &[&str]) -> PoolSnapshot {
+        PoolSnapshot {
+            channels: live.iter().map(|id| channel(id)).collect(),
+            allow_lists: HashMap::from([("a".to_string(), HashSet::from(["1".to_string(), "2".to_string()]))]),
+            discovered: HashMap::from([("b".to_string(), HashMap::from([("3".to_string(), 5), ("4".to_string(), 9)]))]),
+            campaign_priority: HashMap::from([("a".to_string(), 20), ("b".to_string(), 10)]),
+            campaign_games: HashMap::from([("a".to_string(), "Rust".to_string()), ("b".to_string(), "Valorant".to_string())]),
+            lists: StreamerLists::default(),
+        }
+    }
+
+    fn pool (live: &[&str]) -> Event {
+        Event::Pool(Box::new(snapshot(live)))
+    }
+
+    fn watched (actions: &[Action]) -> Option<String> {
+        actions.iter().find_map(|action| match action {
+            Action::Watch { channel, .. } => Some(channel.channel_id.clone()),
+            _ => None,
+        })
+    }
+
+    fn beat (channel_id: &str, at: Instant) -> HashMap<String, Heartbeat> {
+        HashMap::from([("account".to_string(), Heartbeat { channel_id: channel_id.to_string(), at })])
+    }
+
+    #[test]
+    fn empty_pool_starves_without_watching () {
+        let mut scheduler = Scheduler::new(config());
+        let now = Instant::now();
+        for pass in 1..=STARVED_AFTER {
+            let actions = scheduler.handle(pool(&[]), now);
+            assert_eq!(watched(&actions), None);
+            assert_eq!(actions.contains(&Action::Starved), pass == STARVED_AFTER);
+        }
+    }
+
+    #[test]
+    fn picks_the_best_ranked_channel () {
+        let mut scheduler = Scheduler::new(config());
+        let actions = scheduler.handle(pool(&["1", "3", "4"]), Instant::now());
+        // Allow list channels beat directory channels, even of a higher priority game.
+        assert_eq!(watched(&actions), Some("1".to_string()));
+        let Some(Action::Ranked(ranking)) = actions.iter().find(|a| matches!(a, Action::Ranked(_))) else { panic!("no ranking") };
+        assert_eq!(ranking["4"].priority, DEFAULT_TIER + 10 + 9);
+        assert_eq!(ranking["1"].reason, PickReason::AllowList);
+    }
+
+    #[test]
+    fn preferred_and_blocked_streamers () {
+        let mut snapshot = snapshot(&["1", "2", "4"]);
+        snapshot.lists = StreamerLists::parse("[Valorant]\nlogin_4", "login_2");
+        let mut scheduler = Scheduler::new(config());
+        let actions = scheduler.handle(Event::Pool(Box::new(snapshot)), Instant::now());
+        assert_eq!(watched(&actions), Some("4".to_string()));
+        let Some(Action::Ranked(ranking)) = actions.iter().find(|a| matches!(a, Action::Ranked(_))) else { panic!("no ranking") };
+        assert_eq!(ranking["4"].reason, PickReason::Preferred);
+        assert!(!ranking.contains_key("2"));
+    }
+
+    #[test]
+    fn release_moves_on_and_ignores_stale_releases () {
+        let mut scheduler = Scheduler::new(config());
+        let now = Instant::now();
+        // Equal priorities, the higher channel id wins.
+        assert_eq!(watched(&scheduler.handle(pool(&["1", "2"]), now)), Some("2".to_string()));
+
+        let actions = scheduler.handle(Event::Released(channel("1"), ReleaseReason::NotLive), now);
+        assert!(actions.is_empty(), "only the watched channel can be released: {actions:?}");
+
+        let actions = scheduler.handle(Event::Released(channel("2"), ReleaseReason::NoActiveDrop), now);
+        assert_eq!(actions[0], Action::Left { channel: channel("2"), reason: ReleaseReason::NoActiveDrop });
+        assert_eq!(watched(&actions), Some("1".to_string()));
+    }
+
+    #[test]
+    fn all_channels_watched_get_another_chance () {
+        let mut scheduler = Scheduler::new(config());
+        let now = Instant::now();
+        scheduler.handle(pool(&["1"]), now);
+        let actions = scheduler.handle(Event::Released(channel("1"), ReleaseReason::NoActiveDrop), now);
+        assert_eq!(watched(&actions), None);
+
+        let actions = scheduler.handle(pool(&["1"]), now);
+        assert_eq!(watched(&actions), None, "the released channel is skipped for one pass");
+        let actions = scheduler.handle(pool(&["1"]), now);
+        assert_eq!(watched(&actions), Some("1".to_string()));
+    }
+
+    #[test]
+    fn channel_going_offline_is_replaced_at_once () {
+        let mut scheduler = Scheduler::new(config());
+        let now = Instant::now();
+        scheduler.handle(pool(&["1", "3"]), now);
+        let actions = scheduler.handle(pool(&["3"]), now + Duration::from_secs(1));
+        assert_eq!(watched(&actions), Some("3".to_string()));
+        assert!(matches!(&actions[1], Action::Watch { reason, .. } if reason.contains("left the channel pool")));
+    }
+
+    #[test]
+    fn released_channel_is_forgotten_once_offline () {
+        let mut scheduler = Scheduler::new(config());
+        let now = Instant::now();
+        scheduler.handle(pool(&["1", "3"]), now);
+        scheduler.handle(Event::Released(channel("1"), ReleaseReason::NotLive), now);
+        scheduler.handle(pool(&["3"]), now);
+        scheduler.handle(Event::Released(channel("3"), ReleaseReason::NoActiveDrop), now);
+
+        let actions = scheduler.handle(pool(&["1", "3"]), now);
+        assert_eq!(watched(&actions), Some("1".to_string()));
+    }
+
+    #[test]
+    fn stays_within_the_margin () {
+        let mut scheduler = Scheduler::new(config());
+        let now = Instant::now();
+        scheduler.handle(pool(&["3"]), now);
+        // Channel 4 ranks 4 points higher, below the margin of 10.
+        let later = now + Duration::from_secs(3600);
+        assert_eq!(watched(&scheduler.handle(pool(&["3", "4"]), later)), None);
+    }
+
+    #[test]
+    fn upgrade_waits_for_dwell_time_and_heartbeat () {
+        let mut scheduler = Scheduler::new(config());
+        let start = Instant::now();
+        scheduler.handle(pool(&["3"]), start);
+
+        let early = start + Duration::from_secs(60);
+        assert_eq!(watched(&scheduler.handle(pool(&["1", "3"]), early)), None, "dwell time not reached");
+
+        let due = start + Duration::from_secs(600);
+        scheduler.handle(Event::Tick(beat("3", due - Duration::from_secs(10))), due - Duration::from_secs(10));
+        assert_eq!(watched(&scheduler.handle(pool(&["1", "3"]), due)), None, "mid-minute");
+        let beat_at = due + Duration::from_secs(20);
+        let actions = scheduler.handle(Event::Tick(beat("3", beat_at)), beat_at);
+        assert_eq!(watched(&actions), Some("1".to_string()));
+    }
+
+    #[test]
+    fn upgrade_goes_ahead_without_heartbeat_after_wait () {
+        let mut scheduler = Scheduler::new(config());
+        let start = Instant::now();
+        scheduler.handle(pool(&["3"]), start);
+        let due = start + Duration::from_secs(600);
+        scheduler.handle(Event::Tick(beat("3", due - Duration::from_secs(10))), due);
+        assert_eq!(watched(&scheduler.handle(pool(&["1", "3"]), due)), None);
+
+        let timeout = due + Duration::from_secs(90);
+        assert_eq!(watched(&scheduler.handle(Event::Tick(beat("3", due - Duration::from_secs(10))), timeout)), Some("1".to_string()));
+    }
+
+    #[test]
+    fn skip_switches_even_within_dwell_time () {
+        let mut scheduler = Scheduler::new(config());
+        let now = Instant::now();
+        scheduler.handle(pool(&["1", "2"]), now);
+        let actions = scheduler.handle(Event::Released(channel("2"), ReleaseReason::Skipped), now + Duration::from_secs(5));
+        assert_eq!(watched(&actions), Some("1".to_string()));
+    }
+}
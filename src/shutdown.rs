@@ -0,0 +1,93 @@
+use std::{sync::Arc, time::Duration};
+
+use tokio::{signal, time::{Instant, sleep, timeout_at}};
+use tokio_util::sync::CancellationToken;
+use tracing::{error, info, warn};
+
+use crate::{persist_drop_cache, r#static::AppState, webhook::WebhookSendFormat};
+
+/// Upper bound for the whole shutdown, after which the process exits regardless.
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// Runs [`graceful_shutdown`] on a signal. The returned token is cancelled once it has finished.
+pub fn spawn_shutdown_handler (state: Arc<AppState>) -> CancellationToken {
+    let finished = CancellationToken::new();
+    let finished_clone = finished.clone();
+    tokio::spawn(async move {
+        shutdown_signal(&state).await;
+        graceful_shutdown(&state).await;
+        finished_clone.cancel();
+        // `main` returns right away unless it's stuck in a blocking prompt.
+        sleep(Duration::from_secs(1)).await;
+        std::process::exit(0);
+    });
+    finished
+}
+
+/// Resolves on Ctrl-C, SIGTERM, or a shutdown requested from inside the app (the dashboard's `q`).
+async fn shutdown_signal (state: &AppState) {
+    #[cfg(unix)]
+    let terminate = async {
+        match signal::unix::signal(signal::unix::SignalKind::terminate()) {
+            Ok(mut sigterm) => { sigterm.recv().await; },
+            Err(e) => {
+                error!("Failed to listen for SIGTERM: {e}");
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = signal::ctrl_c() => info!("Received Ctrl-C"),
+        _ = terminate => info!("Received SIGTERM"),
+        _ = state.shutdown.cancelled() => {},
+    }
+}
+
+/// Stops heartbeats and new claims, lets in-flight claims finish, saves the drop cache
+/// and marks every account's Discord embed as stopped.
+async fn graceful_shutdown (state: &AppState) {
+    let deadline = Instant::now() + SHUTDOWN_TIMEOUT;
+    info!("Shutting down...");
+    state.shutdown.cancel();
+
+    state.claims.close();
+    if !state.claims.is_empty() {
+        info!("Waiting for {} in-flight claims", state.claims.len());
+    }
+    if timeout_at(deadline, state.claims.wait()).await.is_err() {
+        warn!("{} claims were still running at shutdown", state.claims.len());
+    }
+
+    state.tasks.shutdown();
+    persist_drop_cache(state).await;
+
+    let farming = state.farming.lock().await.take();
+    if let Some(ctx) = farming && ctx.webhook_is_active {
+        let statuses = state.account_status.lock().await.clone();
+        for (login, status) in statuses {
+            let progress_percent = (status.current_minutes * 100).checked_div(status.required_minutes).unwrap_or(0).min(100) as u8;
+            let payload = WebhookSendFormat {
+                twitch_name: login,
+                game_name: status.game.unwrap_or_else(|| "None".to_string()),
+                game_avatar_url: "None".to_string(),
+                streamer_name: status.channel.map(|c| c.channel_login).unwrap_or_else(|| "None".to_string()),
+                progress_percent,
+                progress_text: format!("{}m / {}m", status.current_minutes, status.required_minutes),
+                status: "⏹ Stopped".to_string(),
+            };
+            if timeout_at(deadline, ctx.webhook_tx.send(payload)).await.is_err() {
+                break;
+            }
+        }
+    }
+
+    // The worker drains its queue and exits once the last sender (held by the farming tasks) is gone.
+    let worker = state.webhook_worker.lock().await.take();
+    if let Some(worker) = worker && timeout_at(deadline, worker).await.is_err() {
+        warn!("Timed out delivering the final webhook updates");
+    }
+    info!("Shutdown complete");
+}
@@ -1,73 +1,122 @@
-use std::{collections::{HashMap, HashSet}, path::PathBuf, sync::Arc, time::Duration};
+use std::{collections::{HashMap, HashSet, VecDeque}, path::PathBuf, sync::Arc};
 
-use tokio::{sync::Mutex, time::sleep};
-use twitch_gql_rs::{TwitchClient, structs::{Channels, GameDirectory}};
+use serde::Serialize;
+use tokio::{sync::{Mutex, Notify}, task::JoinHandle};
+use tokio_util::{sync::CancellationToken, task::TaskTracker};
+use twitch_gql_rs::structs::DropCampaigns;
 
-#[derive(Default, Debug, Clone, PartialEq, Eq, Hash)]
+use crate::{accounts::FarmingContext, backend::DropsBackend, capture::Capture, config::{DiscoveryConfig, EndpointsConfig, IntervalsConfig, SwitchConfig}, catalog::CampaignCatalog, channel_cache::ChannelCache, proxy::ProxyPool, scheduler::{Heartbeat, ReleaseReason}, streamers::{PickReason, StreamerLists}, tasks::TaskRegistry, tui::LogTail, vault::Vault};
+
+#[derive(Default, Debug, Clone, PartialEq, Eq, Hash, Serialize)]
 pub struct Channel {
     pub channel_id: String,
     pub channel_login: String
 }
 
-const MAX_ATTEMPTS: u32 = 3;
+/// A channel's place in the latest `update_stream` ranking.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct ChannelRank {
+    pub priority: u32,
+    pub reason: PickReason,
+}
+
+pub const CLAIM_HISTORY_LIMIT: usize = 500;
+
+/// Latest drop progress reported for an account, refreshed by its progress task.
+#[derive(Debug, Default, Clone, Serialize)]
+pub struct AccountStatus {
+    pub channel: Option<Channel>,
+    pub drop_id: Option<String>,
+    pub drop_name: Option<String>,
+    pub game: Option<String>,
+    pub current_minutes: u64,
+    pub required_minutes: u64,
+    pub status: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ClaimRecord {
+    pub login: String,
+    pub drop_id: String,
+    pub claimed_at: String,
+    pub success: bool,
+    pub error: Option<String>,
+}
 
 #[derive(Debug, Default)]
 pub struct AppState {
-    pub accounts: Mutex<Option<Vec<Arc<TwitchClient>>>>,
+    pub accounts: Mutex<Option<Vec<Arc<dyn DropsBackend>>>>,
     pub drop_cache: Mutex<HashMap<String, HashSet<String>>>,
     pub channel_pool: Mutex<HashSet<Channel>>,
-    pub default_channels: Mutex<HashMap<String, HashSet<GameDirectory>>>,
-    pub allow_channels: Mutex<HashMap<String, HashSet<Channels>>>,
+    /// Discovered channels of campaigns without an allow list, by campaign id, with each channel's rank bonus.
+    pub default_channels: Mutex<HashMap<String, HashMap<String, u32>>>,
+    pub discovery: std::sync::OnceLock<DiscoveryConfig>,
+    pub switching: std::sync::OnceLock<SwitchConfig>,
+    pub intervals: std::sync::OnceLock<IntervalsConfig>,
+    pub endpoints: std::sync::OnceLock<EndpointsConfig>,
+    /// Set when started with `--record` or `--replay`.
+    pub capture: std::sync::OnceLock<Capture>,
+    /// Each account's last successful watch heartbeat, by login.
+    pub heartbeats: Mutex<HashMap<String, Heartbeat>>,
     pub campaign_priority: Mutex<HashMap<String, u32>>,
     pub cache_path: std::sync::OnceLock<PathBuf>,
+    pub farming: Mutex<Option<FarmingContext>>,
+    pub vault: std::sync::OnceLock<Vault>,
+    pub proxy_pool: Mutex<ProxyPool>,
+    /// Named long-running tasks, including each account's watch, progress and claim tasks.
+    pub tasks: TaskRegistry,
+    pub account_status: Mutex<HashMap<String, AccountStatus>>,
+    pub paused_accounts: Mutex<HashSet<String>>,
+    /// Priority and pick reason of every channel in `channel_pool` from the latest `update_stream` pass, by channel id.
+    pub channel_priority: Mutex<HashMap<String, ChannelRank>>,
+    pub streamer_lists: Mutex<StreamerLists>,
+    /// Campaigns being farmed, grouped per game in priority order.
+    pub campaigns: Mutex<VecDeque<VecDeque<DropCampaigns>>>,
+    pub catalog: CampaignCatalog,
+    pub pending_drops: Mutex<HashSet<String>>,
+    /// Time-based drop ids of every farmed campaign, by campaign id.
+    pub campaign_drops: Mutex<HashMap<String, Vec<String>>>,
+    pub drop_names: Mutex<HashMap<String, String>>,
+    /// Viewer counts reported over PubSub, by channel id.
+    pub channel_viewers: Mutex<HashMap<String, u64>>,
+    pub channel_cache: Mutex<ChannelCache>,
+    /// Most recent claim attempts, newest last, capped at `CLAIM_HISTORY_LIMIT`.
+    pub claim_history: Mutex<VecDeque<ClaimRecord>>,
+    pub campaign_refresh: Notify,
+    pub log_tail: std::sync::OnceLock<LogTail>,
+    /// Cancelled once shutdown starts, new heartbeats and claims stop right away.
+    pub shutdown: CancellationToken,
+    /// In-flight `claim_drop` calls, awaited on shutdown.
+    pub claims: TaskTracker,
+    pub webhook_worker: Mutex<Option<JoinHandle<()>>>,
 }
 
-#[macro_export]
-macro_rules! retry {
-    ($func:expr) => {
-        retry_backup(|| $func).await.expect("Retry failed after MAX_ATTEMPTS attempts")
-    };
-}
-
-pub async fn retry_backup<F, Fut, T, E> (mut f: F) -> Result<T, E> where F: FnMut() -> Fut, Fut: Future<Output = Result<T, E>> {
-    let mut attempts = 0;
-    loop {
-        match f().await {
-            Ok(t) => return Ok(t),
-            Err(e) => {
-                attempts += 1;
-                if attempts > MAX_ATTEMPTS {
-                    return Err(e);
-                } else {
-                    sleep(Duration::from_secs(5)).await
-                }
-            }
+impl AppState {
+    /// Pauses or resumes watch heartbeats for an account. Returns `false` for unknown logins.
+    pub async fn set_paused (&self, login: &str, paused: bool) -> bool {
+        let registered = self.accounts.lock().await.iter().flatten().any(|c| c.login() == login);
+        if !registered {
+            return false;
+        }
+        let mut paused_accounts = self.paused_accounts.lock().await;
+        if paused {
+            paused_accounts.insert(login.to_string());
+        } else {
+            paused_accounts.remove(login);
         }
+        tracing::info!("Account {login} {}", if paused { "paused" } else { "resumed" });
+        true
     }
-}
 
-pub fn spawn_supervised<F, Fut>(name: impl Into<String>, restart_delay: Duration, mut make_future: F) 
-where 
-    F: FnMut() -> Fut + Send + 'static,
-    Fut: Future<Output = ()> + Send + 'static
-{
-    let name = name.into();
-    tokio::spawn(async move {
-        loop {
-            let handle = tokio::spawn(make_future());
-            match handle.await {
-                Ok(()) => {
-                    tracing::warn!("Task '{name}' exited unexpectedly (it should run forever). Restarting in {}s...", restart_delay.as_secs());
-                }
-                Err(join_err) if join_err.is_panic() => {
-                    tracing::error!("Task '{name}' panicked: {join_err}. Restarting in {}s...", restart_delay.as_secs());
-                }
-                Err(join_err) => {
-                    tracing::warn!("Task '{name}' was cancelled: {join_err}");
-                    return;
-                }
-            }
-            sleep(restart_delay).await;
+    /// Moves every account off the channel being watched. Returns that channel, or `None` if farming isn't running.
+    pub async fn skip_channel (&self) -> Option<Option<Channel>> {
+        let farming = self.farming.lock().await;
+        let ctx = farming.as_ref()?;
+        let watching = ctx.channel_rx.borrow().clone();
+        if let Some(channel) = &watching {
+            ctx.releases.release(channel, ReleaseReason::Skipped);
         }
-    });
-} 
\ No newline at end of file
+        tracing::info!("Skipping channel {}", watching.as_ref().map(|c| c.channel_login.as_str()).unwrap_or("<none>"));
+        Some(watching)
+    }
+}
@@ -1,29 +1,49 @@
-use std::{collections::{BinaryHeap, HashMap, HashSet, VecDeque}, sync::Arc, time::Duration};
+use std::{collections::{HashMap, HashSet}, sync::Arc, time::{Duration, Instant}};
 
-use tokio::sync::{Mutex, Notify, watch::Receiver};
+use tokio::sync::Notify;
 
 use futures_util::{SinkExt, StreamExt, stream};
+use reqwest::Url;
 use serde_json::{Value, json};
-use tokio::time::sleep;
-use tokio_tungstenite::{connect_async, tungstenite::Message};
-use tracing::{debug, error, warn};
-use twitch_gql_rs::{TwitchClient, structs::{Channels, DropCampaigns, GameDirectory}};
+use tokio::time::{sleep, sleep_until};
+use tokio::net::TcpStream;
+use tokio_tungstenite::{MaybeTlsStream, WebSocketStream, client_async_tls, tungstenite::Message};
+use tracing::{debug, error, info, warn};
 
-use crate::{r#static::{AppState, Channel, retry_backup}};
+use crate::{backend::DropsBackend, capture::{Capture, Direction, Replay}, channel_cache::ChannelStatus, directory::discover_streams, metrics::METRICS, proxy::redact, error::{RetryPolicy, retry_backup}, r#static::{AppState, Channel}, scheduler::{Action, Event, PoolSnapshot, ReleaseReason, Scheduler}, tasks::RESTART_DELAY, tunnel::open_tunnel};
 
 const UPDATE_TIME: u64 = 45;
+/// How often the watched channel is re-evaluated between ranking passes.
+const SWITCH_TICK: u64 = 5;
 const MAX_TOPICS: usize = 120;
-const WS_URL: &'static str = "wss://pubsub-edge.twitch.tv/v1";
 
-async fn check_channel_stream(client: Arc<TwitchClient>, channel: Channel) -> Option<Channel> {
-    let stream_info = client.get_stream_info(&channel.channel_login).await.ok()?;
-    if stream_info.stream.is_none() {
-        return None;
-    }
+/// Whether `channel` is live with drops, from the channel cache when its entry is still fresh.
+async fn check_channel_stream(client: Arc<dyn DropsBackend>, channel: Channel, state: Arc<AppState>) -> Option<Channel> {
+    let cached = state.channel_cache.lock().await.get(&channel.channel_id);
+    let status = match cached {
+        Some(status) => status,
+        None => {
+            let status = fetch_channel_status(client.as_ref(), &channel).await?;
+            state.channel_cache.lock().await.insert(&channel.channel_id, status.clone());
+            status
+        }
+    };
+    (status.live && status.drops_enabled).then_some(channel)
+}
 
-    match retry_backup(|| client.get_available_drops_for_channel(&channel.channel_id)).await {
-        Ok(drops) if drops.viewerDropCampaigns.is_some() => Some(channel),
-        Ok(_) => None,
+async fn fetch_channel_status (client: &dyn DropsBackend, channel: &Channel) -> Option<ChannelStatus> {
+    let stream_info = retry_backup(&RetryPolicy::GQL, || client.get_stream_info(&channel.channel_login)).await.ok()?;
+    let Some(stream) = &stream_info.stream else {
+        return Some(ChannelStatus::default());
+    };
+
+    match retry_backup(&RetryPolicy::GQL, || client.get_available_drops_for_channel(&channel.channel_id)).await {
+        Ok(drops) => Some(ChannelStatus {
+            live: true,
+            game: Some(stream_info.broadcastSettings.game.displayName.clone()).filter(|game| !game.is_empty()),
+            drops_enabled: drops.viewerDropCampaigns.is_some(),
+            viewers: stream.viewersCount,
+        }),
         Err(e) => {
             error!("Failed to get available drops for channel {}: {}", channel.channel_login, e);
             None
@@ -31,8 +51,9 @@ async fn check_channel_stream(client: Arc<TwitchClient>, channel: Channel) -> Op
     }
 }
 
-pub async fn filter_streams (client: Arc<TwitchClient>, campaigns_arc: Arc<Mutex<VecDeque<VecDeque<DropCampaigns>>>>, state: Arc<AppState>) {
-    let campaigns = campaigns_arc.lock().await.clone();
+pub async fn filter_streams (client: Arc<dyn DropsBackend>, state: Arc<AppState>) {
+    let campaigns = state.campaigns.lock().await.clone();
+    let lists = state.streamer_lists.lock().await.clone();
     let mut priority_map = HashMap::new();
     let mut candidate_channels = Vec::new();
 
@@ -40,42 +61,18 @@ pub async fn filter_streams (client: Arc<TwitchClient>, campaigns_arc: Arc<Mutex
         let base_prio = ((campaigns.len() - game_idx) * 10) as u32;
         for campaign in campaign_queue {
             priority_map.insert(campaign.id.clone(), base_prio);
-            let campaign_details = match client.get_campaign_details(&campaign.id).await {
-                Ok(details) => details,
-                Err(e) => {
-                    error!("Failed to fetch campaign details for {}: {e}", campaign.id);
-                    continue;
-                }
+            let Some(campaign_details) = state.catalog.details(&campaign.id).await else {
+                continue;
             };
-            if let Some(allow) = campaign_details.allow.channels {
-                let mut allow_channels = state.allow_channels.lock().await;
-                let allow: HashSet<Channels> = allow.into_iter().collect();
-                allow_channels.insert(campaign.id.to_string(), allow.clone());
-                drop(allow_channels);
-                for channel in allow {
-                    candidate_channels.push(Channel { 
-                        channel_id: channel.id, 
-                        channel_login: channel.name 
-                    });
-                }
-            } else {
-                let game_directory = match client.get_game_directory(&campaign_details.game.slug, 30, true).await {
-                    Ok(directory) => directory,
-                    Err(e) => {
-                        error!("Failed to fetch game directory for {}: {e}", campaign_details.game.slug);
-                        continue;
-                    }
-                };
-                let mut all_default = state.default_channels.lock().await;
-                let game_directory: HashSet<GameDirectory> = game_directory.into_iter().collect();
-                all_default.insert(campaign.id.to_string(), game_directory.clone());
-                drop(all_default);
-                for channel in game_directory {
-                    candidate_channels.push(Channel { 
-                        channel_id: channel.broadcaster.id, 
-                        channel_login: channel.broadcaster.login 
-                    });
-                }
+            let game = &campaign.game.displayName;
+            if let Some(allow) = &campaign_details.allow.channels {
+                let allowed = allow.iter().map(|channel| Channel { 
+                    channel_id: channel.id.clone(), 
+                    channel_login: channel.name.clone() 
+                });
+                candidate_channels.extend(lists.select(game, allowed));
+            } else if let Some(channels) = discover_campaign_channels(client.as_ref(), &state, &campaign.id, game, &campaign_details.game.slug).await {
+                candidate_channels.extend(lists.select(game, channels));
             }
         }
     }
@@ -86,8 +83,8 @@ pub async fn filter_streams (client: Arc<TwitchClient>, campaigns_arc: Arc<Mutex
 
     let channel_pool: HashSet<Channel> = stream::iter(candidate_channels)
         .map(|channel| {
-            let client_clone = client.clone();
-            async move { check_channel_stream(client_clone, channel).await }
+            let (client_clone, state_clone) = (client.clone(), state.clone());
+            async move { check_channel_stream(client_clone, channel, state_clone).await }
         })
         .buffer_unordered(10)
         .filter_map(|res| async move { res })
@@ -98,405 +95,356 @@ pub async fn filter_streams (client: Arc<TwitchClient>, campaigns_arc: Arc<Mutex
     *channel_pool_lock = channel_pool;
     drop(channel_pool_lock);
     debug!("Drop channel_pool updated with {} channels", state.channel_pool.lock().await.len());
-    spawn_ws(client.access_token.clone().expect("Access token is required"), state.clone()).await;
+    let (login, auth_token, ws_state) = (client.login().to_string(), client.access_token().to_string(), state.clone());
+    state.tasks.spawn_supervised("pubsub", RESTART_DELAY, move || pubsub(login.clone(), auth_token.clone(), ws_state.clone()));
+    let tasks = state.tasks.clone();
+    tasks.spawn_supervised("channel_refill", RESTART_DELAY, move || refill_channel_pool(client.clone(), state.clone()));
+}
 
-    tokio::spawn(async move {
-        loop {
-            let channel_pool_lock = state.channel_pool.lock().await;
-            let count = channel_pool_lock.len();
-            drop(channel_pool_lock);
-            if count < MAX_TOPICS {
-                let mut to_add = HashSet::new();
-                let campaigns = campaigns_arc.lock().await.clone();
-                for campaign_queue in campaigns.iter() {
-                    for campaign in campaign_queue {
-                        let allow_channels = state.allow_channels.lock().await;
-                        if let Some(channels) = allow_channels.get(&campaign.id) {
-                            for channel in channels {
-                                if to_add.len() + count  >= MAX_TOPICS {
-                                    break;
-                                }
-
-                                let stream_info = if let Ok(channel) = client.get_stream_info(&channel.name).await {
-                                    channel
-                                } else {
-                                    continue;
-                                };
-
-                                if stream_info.stream.is_some() {
-                                    let available_drops = match client.get_available_drops_for_channel(&channel.id).await {
-                                        Ok(drops) => drops,
-                                        Err(e) => {
-                                            error!("Failed to fetch available drops for {}: {e}", channel.name);
-                                            continue;
-                                        }
-                                    };
-                                    if available_drops.viewerDropCampaigns.is_some() {
-                                        to_add.insert(Channel { channel_id: channel.id.clone(), channel_login: channel.name.clone() });
-                                    }
-                                }
+/// Discovers live streams of `game` for a campaign without an allow list, best ranked first,
+/// and records their rank bonus for `rank_channels`.
+async fn discover_campaign_channels (client: &dyn DropsBackend, state: &AppState, campaign_id: &str, game: &str, slug: &str) -> Option<Vec<Channel>> {
+    let settings = state.discovery.get().map(|discovery| discovery.settings_for(game)).unwrap_or_default();
+    match discover_streams(client, slug, &settings).await {
+        Ok(ranked) => {
+            debug!("Discovered {} streams for {game}", ranked.len());
+            let bonuses = ranked.iter().map(|r| (r.stream.broadcaster.id.clone(), r.bonus())).collect();
+            state.default_channels.lock().await.insert(campaign_id.to_string(), bonuses);
+            Some(ranked.into_iter().map(|r| Channel { channel_id: r.stream.broadcaster.id, channel_login: r.stream.broadcaster.login }).collect())
+        },
+        Err(e) => {
+            error!("Failed to fetch game directory for {slug}: {e}");
+            None
+        }
+    }
+}
+
+/// Tops the channel pool back up to `MAX_TOPICS` with live channels that still have drops.
+async fn refill_channel_pool (client: Arc<dyn DropsBackend>, state: Arc<AppState>) {
+    loop {
+        let channel_pool_lock = state.channel_pool.lock().await;
+        let count = channel_pool_lock.len();
+        drop(channel_pool_lock);
+        if count < MAX_TOPICS {
+            let mut to_add = HashSet::new();
+            let campaigns = state.campaigns.lock().await.clone();
+            let lists = state.streamer_lists.lock().await.clone();
+            for campaign_queue in campaigns.iter() {
+                for campaign in campaign_queue {
+                    let game = &campaign.game.displayName;
+                    if let Some(channels) = state.catalog.allowed_channels(&campaign.id).await {
+                        let allowed = channels.into_iter().map(|channel| Channel { channel_id: channel.id, channel_login: channel.name });
+                        for channel in lists.select(game, allowed) {
+                            if to_add.len() + count  >= MAX_TOPICS {
+                                break;
                             }
-                        } else {
-                            let mut default_channels = state.default_channels.lock().await;
-                            let slug = match client.get_slug(&campaign.game.displayName).await {
-                                Ok(slug) => slug,
-                                Err(e) => {
-                                    error!("Failed to fetch game slug for {}: {e}", campaign.game.displayName);
-                                    continue;
-                                }
-                            };
-                            let game_directory = match client.get_game_directory(&slug, 30, true).await {
-                                Ok(directory) => directory,
-                                Err(e) => {
-                                    error!("Failed to fetch game directory for {}: {e}", slug);
-                                    continue;
-                                }
-                            };
-                            let game_directory: HashSet<GameDirectory> = game_directory.into_iter().collect();
-                            default_channels.insert(campaign.id.clone(), game_directory.clone());
-
-                            for channel in &game_directory {
-                                if to_add.len() + count >= MAX_TOPICS {
-                                    break;
-                                }
-
-                                let stream_info = if let Ok(stream) = client.get_stream_info(&channel.broadcaster.login).await {
-                                    stream
-                                } else {
-                                    continue;
-                                };
-
-                                if stream_info.stream.is_some() {
-                                    let available_drops = match client.get_available_drops_for_channel(&channel.broadcaster.id).await {
-                                        Ok(drops) => drops,
-                                        Err(e) => {
-                                            error!("Failed to fetch available drops for {}: {e}", channel.broadcaster.login);
-                                            continue;
-                                        }
-                                    };
-                                    if available_drops.viewerDropCampaigns.is_some() {
-                                        to_add.insert(Channel { channel_id: channel.broadcaster.id.clone(), channel_login: channel.broadcaster.login.clone() });
-                                    }
-                                }
 
+                            if let Some(channel) = check_channel_stream(client.clone(), channel, state.clone()).await {
+                                to_add.insert(channel);
                             }
-                            drop(default_channels);
                         }
+                    } else {
+                        let Some(details) = state.catalog.details(&campaign.id).await else {
+                            continue;
+                        };
+                        let Some(channels) = discover_campaign_channels(client.as_ref(), &state, &campaign.id, game, &details.game.slug).await else {
+                            continue;
+                        };
 
-                        if to_add.len() + count >= MAX_TOPICS {
-                            break;
+                        for channel in lists.select(game, channels) {
+                            if to_add.len() + count >= MAX_TOPICS {
+                                break;
+                            }
+
+                            if let Some(channel) = check_channel_stream(client.clone(), channel, state.clone()).await {
+                                to_add.insert(channel);
+                            }
                         }
+                    }
 
-                    }   
-                }
+                    if to_add.len() + count >= MAX_TOPICS {
+                        break;
+                    }
 
-                let mut channel_pool_lock = state.channel_pool.lock().await;
-                let mut cur = channel_pool_lock.len();
-                for channel in to_add {
-                    if cur >= MAX_TOPICS { 
-                        break 
-                    };
-                    channel_pool_lock.insert(channel);
-                    cur += 1;
-                }
-                drop(channel_pool_lock);
+                }   
+            }
+
+            let mut channel_pool_lock = state.channel_pool.lock().await;
+            for channel in to_add {
+                if channel_pool_lock.len() >= MAX_TOPICS { 
+                    break 
+                };
+                channel_pool_lock.insert(channel);
             }
-            debug!("Drop channel_pool_lock");
-            sleep(Duration::from_secs(UPDATE_TIME)).await
+            drop(channel_pool_lock);
         }
-    });
+        debug!("Drop channel_pool_lock");
+        sleep(Duration::from_secs(UPDATE_TIME)).await
+    }
 }
 
 //ws_logick
-async fn spawn_ws (auth_token: String, state: Arc<AppState>) {
-    tokio::spawn(async move {
+/// Connects to PubSub at `url` through `proxy`, so the socket leaves from the same IP as the account's GQL calls.
+async fn connect_ws (url: &str, proxy: Option<&str>) -> Result<WebSocketStream<MaybeTlsStream<TcpStream>>, Box<dyn std::error::Error + Send + Sync>> {
+    let parsed = Url::parse(url)?;
+    let host = parsed.host_str().ok_or("PubSub URL has no host")?;
+    let port = parsed.port_or_known_default().ok_or("PubSub URL has no port")?;
+    let tunnel = open_tunnel(host.trim_matches(['[', ']']), port, proxy).await?;
+    let (ws_stream, _) = client_async_tls(url, tunnel).await?;
+    Ok(ws_stream)
+}
+
+/// `login` is the account whose `auth_token` is sent; its current proxy is looked up on every reconnect.
+async fn pubsub (login: String, auth_token: String, state: Arc<AppState>) {
+    let recorder = match state.capture.get() {
+        Some(Capture::Replay(replay)) => return replay_pubsub(replay.clone(), state).await,
+        Some(Capture::Record(recorder)) => Some(recorder.clone()),
+        None => None,
+    };
+    let record = |direction, frame: &str| if let Some(recorder) = &recorder {
+        recorder.pubsub(&login, direction, frame);
+    };
+    let url = state.endpoints.get().cloned().unwrap_or_default().pubsub_url;
+    let mut connected_before = false;
+    loop {
+        METRICS.pubsub_connected.set(0);
+        if connected_before {
+            METRICS.pubsub_reconnects.inc();
+        }
+        let proxy = state.proxy_pool.lock().await.proxy_of(&login).cloned();
+        let ws_stream = match connect_ws(&url, proxy.as_deref()).await {
+            Ok(stream) => stream,
+            Err(e) => {
+                let via = proxy.as_deref().map(redact).unwrap_or_else(|| "direct connection".to_string());
+                error!("Failed to connect to WebSocket via {via}: {e}. Retrying in 30 seconds...");
+                sleep(Duration::from_secs(30)).await;
+                continue;
+            }
+        };
+        connected_before = true;
+        METRICS.pubsub_connected.set(1);
+        let (mut write, mut read) = ws_stream.split();
+        let mut send_channels: HashSet<Channel> = HashSet::new();
         loop {
-            let (ws_stream, _) = match connect_async(WS_URL).await {
-                Ok(stream) => stream,
-                Err(e) => {
-                    error!("Failed to connect to WebSocket: {e}. Retrying in 30 seconds...");
-                    sleep(Duration::from_secs(30)).await;
-                    continue;
-                }
-            };
-            let (mut write, mut read) = ws_stream.split();
-            let mut send_channels: HashSet<Channel> = HashSet::new();
-            loop {
-                let channel_pool = state.channel_pool.lock().await;
-                let new_channels: Vec<Channel> = channel_pool.iter().filter(|id| !send_channels.contains(*id)).cloned().collect();
-                let delete_channels: Vec<Channel> = send_channels.iter().filter(|id| !channel_pool.contains(&id)).cloned().collect();
-                drop(channel_pool);
-
-                if !new_channels.is_empty() {
-                    let topics: Vec<String> = new_channels.iter().map(|channel| format!("video-playback-by-id.{}", channel.channel_id)).collect();
-                    let payload = json!({
-                        "type": "LISTEN",
-                        "data": {
-                            "topics": topics,
-                            "auth_token": auth_token
-                        }
-                    });
-                    let payload = serde_json::to_string(&payload).expect("json! macro production is guaranteed to be serializable");
-                    let payload = tokio_tungstenite::tungstenite::Message::Text(payload.into());
-                    write.send(payload).await.unwrap_or_else(|e| warn!("Failed to send payload to WebSocket: {e}"));
-                    send_channels.extend(new_channels);
-                }
+            let channel_pool = state.channel_pool.lock().await;
+            let new_channels: Vec<Channel> = channel_pool.iter().filter(|id| !send_channels.contains(*id)).cloned().collect();
+            let delete_channels: Vec<Channel> = send_channels.iter().filter(|id| !channel_pool.contains(id)).cloned().collect();
+            drop(channel_pool);
+
+            if !new_channels.is_empty() {
+                let topics: Vec<String> = new_channels.iter().map(|channel| format!("video-playback-by-id.{}", channel.channel_id)).collect();
+                let payload = json!({
+                    "type": "LISTEN",
+                    "data": {
+                        "topics": topics,
+                        "auth_token": auth_token
+                    }
+                });
+                let payload = serde_json::to_string(&payload).expect("json! macro production is guaranteed to be serializable");
+                record(Direction::Sent, &payload);
+                let payload = tokio_tungstenite::tungstenite::Message::Text(payload.into());
+                write.send(payload).await.unwrap_or_else(|e| warn!("Failed to send payload to WebSocket: {e}"));
+                send_channels.extend(new_channels);
+            }
 
-                if !delete_channels.is_empty() {
-                    let delete_topics: Vec<String> = delete_channels.iter().map(|channel| format!("video-playback-by-id.{}", channel.channel_id)).collect();
-                    let payload = json!({
-                        "type": "UNLISTEN",
-                        "data": {
-                            "topics": delete_topics,
-                            "auth_token": auth_token
-                        }
-                    });
-                    let payload = serde_json::to_string(&payload).expect("json! macro production is guaranteed to be serializable");
-                    let payload = tokio_tungstenite::tungstenite::Message::Text(payload.into());
-                    write.send(payload).await.unwrap_or_else(|e| warn!("Failed to send payload to WebSocket: {e}"));
-                    for delete in delete_channels {
-                        send_channels.remove(&delete);
+            if !delete_channels.is_empty() {
+                let delete_topics: Vec<String> = delete_channels.iter().map(|channel| format!("video-playback-by-id.{}", channel.channel_id)).collect();
+                let payload = json!({
+                    "type": "UNLISTEN",
+                    "data": {
+                        "topics": delete_topics,
+                        "auth_token": auth_token
                     }
-                };
+                });
+                let payload = serde_json::to_string(&payload).expect("json! macro production is guaranteed to be serializable");
+                record(Direction::Sent, &payload);
+                let payload = tokio_tungstenite::tungstenite::Message::Text(payload.into());
+                write.send(payload).await.unwrap_or_else(|e| warn!("Failed to send payload to WebSocket: {e}"));
+                for delete in delete_channels {
+                    send_channels.remove(&delete);
+                }
+            };
 
-                if let Some(msg) = read.next().await {
-                    match msg {
-                        Ok(Message::Text(text)) => {
-                            debug!("Received WebSocket message: {text}");
-                            let json: Value = match serde_json::from_str(&text) {
-                                Ok(v) => v,
-                                Err(e) => {
-                                    error!("Failed to parse JSON text: {e}");
-                                    continue;
-                                }
-                            };
-                            let msg_type = json.get("type").and_then(|t| t.as_str()).unwrap_or("");
-                            match msg_type {
-                                "PING" => {
-                                    debug!("Received PING from WebSocket, sending PONG");
-                                    let pong = Message::Text("{\"type\":\"PONG\"}".into());
-                                    let _ = write.send(pong).await;
-                                    continue;
-                                },
-                                "PONG" => {continue;}
-                                "RECONNECT" => {
-                                    warn!("Received RECONNECT from WebSocket, reconnecting...");
-                                    break;
-                                },
-                                "RESPONSE" => {
-                                    if let Some(err) = json.get("error").and_then(|e| e.as_str()) {
-                                        if !err.is_empty() {
-                                            error!("WebSocket RESPONSE error: {err}");
-                                        }
-                                    }
-                                    continue;
-                                },
-                                "MESSAGE" => {
-                                    if let Some(data) = json.get("data") {
-                                        let topic = data.get("topic").and_then(|t| t.as_str()).unwrap_or_default();
-                                        let message_str = data.get("message").and_then(|m| m.as_str()).unwrap_or_default();
-                                        if let Ok(message_json) = serde_json::from_str::<Value>(message_str) {
-                                            if let Some(viewers) = message_json.get("viewers").and_then(|v| v.as_u64()) {
-                                                debug!("Stream {} has {} viewers", topic, viewers);
-                                                if viewers == 0 {
-                                                    if let Some(id_str) = topic.split('.').last() {
-                                                        let mut channel_pool = state.channel_pool.lock().await;
-                                                        channel_pool.retain(|channel| channel.channel_id != id_str);
-                                                        send_channels.retain(|channel| channel.channel_id != id_str);
-                                                    }
-                                                }
-                                            } else {
-                                                debug!("No viewers field in message for topic {}", topic);
-                                            }
-                                        } else {
-                                            error!("Failed to parse message JSON for topic {}", topic);
-                                        }
-                                    }
-                                },
-                                _ => { debug!("Received unknown message type: {}", msg_type) }
-                            }
+            match read.next().await {
+                Some(Ok(Message::Text(text))) => {
+                    record(Direction::Received, &text);
+                    match handle_frame(&text, &state, &mut send_channels).await {
+                        Frame::Handled => {},
+                        Frame::Ping => {
+                            let pong = "{\"type\":\"PONG\"}";
+                            record(Direction::Sent, pong);
+                            let _ = write.send(Message::Text(pong.into())).await;
                         },
-                        Ok(Message::Ping(ping)) => write.send(Message::Pong(ping)).await.unwrap_or_else(|e| error!("Failed to send PONG to WebSocket: {e}")),
-                        Ok(_) => {},
-                        Err(e) => {
-                            debug!("WebSocket error: {e}");
-                            sleep(Duration::from_secs(UPDATE_TIME)).await;
-                            break
-                        } 
+                        Frame::Reconnect => break,
                     }
+                },
+                Some(Ok(Message::Ping(ping))) => write.send(Message::Pong(ping)).await.unwrap_or_else(|e| error!("Failed to send PONG to WebSocket: {e}")),
+                Some(Ok(_)) => {},
+                Some(Err(e)) => {
+                    debug!("WebSocket error: {e}");
+                    sleep(Duration::from_secs(UPDATE_TIME)).await;
+                    break
+                },
+                // A proxy that closes the tunnel cleanly ends the stream, and it stays ended.
+                None => {
+                    warn!("WebSocket closed by Twitch or the proxy, reconnecting in {UPDATE_TIME} seconds");
+                    sleep(Duration::from_secs(UPDATE_TIME)).await;
+                    break
                 }
             }
-        }   
-    });
+        }
+    }   
 }
 
-#[derive(PartialEq, Eq, Clone)]
-struct Priority {
-    priority: u32,
-    name: Channel
+/// What the PubSub loop should do after a text frame.
+enum Frame {
+    Handled,
+    Ping,
+    Reconnect,
 }
 
-impl Ord for Priority {
-    fn cmp (&self, other: &Self) -> std::cmp::Ordering {
-        self.priority.cmp(&other.priority).then_with(|| self.name.channel_id.cmp(&other.name.channel_id))
+async fn handle_frame (text: &str, state: &AppState, send_channels: &mut HashSet<Channel>) -> Frame {
+    debug!("Received WebSocket message: {text}");
+    let json: Value = match serde_json::from_str(text) {
+        Ok(v) => v,
+        Err(e) => {
+            error!("Failed to parse JSON text: {e}");
+            return Frame::Handled;
+        }
+    };
+    let msg_type = json.get("type").and_then(|t| t.as_str()).unwrap_or("");
+    match msg_type {
+        "PING" => {
+            debug!("Received PING from WebSocket, sending PONG");
+            return Frame::Ping;
+        },
+        "PONG" => {},
+        "RECONNECT" => {
+            warn!("Received RECONNECT from WebSocket, reconnecting...");
+            return Frame::Reconnect;
+        },
+        "RESPONSE" => {
+            if let Some(err) = json.get("error").and_then(|e| e.as_str()) && !err.is_empty() {
+                error!("WebSocket RESPONSE error: {err}");
+            }
+        },
+        "MESSAGE" => {
+            if let Some(data) = json.get("data") {
+                let topic = data.get("topic").and_then(|t| t.as_str()).unwrap_or_default();
+                let message_str = data.get("message").and_then(|m| m.as_str()).unwrap_or_default();
+                if let Ok(message_json) = serde_json::from_str::<Value>(message_str) {
+                    let message_type = message_json.get("type").and_then(|t| t.as_str()).unwrap_or_default();
+                    if let "stream-down" | "stream-up" = message_type && let Some(id_str) = topic.split('.').next_back() {
+                        debug!("Channel {id_str} sent {message_type}, dropping its cached status");
+                        state.channel_cache.lock().await.invalidate(id_str);
+                        if message_type == "stream-down" {
+                            state.channel_pool.lock().await.retain(|channel| channel.channel_id != id_str);
+                            send_channels.retain(|channel| channel.channel_id != id_str);
+                        }
+                    } else if let Some(viewers) = message_json.get("viewers").and_then(|v| v.as_u64()) {
+                        debug!("Stream {} has {} viewers", topic, viewers);
+                        if let Some(id_str) = topic.split('.').next_back() {
+                            state.channel_viewers.lock().await.insert(id_str.to_string(), viewers);
+                            state.channel_cache.lock().await.set_viewers(id_str, viewers);
+                        }
+                        if viewers == 0 && let Some(id_str) = topic.split('.').next_back() {
+                            state.channel_cache.lock().await.invalidate(id_str);
+                            let mut channel_pool = state.channel_pool.lock().await;
+                            channel_pool.retain(|channel| channel.channel_id != id_str);
+                            send_channels.retain(|channel| channel.channel_id != id_str);
+                        }
+                    } else {
+                        debug!("No viewers field in message for topic {}", topic);
+                    }
+                } else {
+                    error!("Failed to parse message JSON for topic {}", topic);
+                }
+            }
+        },
+        _ => { debug!("Received unknown message type: {}", msg_type) }
     }
+    Frame::Handled
 }
 
-impl PartialOrd for Priority {
-    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
-        Some(self.cmp(other))
+/// Handles the recorded PubSub frames at the time they arrived in the recording, then stays quiet.
+async fn replay_pubsub (replay: Arc<Replay>, state: Arc<AppState>) {
+    let mut send_channels = HashSet::new();
+    while let Some((at, frame)) = replay.pending_frame() {
+        sleep_until(at.into()).await;
+        handle_frame(frame, &state, &mut send_channels).await;
+        replay.frame_handled();
     }
+    std::future::pending::<()>().await;
 }
 
-async fn send_now_watched (mut stream_candidates_rx: Receiver<BinaryHeap<Priority>>, tx_now_watch: tokio::sync::watch::Sender<Option<Channel>>, notify: Arc<Notify>, tx_for_delete: tokio::sync::watch::Sender<Channel>) {
-    tokio::spawn(async move {
-        loop {
-            if stream_candidates_rx.changed().await.is_ok() {
-                let watch = stream_candidates_rx.borrow().clone();
-                    if let Some(max) = watch.peek() {
-                        debug!("Send: {}", max.name.channel_login);
-                        if let Err(e) = tx_now_watch.send(Some(Channel { channel_id: max.name.channel_id.to_string(), channel_login: max.name.channel_login.to_string() })) {
-                            error!("Failed to send watch update for {}: {e}", max.name.channel_login);
-                        };
-                        notify.notified().await;
-                        debug!("Notified to claim drops for: {}", max.name.channel_login);
-
-                        loop {
-                            if let Err(e) = tx_for_delete.send(max.name.clone()) {
-                                error!("Failed to send delete notification for {}: {e}", max.name.channel_login);
-                                sleep(Duration::from_secs(5)).await;
-                                continue;
-                            } else {
-                                break;
-                            }
-                        }
-
-                        sleep(Duration::from_secs(5)).await;
-                        continue;
-                    } else {
-                        sleep(Duration::from_secs(5)).await;
-                        continue;
-                    }  
-                
-            }  
-        }
-
-    });
+/// Requests from the account tasks and the control API to give up the channel being watched.
+#[derive(Debug, Default)]
+pub struct ReleaseQueue {
+    pending: std::sync::Mutex<Vec<(Channel, ReleaseReason)>>,
+    notify: Notify,
 }
 
-const ALLOW_TIER: u32 = 10_000;
-const DEFAULT_TIER: u32 = 0;
-
-pub async fn update_stream (tx_now_watch: tokio::sync::watch::Sender<Option<Channel>>, notify: Arc<Notify>, state: Arc<AppState>) {
-    tokio::spawn(async move {
-        let mut old_channel_pool: HashSet<Channel> = HashSet::new();
-        let mut watched: HashSet<Channel> = HashSet::new();
-        let mut empty_cycles = 0;
-
-        let (stream_candidates_tx, stream_candidates_rx) = tokio::sync::watch::channel(BinaryHeap::new());
-        let (tx_for_delete, mut rx_for_delete) = tokio::sync::watch::channel(Channel::default());
-
-        send_now_watched(stream_candidates_rx, tx_now_watch, notify, tx_for_delete).await;
-
-        let channel_to_delete = Arc::new(Mutex::new(Channel::default()));
-        let channel_to_delete_clone = Arc::clone(&channel_to_delete);
-
-        tokio::spawn(async move {
-            loop {
-                if rx_for_delete.changed().await.is_ok() {
-                    let channel = rx_for_delete.borrow().clone();
-                    debug!("Channel to delete: {}", channel.channel_login);
-                    *channel_to_delete.lock().await = channel;
-                }
-            }
-        });
+impl ReleaseQueue {
+    pub fn release (&self, channel: &Channel, reason: ReleaseReason) {
+        self.pending.lock().unwrap_or_else(|e| e.into_inner()).push((channel.clone(), reason));
+        self.notify.notify_one();
+    }
 
+    async fn next (&self) -> Vec<(Channel, ReleaseReason)> {
         loop {
-            let channel_pool = state.channel_pool.lock().await;
-            let allow_channels = state.allow_channels.lock().await;
-            let default_channels = state.default_channels.lock().await;
-            let campaign_priority = state.campaign_priority.lock().await;
-
-            if channel_pool.is_empty() || (allow_channels.is_empty() && default_channels.is_empty()) {
-                sleep(Duration::from_secs(UPDATE_TIME)).await;
-                continue;
+            let released = std::mem::take(&mut *self.pending.lock().unwrap_or_else(|e| e.into_inner()));
+            if !released.is_empty() {
+                return released;
             }
+            self.notify.notified().await;
+        }
+    }
+}
 
-            let offline: Vec<Channel> = old_channel_pool.difference(&channel_pool).cloned().collect();
-            for ch in offline {
-                debug!("Channel went offline: {}", ch.channel_login);
-                watched.remove(&ch);
-            }
-
-            let just_watched = {
-                let mut guard = channel_to_delete_clone.lock().await;
-                if *guard != Channel::default() {
-                    let ch = guard.clone();
-                    *guard = Channel::default();
-                    ch
-                } else {
-                    Channel::default()
-                }
-            };
-
-            debug!("Just watched: {}", just_watched.channel_login);
-            if just_watched != Channel::default() {
-                watched.insert(just_watched.clone());
-            }
-            
-            let mut new_heap: BinaryHeap<Priority> = BinaryHeap::new();
-            for channel in channel_pool.iter() {
-                if watched.contains(channel) {
-                    continue;
-                }
-
-                let mut prio = 0;
-
-                for (camp_id, allow_set) in allow_channels.iter() {
-                    if allow_set.iter().any(|s| s.id == channel.channel_id) {
-                        let base = *campaign_priority.get(camp_id).unwrap_or(&0);
-                        let current_prio = ALLOW_TIER + base;
-                        if current_prio > prio { prio = current_prio; }
-                    }
-                }
-            
-                for (camp_id, def_set) in default_channels.iter() {
-                    if def_set.iter().any(|s| s.broadcaster.id == channel.channel_id) {
-                        let base = *campaign_priority.get(camp_id).unwrap_or(&0);
-                        let current_prio = DEFAULT_TIER + base;
-                        if current_prio > prio { prio = current_prio; }
-                    }
-                }
+pub fn update_stream (tx_now_watch: tokio::sync::watch::Sender<Option<Channel>>, releases: Arc<ReleaseQueue>, state: Arc<AppState>) {
+    let tasks = state.tasks.clone();
+    tasks.spawn_supervised("update_stream", RESTART_DELAY, move || schedule_channels(tx_now_watch.clone(), releases.clone(), state.clone()));
+}
 
-                if prio > 0 {
-                    new_heap.push(Priority {
-                        priority: prio,
-                        name: channel.clone(),
-                    });
+/// Feeds the [`Scheduler`] ranking passes, releases and heartbeats, and carries out what it decides.
+async fn schedule_channels (tx_now_watch: tokio::sync::watch::Sender<Option<Channel>>, releases: Arc<ReleaseQueue>, state: Arc<AppState>) {
+    let mut scheduler = Scheduler::new(state.switching.get().cloned().unwrap_or_default());
+    let mut next_pass = Instant::now();
+    loop {
+        let events = tokio::select! {
+            _ = sleep_until(next_pass.into()) => {
+                next_pass = Instant::now() + Duration::from_secs(UPDATE_TIME);
+                vec![Event::Pool(Box::new(pool_snapshot(&state).await))]
+            },
+            released = releases.next() => released.into_iter().map(|(channel, reason)| Event::Released(channel, reason)).collect(),
+            _ = sleep(Duration::from_secs(SWITCH_TICK)) => vec![Event::Tick(state.heartbeats.lock().await.clone())],
+        };
+
+        for event in events {
+            for action in scheduler.handle(event, Instant::now()) {
+                match action {
+                    Action::Watch { channel, reason } => {
+                        info!(channel = %channel.channel_login, reason, "Switching to channel {} ({reason})", channel.channel_login);
+                        if let Err(e) = tx_now_watch.send(Some(channel.clone())) {
+                            error!("Failed to send watch update for {}: {e}", channel.channel_login);
+                        }
+                    },
+                    Action::Left { channel, reason } => info!(channel = %channel.channel_login, reason = reason.as_str(), "Leaving channel {} ({})", channel.channel_login, reason.as_str()),
+                    Action::Ranked(ranking) => *state.channel_priority.lock().await = ranking,
+                    Action::Starved => warn!("No streams found for 3 ranking passes in a row"),
                 }
             }
-
-            if new_heap.is_empty() {
-                empty_cycles += 1;
-                watched.clear();
-
-                let pause = if empty_cycles >= 3 {
-                    warn!("No streams found for 3 cycles, pausing updates for 45 seconds");
-                    empty_cycles = 0;
-                    Duration::from_secs(45)
-                } else {
-                    Duration::from_secs(UPDATE_TIME)
-                };
-
-                sleep(pause).await;
-                continue;
-            }
-            
-            empty_cycles = 0;
-            old_channel_pool = channel_pool.clone();
-            let _ = stream_candidates_tx.send(new_heap);
-
-            sleep(Duration::from_secs(UPDATE_TIME)).await;
         }
-    });
-}
\ No newline at end of file
+    }
+}
+
+/// The shared state the ranking works from. An empty pool is sent too, the [`Scheduler`] decides what that means.
+async fn pool_snapshot (state: &AppState) -> PoolSnapshot {
+    let lists = state.streamer_lists.lock().await.clone();
+    let campaign_games = state.campaigns.lock().await.iter().flatten().map(|c| (c.id.clone(), c.game.displayName.clone())).collect();
+    let allow_lists = state.catalog.allow_lists().await;
+    let discovered = state.default_channels.lock().await.clone();
+    let channels = state.channel_pool.lock().await.clone();
+    let campaign_priority = state.campaign_priority.lock().await.clone();
+    PoolSnapshot { channels, allow_lists, discovered, campaign_priority, campaign_games, lists }
+}
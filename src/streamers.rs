@@ -0,0 +1,144 @@
+use std::{collections::{HashMap, HashSet}, path::PathBuf, sync::Arc, time::{Duration, SystemTime}};
+
+use serde::Serialize;
+use tokio::{fs, time::sleep};
+use tracing::{info, warn};
+
+use crate::{r#static::{AppState, Channel}, tasks::RESTART_DELAY};
+
+/// How often the list files are checked for changes.
+const RELOAD_INTERVAL: u64 = 30;
+
+/// Why a channel ranks where it does, shown next to it in the status output.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PickReason {
+    /// Listed in `preferred.txt`, for the game or globally.
+    Preferred,
+    /// On the campaign's own channel list.
+    AllowList,
+    /// Found in the game's directory.
+    Directory,
+}
+
+impl PickReason {
+    pub fn as_str (&self) -> &'static str {
+        match self {
+            PickReason::Preferred => "preferred",
+            PickReason::AllowList => "allow list",
+            PickReason::Directory => "directory",
+        }
+    }
+}
+
+/// Streamers from `preferred.txt` and `blocked.txt`, by lowercase login.
+#[derive(Debug, Default, Clone)]
+pub struct StreamerLists {
+    preferred: HashSet<String>,
+    /// Preferred streamers by lowercase game name.
+    preferred_by_game: HashMap<String, HashSet<String>>,
+    blocked: HashSet<String>,
+}
+
+impl StreamerLists {
+    /// `preferred.txt` takes one login per line. Logins before the first `[Game name]` header are
+    /// preferred for every game, the ones after a header only for that game. `blocked.txt` is a plain list.
+    pub fn parse (preferred: &str, blocked: &str) -> Self {
+        let mut lists = StreamerLists::default();
+        let mut game: Option<String> = None;
+        for line in entries(preferred) {
+            if let Some(name) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+                game = Some(name.trim().to_lowercase());
+                continue;
+            }
+            let login = line.to_lowercase();
+            match &game {
+                Some(game) => { lists.preferred_by_game.entry(game.clone()).or_default().insert(login); },
+                None => { lists.preferred.insert(login); },
+            }
+        }
+        lists.blocked = entries(blocked).map(str::to_lowercase).collect();
+        lists
+    }
+
+    pub fn is_blocked (&self, login: &str) -> bool {
+        self.blocked.contains(&login.to_lowercase())
+    }
+
+    pub fn is_preferred (&self, game: &str, login: &str) -> bool {
+        let login = login.to_lowercase();
+        self.preferred.contains(&login) || self.preferred_by_game.get(&game.trim().to_lowercase()).is_some_and(|logins| logins.contains(&login))
+    }
+
+    /// Drops blocked channels and moves preferred ones to the front, keeping the order otherwise,
+    /// so preferred streamers are checked first and survive the pool cap.
+    pub fn select (&self, game: &str, channels: impl IntoIterator<Item = Channel>) -> Vec<Channel> {
+        let (preferred, rest): (Vec<Channel>, Vec<Channel>) = channels.into_iter()
+            .filter(|channel| !self.is_blocked(&channel.channel_login))
+            .partition(|channel| self.is_preferred(game, &channel.channel_login));
+        preferred.into_iter().chain(rest).collect()
+    }
+}
+
+/// Non-empty lines without ` #` comments, the same rules as `proxies.txt`.
+fn entries (text: &str) -> impl Iterator<Item = &str> {
+    text.lines()
+        .map(|line| line.trim_start_matches('\u{feff}'))
+        .map(|line| line.split_once(" #").map_or(line, |(entry, _)| entry).trim())
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+}
+
+/// Loads both lists now and reloads them whenever either file changes.
+pub async fn watch_streamer_lists (preferred: PathBuf, blocked: PathBuf, state: Arc<AppState>) {
+    let (lists, _) = load(&preferred, &blocked).await;
+    *state.streamer_lists.lock().await = lists;
+    let tasks = state.tasks.clone();
+    tasks.spawn_supervised("streamer_lists", RESTART_DELAY, move || reload_loop(preferred.clone(), blocked.clone(), state.clone()));
+}
+
+async fn reload_loop (preferred: PathBuf, blocked: PathBuf, state: Arc<AppState>) {
+    let mut seen = (modified(&preferred).await, modified(&blocked).await);
+    loop {
+        sleep(Duration::from_secs(RELOAD_INTERVAL)).await;
+        let current = (modified(&preferred).await, modified(&blocked).await);
+        if current == seen {
+            continue;
+        }
+        seen = current;
+
+        let (lists, counts) = load(&preferred, &blocked).await;
+        info!("Streamer lists reloaded: {} preferred, {} blocked", counts.0, counts.1);
+        *state.streamer_lists.lock().await = lists.clone();
+
+        let watching = state.farming.lock().await.as_ref().and_then(|ctx| ctx.channel_rx.borrow().clone());
+        if let Some(watching) = watching && lists.is_blocked(&watching.channel_login) {
+            info!("Channel {} is now blocked", watching.channel_login);
+            state.skip_channel().await;
+        }
+    }
+}
+
+async fn modified (path: &PathBuf) -> Option<SystemTime> {
+    fs::metadata(path).await.and_then(|m| m.modified()).ok()
+}
+
+/// A missing file counts as an empty list.
+async fn load (preferred: &PathBuf, blocked: &PathBuf) -> (StreamerLists, (usize, usize)) {
+    let read = |path: &PathBuf| {
+        let path = path.clone();
+        async move {
+            match fs::read_to_string(&path).await {
+                Ok(text) => text,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+                Err(e) => {
+                    warn!("Failed to read {}: {e}", path.display());
+                    String::new()
+                }
+            }
+        }
+    };
+    let lists = StreamerLists::parse(&read(preferred).await, &read(blocked).await);
+    let preferred = lists.preferred.len() + lists.preferred_by_game.values().map(HashSet::len).sum::<usize>();
+    let blocked = lists.blocked.len();
+    (lists, (preferred, blocked))
+}
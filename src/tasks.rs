@@ -0,0 +1,132 @@
+use std::{collections::HashMap, sync::{Arc, Mutex, atomic::{AtomicU64, Ordering}}, time::Duration};
+
+use serde::Serialize;
+use tokio::time::sleep;
+use tokio_util::sync::CancellationToken;
+use tracing::{error, info, warn};
+
+/// How long a supervised task waits before it is restarted.
+pub const RESTART_DELAY: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TaskState {
+    Running,
+    Restarting,
+    Stopped,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct TaskStatus {
+    pub name: String,
+    pub state: TaskState,
+    pub restarts: u32,
+    pub started_at: String,
+    pub last_error: Option<String>,
+}
+
+#[derive(Debug)]
+struct TaskEntry {
+    generation: u64,
+    token: CancellationToken,
+    status: TaskStatus,
+}
+
+/// Named long-running tasks. Spawning a name that is already registered cancels the old task first,
+/// so running the farming setup twice replaces its tasks instead of duplicating them.
+#[derive(Debug, Clone, Default)]
+pub struct TaskRegistry {
+    tasks: Arc<Mutex<HashMap<String, TaskEntry>>>,
+    root: CancellationToken,
+    next_generation: Arc<AtomicU64>,
+}
+
+impl TaskRegistry {
+    /// Runs `make_future` as `name`, starting it again after `restart_delay` whenever it returns or panics,
+    /// until the task is cancelled, replaced, or the registry shuts down.
+    pub fn spawn_supervised<F, Fut> (&self, name: impl Into<String>, restart_delay: Duration, mut make_future: F)
+    where
+        F: FnMut() -> Fut + Send + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        let name = name.into();
+        let token = self.root.child_token();
+        let generation = self.next_generation.fetch_add(1, Ordering::Relaxed);
+        let status = TaskStatus {
+            name: name.clone(),
+            state: TaskState::Running,
+            restarts: 0,
+            started_at: chrono::Local::now().to_rfc3339(),
+            last_error: None,
+        };
+        if let Some(old) = self.lock().insert(name.clone(), TaskEntry { generation, token: token.clone(), status }) {
+            old.token.cancel();
+        }
+
+        let registry = self.clone();
+        tokio::spawn(async move {
+            loop {
+                let mut handle = tokio::spawn(make_future());
+                let outcome = tokio::select! {
+                    _ = token.cancelled() => {
+                        handle.abort();
+                        registry.update(&name, generation, |status| status.state = TaskState::Stopped);
+                        return;
+                    },
+                    outcome = &mut handle => outcome,
+                };
+
+                let reason = match outcome {
+                    Ok(()) => {
+                        warn!("Task '{name}' exited unexpectedly (it should run forever). Restarting in {}s...", restart_delay.as_secs());
+                        "exited".to_string()
+                    },
+                    Err(join_err) => {
+                        error!("Task '{name}' panicked: {join_err}. Restarting in {}s...", restart_delay.as_secs());
+                        join_err.to_string()
+                    },
+                };
+                registry.update(&name, generation, |status| {
+                    status.state = TaskState::Restarting;
+                    status.restarts += 1;
+                    status.last_error = Some(reason);
+                });
+
+                tokio::select! {
+                    _ = token.cancelled() => {
+                        registry.update(&name, generation, |status| status.state = TaskState::Stopped);
+                        return;
+                    },
+                    _ = sleep(restart_delay) => {},
+                }
+                info!("Restarting task '{name}'");
+                registry.update(&name, generation, |status| {
+                    status.state = TaskState::Running;
+                    status.started_at = chrono::Local::now().to_rfc3339();
+                });
+            }
+        });
+    }
+
+    /// Cancels every task, used on shutdown.
+    pub fn shutdown (&self) {
+        self.root.cancel();
+    }
+
+    pub fn statuses (&self) -> Vec<TaskStatus> {
+        let mut statuses: Vec<TaskStatus> = self.lock().values().map(|entry| entry.status.clone()).collect();
+        statuses.sort_by(|a, b| a.name.cmp(&b.name));
+        statuses
+    }
+
+    fn lock (&self) -> std::sync::MutexGuard<'_, HashMap<String, TaskEntry>> {
+        self.tasks.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Updates the entry only if it still belongs to this generation of the task, not a replacement.
+    fn update (&self, name: &str, generation: u64, change: impl FnOnce(&mut TaskStatus)) {
+        if let Some(entry) = self.lock().get_mut(name) && entry.generation == generation {
+            change(&mut entry.status);
+        }
+    }
+}
@@ -0,0 +1,291 @@
+use std::{collections::VecDeque, io, sync::{Arc, Mutex}, time::Duration};
+
+use ratatui::{DefaultTerminal, Frame, crossterm::event::{self, Event, KeyCode, KeyEventKind, KeyModifiers}, layout::{Constraint, Layout}, style::{Color, Modifier, Style, Stylize}, text::Line, widgets::{Block, Cell, List, ListItem, Paragraph, Row, Table, TableState}};
+use tokio::time::sleep;
+use tracing::error;
+use tracing_subscriber::fmt::MakeWriter;
+
+use crate::{r#static::AppState, tasks::RESTART_DELAY};
+
+const LOG_TAIL_LINES: usize = 200;
+const REFRESH: Duration = Duration::from_millis(250);
+
+/// Keeps the last log lines in memory for the dashboard. Used as a `tracing` writer.
+#[derive(Debug, Clone, Default)]
+pub struct LogTail(Arc<Mutex<VecDeque<String>>>);
+
+impl LogTail {
+    fn lines (&self) -> Vec<String> {
+        self.0.lock().map(|lines| lines.iter().cloned().collect()).unwrap_or_default()
+    }
+}
+
+pub struct LogTailWriter {
+    tail: LogTail,
+    buf: Vec<u8>,
+}
+
+impl io::Write for LogTailWriter {
+    fn write (&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buf.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush (&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for LogTailWriter {
+    fn drop (&mut self) {
+        let text = String::from_utf8_lossy(&self.buf);
+        if let Ok(mut lines) = self.tail.0.lock() {
+            for line in text.lines().filter(|l| !l.is_empty()) {
+                if lines.len() >= LOG_TAIL_LINES {
+                    lines.pop_front();
+                }
+                lines.push_back(line.to_string());
+            }
+        }
+    }
+}
+
+impl<'a> MakeWriter<'a> for LogTail {
+    type Writer = LogTailWriter;
+
+    fn make_writer (&'a self) -> Self::Writer {
+        LogTailWriter { tail: self.clone(), buf: Vec::new() }
+    }
+}
+
+struct AccountRow {
+    login: String,
+    channel: String,
+    game: String,
+    drop: String,
+    current: u64,
+    required: u64,
+    status: String,
+}
+
+struct CampaignRow {
+    game: String,
+    name: String,
+    pending: usize,
+    total: usize,
+}
+
+struct PoolRow {
+    login: String,
+    priority: Option<u32>,
+    reason: &'static str,
+    viewers: Option<u64>,
+}
+
+struct Snapshot {
+    accounts: Vec<AccountRow>,
+    campaigns: Vec<CampaignRow>,
+    pool: Vec<PoolRow>,
+    watching: Option<String>,
+    logs: Vec<String>,
+}
+
+impl Snapshot {
+    async fn collect (state: &AppState, logs: &LogTail) -> Self {
+        let logins: Vec<String> = state.accounts.lock().await.iter().flatten().map(|c| c.login().to_string()).collect();
+        let statuses = state.account_status.lock().await.clone();
+        let accounts = logins.into_iter().map(|login| {
+            let status = statuses.get(&login).cloned().unwrap_or_default();
+            AccountRow {
+                channel: status.channel.map(|c| c.channel_login).unwrap_or_default(),
+                game: status.game.unwrap_or_default(),
+                drop: status.drop_name.or(status.drop_id).unwrap_or_default(),
+                current: status.current_minutes,
+                required: status.required_minutes,
+                status: if status.status.is_empty() { "Waiting...".to_string() } else { status.status },
+                login,
+            }
+        }).collect();
+
+        let groups = state.campaigns.lock().await.clone();
+        let campaign_drops = state.campaign_drops.lock().await.clone();
+        let pending = state.pending_drops.lock().await.clone();
+        let campaigns = groups.iter().flatten().map(|campaign| {
+            let drops = campaign_drops.get(&campaign.id).map(Vec::as_slice).unwrap_or_default();
+            CampaignRow {
+                game: campaign.game.displayName.clone(),
+                name: campaign.name.clone(),
+                pending: drops.iter().filter(|id| pending.contains(*id)).count(),
+                total: drops.len(),
+            }
+        }).collect();
+
+        let priorities = state.channel_priority.lock().await.clone();
+        let viewers = state.channel_viewers.lock().await.clone();
+        let mut pool: Vec<PoolRow> = state.channel_pool.lock().await.iter().map(|channel| PoolRow {
+            login: channel.channel_login.clone(),
+            priority: priorities.get(&channel.channel_id).map(|rank| rank.priority),
+            reason: priorities.get(&channel.channel_id).map_or("-", |rank| rank.reason.as_str()),
+            viewers: viewers.get(&channel.channel_id).copied(),
+        }).collect();
+        pool.sort_by(|a, b| b.priority.cmp(&a.priority).then_with(|| b.viewers.cmp(&a.viewers)));
+
+        let watching = state.farming.lock().await.as_ref().and_then(|ctx| ctx.channel_rx.borrow().clone()).map(|c| c.channel_login);
+        Snapshot { accounts, campaigns, pool, watching, logs: logs.lines() }
+    }
+}
+
+/// Full-screen dashboard shown while farming with `--tui`. The terminal is restored when this is dropped.
+pub struct Tui {
+    running: Arc<Mutex<bool>>,
+}
+
+impl Drop for Tui {
+    fn drop (&mut self) {
+        stop_terminal(&self.running);
+    }
+}
+
+fn stop_terminal (running: &Mutex<bool>) {
+    let mut running = running.lock().unwrap_or_else(|e| e.into_inner());
+    if *running {
+        *running = false;
+        ratatui::restore();
+    }
+}
+
+/// Starts the dashboard as the supervised "tui" task.
+pub fn spawn_tui (state: Arc<AppState>, logs: LogTail) -> Tui {
+    let running = Arc::new(Mutex::new(true));
+    let terminal = Arc::new(Mutex::new(ratatui::init()));
+    let running_clone = running.clone();
+    let tasks = state.tasks.clone();
+    tasks.spawn_supervised("tui", RESTART_DELAY, move || dashboard(state.clone(), logs.clone(), running_clone.clone(), terminal.clone()));
+
+    Tui { running }
+}
+
+async fn dashboard (state: Arc<AppState>, logs: LogTail, running: Arc<Mutex<bool>>, terminal: Arc<Mutex<DefaultTerminal>>) {
+    let mut table = TableState::default().with_selected(Some(0));
+    loop {
+        let snapshot = Snapshot::collect(&state, &logs).await;
+        if let Some(selected) = table.selected() && selected >= snapshot.accounts.len() {
+            table.select(Some(snapshot.accounts.len().saturating_sub(1)));
+        }
+
+        {
+            // Drawing happens under the lock so `Tui::drop` can't restore the terminal mid-frame.
+            let running = running.lock().unwrap_or_else(|e| e.into_inner());
+            if !*running {
+                break;
+            }
+            if let Err(e) = terminal.lock().unwrap_or_else(|e| e.into_inner()).draw(|frame| draw(frame, &snapshot, &mut table)) {
+                error!("Failed to draw dashboard: {e}");
+            }
+        }
+
+        while event::poll(Duration::ZERO).unwrap_or(false) {
+            let Ok(Event::Key(key)) = event::read() else { continue };
+            if key.kind != KeyEventKind::Press {
+                continue;
+            }
+            // Raw mode swallows SIGINT, so Ctrl-C arrives as a key press.
+            if key.code == KeyCode::Char('q') || (key.code == KeyCode::Char('c') && key.modifiers.contains(KeyModifiers::CONTROL)) {
+                stop_terminal(&running);
+                state.shutdown.cancel();
+                break;
+            }
+            match key.code {
+                KeyCode::Up | KeyCode::Char('k') => table.select_previous(),
+                KeyCode::Down | KeyCode::Char('j') => table.select_next(),
+                KeyCode::Char('s') => {
+                    state.skip_channel().await;
+                },
+                KeyCode::Char('p') => {
+                    if let Some(row) = table.selected().and_then(|i| snapshot.accounts.get(i)) {
+                        let paused = state.paused_accounts.lock().await.contains(&row.login);
+                        state.set_paused(&row.login, !paused).await;
+                    }
+                },
+                KeyCode::Char('r') => {
+                    tracing::info!("Campaign refresh requested from the dashboard");
+                    state.campaign_refresh.notify_one();
+                },
+                _ => {}
+            }
+        }
+        sleep(REFRESH).await;
+    }
+    // A closed dashboard stays closed, parking here keeps the supervisor from bringing it back.
+    std::future::pending::<()>().await;
+}
+
+fn progress_bar (current: u64, required: u64) -> String {
+    if required == 0 {
+        return "-".to_string();
+    }
+    let ratio = (current as f64 / required as f64).min(1.0);
+    let filled = (ratio * 10.0).round() as usize;
+    format!("{}{} {:>3.0}%", "█".repeat(filled), "░".repeat(10 - filled), ratio * 100.0)
+}
+
+fn eta (current: u64, required: u64) -> String {
+    match required.saturating_sub(current) {
+        _ if required == 0 => "-".to_string(),
+        0 => "ready".to_string(),
+        left if left >= 60 => format!("{}h{:02}m", left / 60, left % 60),
+        left => format!("{left}m"),
+    }
+}
+
+fn draw (frame: &mut Frame, snapshot: &Snapshot, table: &mut TableState) {
+    let [accounts_area, middle_area, logs_area, footer_area] = Layout::vertical([
+        Constraint::Percentage(40),
+        Constraint::Percentage(30),
+        Constraint::Fill(1),
+        Constraint::Length(1),
+    ]).areas(frame.area());
+    let [campaigns_area, pool_area] = Layout::horizontal([Constraint::Percentage(55), Constraint::Percentage(45)]).areas(middle_area);
+
+    let header = Row::new(["Account", "Channel", "Game", "Drop", "Progress", "ETA", "Status"]).style(Style::new().bold());
+    let rows = snapshot.accounts.iter().map(|a| Row::new([
+        Cell::from(a.login.as_str()),
+        Cell::from(a.channel.as_str()),
+        Cell::from(a.game.as_str()),
+        Cell::from(a.drop.as_str()),
+        Cell::from(progress_bar(a.current, a.required)).fg(Color::Cyan),
+        Cell::from(eta(a.current, a.required)),
+        Cell::from(a.status.as_str()),
+    ]));
+    let widths = [Constraint::Length(18), Constraint::Length(18), Constraint::Length(20), Constraint::Fill(1), Constraint::Length(15), Constraint::Length(7), Constraint::Length(26)];
+    let accounts = Table::new(rows, widths)
+        .header(header)
+        .block(Block::bordered().title(format!(" Accounts ({}) ", snapshot.accounts.len())))
+        .row_highlight_style(Style::new().add_modifier(Modifier::REVERSED));
+    frame.render_stateful_widget(accounts, accounts_area, table);
+
+    let campaigns: Vec<ListItem> = snapshot.campaigns.iter().map(|c| {
+        ListItem::new(format!("{} · {} ({}/{} pending)", c.game, c.name, c.pending, c.total))
+    }).collect();
+    frame.render_widget(List::new(campaigns).block(Block::bordered().title(" Campaign queue ")), campaigns_area);
+
+    let pool_rows = snapshot.pool.iter().map(|p| {
+        let row = Row::new([
+            p.login.clone(),
+            p.priority.map(|p| p.to_string()).unwrap_or_else(|| "-".to_string()),
+            p.reason.to_string(),
+            p.viewers.map(|v| v.to_string()).unwrap_or_else(|| "-".to_string()),
+        ]);
+        if snapshot.watching.as_ref() == Some(&p.login) { row.fg(Color::Green) } else { row }
+    });
+    let pool = Table::new(pool_rows, [Constraint::Fill(1), Constraint::Length(8), Constraint::Length(10), Constraint::Length(8)])
+        .header(Row::new(["Channel", "Priority", "Reason", "Viewers"]).style(Style::new().bold()))
+        .block(Block::bordered().title(format!(" Channel pool ({}) ", snapshot.pool.len())));
+    frame.render_widget(pool, pool_area);
+
+    let visible = logs_area.height.saturating_sub(2) as usize;
+    let logs: Vec<Line> = snapshot.logs.iter().skip(snapshot.logs.len().saturating_sub(visible)).map(|l| Line::from(l.as_str())).collect();
+    frame.render_widget(Paragraph::new(logs).block(Block::bordered().title(" Log ")), logs_area);
+
+    frame.render_widget(Paragraph::new(" ↑/↓ select account   s skip channel   p pause/resume account   r refresh campaigns   q quit").dark_gray(), footer_area);
+}
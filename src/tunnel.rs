@@ -0,0 +1,132 @@
+use std::{error::Error, time::Duration};
+
+use base64::{Engine, engine::general_purpose::STANDARD};
+use percent_encoding::percent_decode_str;
+use reqwest::Url;
+use tokio::{io::{AsyncReadExt, AsyncWriteExt}, net::{TcpStream, lookup_host}, time::timeout};
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(30);
+const MAX_RESPONSE_HEAD: usize = 8 * 1024;
+
+/// Opens a TCP stream to `host:port` through `proxy` (HTTP CONNECT or SOCKS5), or directly without one.
+/// The returned stream is a plain tunnel, TLS and the WebSocket handshake are done by the caller.
+pub async fn open_tunnel (host: &str, port: u16, proxy: Option<&str>) -> Result<TcpStream, Box<dyn Error + Send + Sync>> {
+    let Some(proxy) = proxy else {
+        return Ok(timeout(CONNECT_TIMEOUT, TcpStream::connect((host, port))).await.map_err(|_| "connect timed out")??);
+    };
+
+    let url = Url::parse(proxy)?;
+    let proxy_host = url.host_str().ok_or("proxy URL has no host")?;
+    let proxy_port = url.port_or_known_default().unwrap_or(1080);
+    let credentials = match url.username() {
+        "" => None,
+        user => Some((decode(user)?, decode(url.password().unwrap_or_default())?)),
+    };
+
+    let handshake = async {
+        let mut stream = TcpStream::connect((proxy_host.trim_matches(['[', ']']), proxy_port)).await?;
+        match url.scheme() {
+            "http" => http_connect(&mut stream, host, port, credentials).await?,
+            "socks5" => {
+                let addr = lookup_host((host, port)).await?.next().ok_or("failed to resolve target host")?;
+                socks5_connect(&mut stream, &addr.ip().to_string(), port, credentials).await?
+            },
+            "socks5h" => socks5_connect(&mut stream, host, port, credentials).await?,
+            scheme => return Err(format!("{scheme} proxies can't tunnel the WebSocket, use http or socks5"))?,
+        }
+        Ok::<_, Box<dyn Error + Send + Sync>>(stream)
+    };
+    timeout(CONNECT_TIMEOUT, handshake).await.map_err(|_| "proxy handshake timed out")?
+}
+
+fn decode (part: &str) -> Result<String, Box<dyn Error + Send + Sync>> {
+    Ok(percent_decode_str(part).decode_utf8()?.into_owned())
+}
+
+async fn http_connect (stream: &mut TcpStream, host: &str, port: u16, credentials: Option<(String, String)>) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let mut request = format!("CONNECT {host}:{port} HTTP/1.1\r\nHost: {host}:{port}\r\n");
+    if let Some((user, pass)) = credentials {
+        request.push_str(&format!("Proxy-Authorization: Basic {}\r\n", STANDARD.encode(format!("{user}:{pass}"))));
+    }
+    request.push_str("\r\n");
+    stream.write_all(request.as_bytes()).await?;
+
+    // Read byte by byte so nothing past the response head is consumed from the tunnel.
+    let mut head = Vec::new();
+    while !head.ends_with(b"\r\n\r\n") {
+        if head.len() >= MAX_RESPONSE_HEAD {
+            return Err("proxy sent an oversized CONNECT response")?;
+        }
+        head.push(stream.read_u8().await?);
+    }
+
+    let status_line = String::from_utf8_lossy(&head);
+    let status_line = status_line.lines().next().unwrap_or_default();
+    match status_line.split_whitespace().nth(1) {
+        Some(code) if code.starts_with('2') => Ok(()),
+        Some("407") => Err("proxy rejected the credentials")?,
+        _ => Err(format!("proxy refused CONNECT: {status_line}"))?,
+    }
+}
+
+async fn socks5_connect (stream: &mut TcpStream, host: &str, port: u16, credentials: Option<(String, String)>) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let method = if credentials.is_some() { 0x02 } else { 0x00 };
+    stream.write_all(&[0x05, 0x01, method]).await?;
+    let mut reply = [0u8; 2];
+    stream.read_exact(&mut reply).await?;
+    if reply[0] != 0x05 || reply[1] != method {
+        return Err("SOCKS5 proxy doesn't support the required authentication method")?;
+    }
+
+    if let Some((user, pass)) = credentials {
+        if user.len() > 255 || pass.len() > 255 {
+            return Err("SOCKS5 credentials are longer than 255 bytes")?;
+        }
+        let mut auth = vec![0x01, user.len() as u8];
+        auth.extend_from_slice(user.as_bytes());
+        auth.push(pass.len() as u8);
+        auth.extend_from_slice(pass.as_bytes());
+        stream.write_all(&auth).await?;
+        stream.read_exact(&mut reply).await?;
+        if reply[1] != 0x00 {
+            return Err("proxy rejected the credentials")?;
+        }
+    }
+
+    let mut request = vec![0x05, 0x01, 0x00];
+    match host.parse::<std::net::IpAddr>() {
+        Ok(std::net::IpAddr::V4(ip)) => {
+            request.push(0x01);
+            request.extend_from_slice(&ip.octets());
+        },
+        Ok(std::net::IpAddr::V6(ip)) => {
+            request.push(0x04);
+            request.extend_from_slice(&ip.octets());
+        },
+        Err(_) => {
+            if host.len() > 255 {
+                return Err("target host name is too long for SOCKS5")?;
+            }
+            request.push(0x03);
+            request.push(host.len() as u8);
+            request.extend_from_slice(host.as_bytes());
+        }
+    }
+    request.extend_from_slice(&port.to_be_bytes());
+    stream.write_all(&request).await?;
+
+    let mut head = [0u8; 4];
+    stream.read_exact(&mut head).await?;
+    if head[1] != 0x00 {
+        return Err(format!("SOCKS5 proxy refused the connection (code {})", head[1]))?;
+    }
+    let bound_len = match head[3] {
+        0x01 => 4,
+        0x04 => 16,
+        0x03 => stream.read_u8().await? as usize,
+        atyp => return Err(format!("SOCKS5 proxy replied with unknown address type {atyp}"))?,
+    };
+    let mut bound = vec![0u8; bound_len + 2];
+    stream.read_exact(&mut bound).await?;
+    Ok(())
+}
@@ -0,0 +1,397 @@
+//! Optional encryption of saved sessions at rest.
+//!
+//! `twitch_gql_rs` can only build a `TwitchClient` from a session file on disk, so loading a sealed
+//! session briefly writes the decrypted JSON, OAuth token included, to a private `data/.<login>.<random>.tmp`
+//! file. It is deleted as soon as the client is built, on every exit path. A crash or kill can still
+//! leave one behind, so leftovers are deleted whenever the vault opens.
+
+use std::{env, fmt, io, path::{Path, PathBuf}};
+
+use argon2::{Algorithm, Argon2, Params, Version};
+use base64::{Engine, engine::general_purpose::STANDARD};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce, aead::{Aead, KeyInit, Payload}};
+use serde::{Deserialize, Serialize};
+use tokio::{fs, io::AsyncWriteExt};
+use tracing::{error, info, warn};
+use twitch_gql_rs::TwitchClient;
+
+use crate::{cli::require_interactive, config::VaultConfig, error::AppError};
+
+pub const SESSION_EXTENSION: &str = "session";
+pub const VAULT_FILE: &str = "vault.json";
+const TEMP_SUFFIX: &str = ".tmp";
+/// Suffixes of the files a rekey stages next to each session and the header, see [`Vault::rekey`].
+const STAGED_SUFFIX: &str = ".new";
+const BACKUP_SUFFIX: &str = ".old";
+
+const PASSPHRASE_ENV: &str = "DROPSENTRY_VAULT_PASSPHRASE";
+const NEW_PASSPHRASE_ENV: &str = "DROPSENTRY_VAULT_NEW_PASSPHRASE";
+const KEY_FILE_ENV: &str = "DROPSENTRY_VAULT_KEY_FILE";
+const CHECK_PLAINTEXT: &[u8] = b"dropsentry-vault";
+const CHECK_AAD: &[u8] = b"vault";
+const NONCE_LEN: usize = 24;
+const SALT_LEN: usize = 16;
+const KEY_LEN: usize = 32;
+
+/// Stored in `data/vault.json`. Holds everything needed to re-derive the key, plus a sealed
+/// known value used to reject a wrong passphrase before any session is touched.
+#[derive(Debug, Serialize, Deserialize)]
+struct VaultHeader {
+    version: u32,
+    m_cost: u32,
+    t_cost: u32,
+    p_cost: u32,
+    salt: String,
+    check: String,
+}
+
+/// Passphrase-derived key (Argon2id) used to seal session files with XChaCha20-Poly1305.
+/// Each sealed file is `nonce || ciphertext`, authenticated against the file stem (the login).
+pub struct Vault {
+    cipher: XChaCha20Poly1305,
+}
+
+impl fmt::Debug for Vault {
+    fn fmt (&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Vault { .. }")
+    }
+}
+
+impl Vault {
+    /// Unlocks the vault in `home_dir`, creating it on first use.
+    /// A rekey that was interrupted is finished or rolled back first, for the sessions in `dirs`.
+    pub async fn open (home_dir: &Path, dirs: &[&Path], config: &VaultConfig) -> Result<Self, AppError> {
+        recover_rekey(home_dir, dirs).await?;
+        for stale in list_files(home_dir, is_temp_session).await? {
+            match fs::remove_file(&stale).await {
+                Ok(()) => warn!("Deleted a decrypted session left behind by an earlier run: {}", stale.display()),
+                Err(e) => error!("Failed to delete leftover decrypted session {}: {e}", stale.display()),
+            }
+        }
+        let header_path = home_dir.join(VAULT_FILE);
+        if header_path.exists() {
+            return Self::unlock(&header_path, config).await;
+        }
+
+        info!("Creating a new session vault at {}", header_path.display());
+        let passphrase = match configured_passphrase(config).await? {
+            Some(passphrase) => passphrase,
+            None => prompt_new_passphrase("▸ New vault passphrase")?,
+        };
+        let (vault, header) = Self::create(&passphrase)?;
+        fs::write(&header_path, serde_json::to_string_pretty(&header)?).await?;
+        Ok(vault)
+    }
+
+    async fn unlock (header_path: &Path, config: &VaultConfig) -> Result<Self, AppError> {
+        let header: VaultHeader = serde_json::from_str(&fs::read_to_string(header_path).await?)?;
+        let passphrase = match configured_passphrase(config).await? {
+            Some(passphrase) => passphrase,
+            None => {
+                require_interactive("Unlocking the session vault")?;
+                dialoguer::Password::new().with_prompt("▸ Vault passphrase").interact()?
+            },
+        };
+
+        let salt = STANDARD.decode(&header.salt).map_err(|e| AppError::Vault(format!("Corrupted vault salt: {e}")))?;
+        let params = Params::new(header.m_cost, header.t_cost, header.p_cost, Some(KEY_LEN)).map_err(|e| AppError::Vault(format!("Invalid vault parameters: {e}")))?;
+        let vault = Self::derive(&passphrase, &salt, params)?;
+
+        let check = STANDARD.decode(&header.check).map_err(|e| AppError::Vault(format!("Corrupted vault check: {e}")))?;
+        if vault.open_blob(&check, CHECK_AAD).ok().as_deref() != Some(CHECK_PLAINTEXT) {
+            return Err(AppError::Vault("Wrong vault passphrase".to_string()));
+        }
+        Ok(vault)
+    }
+
+    fn create (passphrase: &str) -> Result<(Self, VaultHeader), AppError> {
+        let salt: [u8; SALT_LEN] = rand::random();
+        let params = Params::new(Params::DEFAULT_M_COST, Params::DEFAULT_T_COST, Params::DEFAULT_P_COST, Some(KEY_LEN)).map_err(|e| AppError::Vault(format!("Invalid vault parameters: {e}")))?;
+        let vault = Self::derive(passphrase, &salt, params.clone())?;
+        let header = VaultHeader {
+            version: 1,
+            m_cost: params.m_cost(),
+            t_cost: params.t_cost(),
+            p_cost: params.p_cost(),
+            salt: STANDARD.encode(salt),
+            check: STANDARD.encode(vault.seal(CHECK_PLAINTEXT, CHECK_AAD)?),
+        };
+        Ok((vault, header))
+    }
+
+    fn derive (passphrase: &str, salt: &[u8], params: Params) -> Result<Self, AppError> {
+        let mut key = [0u8; KEY_LEN];
+        Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+            .hash_password_into(passphrase.as_bytes(), salt, &mut key)
+            .map_err(|e| AppError::Vault(format!("Failed to derive vault key: {e}")))?;
+        let cipher = XChaCha20Poly1305::new_from_slice(&key).map_err(|e| AppError::Vault(format!("Invalid vault key: {e}")))?;
+        Ok(Vault { cipher })
+    }
+
+    fn seal (&self, plaintext: &[u8], aad: &[u8]) -> Result<Vec<u8>, AppError> {
+        let nonce: [u8; NONCE_LEN] = rand::random();
+        let ciphertext = self.cipher.encrypt(XNonce::from_slice(&nonce), Payload { msg: plaintext, aad }).map_err(|_| AppError::Vault("Failed to encrypt session".to_string()))?;
+        let mut blob = nonce.to_vec();
+        blob.extend(ciphertext);
+        Ok(blob)
+    }
+
+    fn open_blob (&self, blob: &[u8], aad: &[u8]) -> Result<Vec<u8>, AppError> {
+        if blob.len() < NONCE_LEN {
+            return Err(AppError::Vault("Encrypted session is truncated".to_string()));
+        }
+        let (nonce, ciphertext) = blob.split_at(NONCE_LEN);
+        let plaintext = self.cipher.decrypt(XNonce::from_slice(nonce), Payload { msg: ciphertext, aad }).map_err(|_| AppError::Vault("Failed to decrypt session: wrong passphrase or corrupted file".to_string()))?;
+        Ok(plaintext)
+    }
+
+    /// Seals a client into `path`. Like [`TwitchClient::save_file`], an existing file is never overwritten.
+    pub async fn save_client (&self, client: &TwitchClient, path: &Path) -> Result<(), AppError> {
+        if path.exists() {
+            return Err(AppError::Io(io::Error::new(io::ErrorKind::AlreadyExists, format!("Session file {} already exists", path.display()))));
+        }
+        let sealed = self.seal(&serde_json::to_vec_pretty(client)?, &aad_for(path))?;
+        write_private(path, &sealed).await?;
+        Ok(())
+    }
+
+    /// Unseals a session file into a ready-to-use client.
+    pub async fn load_client (&self, path: &Path, proxy: &Option<String>) -> Result<TwitchClient, AppError> {
+        let plaintext = self.open_blob(&fs::read(path).await?, &aad_for(path))?;
+
+        // `TwitchClient` can only build its HTTP client from a file, so the plaintext lives
+        // in a private temp file for the duration of the load, see the module docs.
+        let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+        let temp = TempSession(path.with_file_name(format!(".{stem}.{:016x}{TEMP_SUFFIX}", rand::random::<u64>())));
+        write_private(&temp.0, &plaintext).await?;
+        Ok(TwitchClient::load_from_file(&temp.0, proxy).await?)
+    }
+
+    /// Encrypts a plaintext `<login>.json` session in place and returns the new path.
+    pub async fn migrate_file (&self, path: &Path) -> Result<PathBuf, AppError> {
+        let sealed_path = path.with_extension(SESSION_EXTENSION);
+        let sealed = self.seal(&fs::read(path).await?, &aad_for(&sealed_path))?;
+        write_private(&sealed_path, &sealed).await?;
+        fs::remove_file(path).await?;
+        Ok(sealed_path)
+    }
+
+    /// Encrypts every plaintext session found in `dirs`.
+    pub async fn migrate (&self, dirs: &[&Path]) -> Result<usize, AppError> {
+        let mut migrated = 0;
+        for dir in dirs {
+            for path in list_files(dir, |path| crate::accounts::is_session_file(path) && path.extension().is_some_and(|e| e == "json")).await? {
+                self.migrate_file(&path).await?;
+                info!("Encrypted plaintext session {}", path.display());
+                migrated += 1;
+            }
+        }
+        Ok(migrated)
+    }
+
+    /// Re-encrypts every sealed session in `dirs` under a new passphrase.
+    /// All sessions are decrypted before anything is written, so a wrong passphrase changes nothing.
+    ///
+    /// Every session gets a `.session.old` copy and a `.session.new` resealed file, and the new header
+    /// goes to `vault.json.new`, written last. Copying the old header to `vault.json.old` commits the
+    /// rekey, after which the staged files replace the live ones. [`recover_rekey`] rolls an interrupted
+    /// rekey back before the commit and forward after it, so the header always matches the sessions.
+    pub async fn rekey (home_dir: &Path, dirs: &[&Path], config: &VaultConfig) -> Result<usize, AppError> {
+        let header_path = home_dir.join(VAULT_FILE);
+        if !header_path.exists() {
+            return Err(AppError::Config("No vault found. Enable it with \"vault\": { \"enabled\": true } in data/config.json first".to_string()));
+        }
+        recover_rekey(home_dir, dirs).await?;
+        let old = Self::unlock(&header_path, config).await?;
+
+        let mut sessions = Vec::new();
+        for dir in dirs {
+            for path in list_files(dir, |path| path.extension().is_some_and(|e| e == SESSION_EXTENSION)).await? {
+                let sealed = fs::read(&path).await?;
+                let plaintext = old.open_blob(&sealed, &aad_for(&path))?;
+                sessions.push((path, sealed, plaintext));
+            }
+        }
+
+        let passphrase = match env::var(NEW_PASSPHRASE_ENV) {
+            Ok(passphrase) if !passphrase.is_empty() => passphrase,
+            _ => prompt_new_passphrase("▸ New vault passphrase")?,
+        };
+        let (new, header) = Self::create(&passphrase)?;
+
+        let staging = async {
+            for (path, sealed, plaintext) in &sessions {
+                write_private(&with_suffix(path, BACKUP_SUFFIX), sealed).await?;
+                write_private(&with_suffix(path, STAGED_SUFFIX), &new.seal(plaintext, &aad_for(path))?).await?;
+            }
+            fs::write(with_suffix(&header_path, STAGED_SUFFIX), serde_json::to_string_pretty(&header)?).await?;
+            Ok::<_, AppError>(())
+        };
+        if let Err(e) = staging.await {
+            discard_staged(home_dir, dirs).await;
+            return Err(e);
+        }
+
+        let marker = with_suffix(&header_path, BACKUP_SUFFIX);
+        if let Err(e) = fs::copy(&header_path, &marker).await {
+            let _ = fs::remove_file(&marker).await;
+            discard_staged(home_dir, dirs).await;
+            return Err(e.into());
+        }
+        if let Err(e) = roll_forward(home_dir, dirs).await {
+            // Without the marker the next open rolls back too, should this attempt fail halfway.
+            let _ = fs::remove_file(&marker).await;
+            if let Err(rollback) = roll_back(home_dir, dirs).await {
+                error!("Failed to roll back the vault rekey, it is rolled back the next time the vault opens: {rollback}");
+            }
+            return Err(e);
+        }
+        discard_staged(home_dir, dirs).await;
+        Ok(sessions.len())
+    }
+}
+
+/// Brings back a consistent vault after an interrupted [`Vault::rekey`]: a committed rekey is
+/// finished, an uncommitted one is undone, and the files it staged are deleted.
+async fn recover_rekey (home_dir: &Path, dirs: &[&Path]) -> Result<(), AppError> {
+    let header_path = home_dir.join(VAULT_FILE);
+    let committed = with_suffix(&header_path, BACKUP_SUFFIX).exists();
+    if with_suffix(&header_path, STAGED_SUFFIX).exists() {
+        if committed {
+            warn!("Finishing a vault rekey that was interrupted after it was committed");
+            roll_forward(home_dir, dirs).await?;
+        } else {
+            warn!("Rolling back a vault rekey that was interrupted before it was committed");
+            roll_back(home_dir, dirs).await?;
+        }
+    }
+    discard_staged(home_dir, dirs).await;
+    Ok(())
+}
+
+/// Moves the resealed sessions and the new header into place.
+async fn roll_forward (home_dir: &Path, dirs: &[&Path]) -> Result<(), AppError> {
+    for dir in dirs {
+        for staged in list_files(dir, |path| is_staged_session(path, STAGED_SUFFIX)).await? {
+            fs::rename(&staged, staged.with_extension("")).await?;
+        }
+    }
+    let header_path = home_dir.join(VAULT_FILE);
+    fs::rename(with_suffix(&header_path, STAGED_SUFFIX), header_path).await?;
+    Ok(())
+}
+
+/// Puts the sessions sealed under the old key back and drops the new header.
+async fn roll_back (home_dir: &Path, dirs: &[&Path]) -> Result<(), AppError> {
+    for dir in dirs {
+        for backup in list_files(dir, |path| is_staged_session(path, BACKUP_SUFFIX)).await? {
+            fs::rename(&backup, backup.with_extension("")).await?;
+        }
+    }
+    fs::remove_file(with_suffix(&home_dir.join(VAULT_FILE), STAGED_SUFFIX)).await?;
+    Ok(())
+}
+
+/// Deletes whatever a rekey left next to the sessions and the header. Errors are only logged,
+/// the files are tried again on the next open.
+async fn discard_staged (home_dir: &Path, dirs: &[&Path]) {
+    let header_path = home_dir.join(VAULT_FILE);
+    let mut leftovers = vec![with_suffix(&header_path, STAGED_SUFFIX), with_suffix(&header_path, BACKUP_SUFFIX)];
+    for dir in dirs {
+        match list_files(dir, |path| is_staged_session(path, STAGED_SUFFIX) || is_staged_session(path, BACKUP_SUFFIX)).await {
+            Ok(files) => leftovers.extend(files),
+            Err(e) => error!("Failed to look for files left behind by a vault rekey in {}: {e}", dir.display()),
+        }
+    }
+    for path in leftovers {
+        match fs::remove_file(&path).await {
+            Ok(()) => {},
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {},
+            Err(e) => error!("Failed to delete {} left behind by a vault rekey: {e}", path.display()),
+        }
+    }
+}
+
+fn with_suffix (path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(suffix);
+    PathBuf::from(name)
+}
+
+fn is_staged_session (path: &Path, suffix: &str) -> bool {
+    path.file_name().unwrap_or_default().to_string_lossy().ends_with(&format!(".{SESSION_EXTENSION}{suffix}"))
+}
+
+/// A decrypted session on disk, deleted when dropped, including on errors and cancellation.
+struct TempSession(PathBuf);
+
+impl Drop for TempSession {
+    fn drop (&mut self) {
+        match std::fs::remove_file(&self.0) {
+            Ok(()) => {},
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {},
+            Err(e) => error!("Failed to delete decrypted session {}, delete it by hand: {e}", self.0.display()),
+        }
+    }
+}
+
+fn is_temp_session (path: &Path) -> bool {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    name.starts_with('.') && name.ends_with(TEMP_SUFFIX)
+}
+
+fn aad_for (path: &Path) -> Vec<u8> {
+    path.file_stem().unwrap_or_default().to_string_lossy().as_bytes().to_vec()
+}
+
+async fn configured_passphrase (config: &VaultConfig) -> Result<Option<String>, AppError> {
+    if let Ok(passphrase) = env::var(PASSPHRASE_ENV) && !passphrase.is_empty() {
+        return Ok(Some(passphrase));
+    }
+    let key_file = env::var(KEY_FILE_ENV).ok().filter(|p| !p.is_empty()).or_else(|| Some(config.key_file.clone()).filter(|p| !p.is_empty()));
+    if let Some(key_file) = key_file {
+        let passphrase = fs::read_to_string(&key_file).await.map_err(|e| AppError::Config(format!("Failed to read vault key file {key_file}: {e}")))?;
+        let passphrase = passphrase.trim_end_matches(['\r', '\n']).to_string();
+        if passphrase.is_empty() {
+            return Err(AppError::Config(format!("Vault key file {key_file} is empty")));
+        }
+        return Ok(Some(passphrase));
+    }
+    Ok(None)
+}
+
+fn prompt_new_passphrase (prompt: &str) -> Result<String, AppError> {
+    require_interactive("Setting a vault passphrase")?;
+    let passphrase = dialoguer::Password::new()
+        .with_prompt(prompt)
+        .with_confirmation("▸ Repeat passphrase", "Passphrases do not match")
+        .validate_with(|input: &String| if input.is_empty() { Err("Passphrase cannot be empty") } else { Ok(()) })
+        .interact()?;
+    Ok(passphrase)
+}
+
+async fn list_files (dir: &Path, filter: impl Fn(&Path) -> bool) -> Result<Vec<PathBuf>, AppError> {
+    let mut files = Vec::new();
+    if !dir.exists() {
+        return Ok(files);
+    }
+    let mut entries = fs::read_dir(dir).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        let path = entry.path();
+        if path.is_file() && filter(&path) {
+            files.push(path);
+        }
+    }
+    Ok(files)
+}
+
+async fn write_private (path: &Path, contents: &[u8]) -> Result<(), AppError> {
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    options.mode(0o600);
+    let mut file = options.open(path).await?;
+    file.write_all(contents).await?;
+    file.flush().await?;
+    Ok(())
+}
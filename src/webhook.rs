@@ -4,7 +4,9 @@ use rand::{rng, seq::IndexedRandom};
 use reqwest::{Client, Proxy};
 use serde_json::json;
 use tracing::error;
-use tokio::sync::mpsc::Receiver;
+use tokio::{sync::mpsc::Receiver, task::JoinHandle};
+
+use crate::{metrics::METRICS, proxy::redact};
 
 #[derive(Debug, Default, Clone)]
 pub struct WebhookSendFormat {
@@ -17,7 +19,8 @@ pub struct WebhookSendFormat {
     pub status: String,
 }
 
-pub async fn webhook_message_worker(wh_url: String, mut info_rx: Receiver<WebhookSendFormat>, proxies: &[String]) {
+/// Posts and then edits one embed per account. The returned task ends once every sender is dropped.
+pub async fn webhook_message_worker(wh_url: String, mut info_rx: Receiver<WebhookSendFormat>, proxies: &[String]) -> JoinHandle<()> {
     let random_proxy = proxies.choose(&mut rng());
 
     let client = if let Some(proxy_str) = random_proxy {
@@ -29,7 +32,7 @@ pub async fn webhook_message_worker(wh_url: String, mut info_rx: Receiver<Webhoo
                 })
             },
             Err(e) => {
-                error!("Proxy error {}: {}", proxy_str, e);
+                error!("Proxy error {}: {}", redact(proxy_str), e);
                 Client::new()
             }
         }
@@ -85,8 +88,16 @@ pub async fn webhook_message_worker(wh_url: String, mut info_rx: Receiver<Webhoo
 
             if let Some(msg_id) = account_messages.get(&info.twitch_name) {
                 let edit_url = format!("{}/messages/{}", wh_url, msg_id);
-                if let Err(e) = client.patch(edit_url).json(&payload).send().await {
-                    error!("Failed to edit message: {}", e);
+                match client.patch(edit_url).json(&payload).send().await {
+                    Ok(response) if !response.status().is_success() => {
+                        METRICS.webhook_failures.inc();
+                        error!("Failed to edit message: HTTP {}", response.status());
+                    },
+                    Ok(_) => {},
+                    Err(e) => {
+                        METRICS.webhook_failures.inc();
+                        error!("Failed to edit message: {}", e);
+                    }
                 }
             } else {
                 let post_url = format!("{}?wait=true", wh_url);
@@ -102,11 +113,17 @@ pub async fn webhook_message_worker(wh_url: String, mut info_rx: Receiver<Webhoo
                             } else {
                                 error!("Failed to parse JSON response");
                             }
+                        } else {
+                            METRICS.webhook_failures.inc();
+                            error!("Failed to send webhook message: HTTP {}", response.status());
                         }
                     },
-                    Err(e) => error!("Failed to send webhook message: {}", e)
+                    Err(e) => {
+                        METRICS.webhook_failures.inc();
+                        error!("Failed to send webhook message: {}", e);
+                    }
                 }
             }
         }
-    });
-}
\ No newline at end of file
+    })
+}
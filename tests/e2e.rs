@@ -0,0 +1,189 @@
+//! Runs the real binary, headless, against the mock Twitch in `tests/mock`.
+//! Each test gets its own working directory with a config, a session and `games.txt`, and its own mock.
+
+mod mock;
+
+use std::{path::PathBuf, process::Stdio, time::Duration};
+
+use serde_json::json;
+use tokio::{process::{Child, Command}, time::{Instant, sleep}};
+
+use mock::{CA_CERT, Hit, MockTwitch, PUBSUB_URL, Scenario};
+
+const LOGIN: &str = "mockviewer";
+/// The claim sweep first looks at the inventory `claim_sweep_secs` in.
+const CLAIM_WAIT: Duration = Duration::from_secs(30);
+const WATCH_WAIT: Duration = Duration::from_secs(60);
+
+struct Farm {
+    dir: PathBuf,
+    child: Child,
+    mock: MockTwitch,
+}
+
+impl Farm {
+    async fn start (name: &str, scenario: Scenario, args: &[&str]) -> Self {
+        let dir = std::env::temp_dir().join(format!("dropsentry-e2e-{name}-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(dir.join("data")).unwrap();
+        std::fs::create_dir_all(dir.join("lists")).unwrap();
+
+        let mock = MockTwitch::start(scenario.clone()).await;
+        let config = json!({
+            "games_path": "./lists/games.txt",
+            "autostart": false,
+            "proxies_path": "./lists/proxies.txt",
+            "discord_webhook_url": "",
+            "proxy_overrides": { scenario.login.clone(): mock.proxy_url() },
+            "intervals": { "progress_secs": 2, "claim_sweep_secs": 3 },
+            "endpoints": { "pubsub_url": PUBSUB_URL },
+        });
+        let session = json!({
+            "client_id": "kimne78kx3ncx6brgo4mv6wki5h1ko",
+            "user_agent": "Mozilla/5.0",
+            "client_url": "https://www.twitch.tv",
+            "device_id": "e2e-device",
+            "user_id": scenario.user_id,
+            "login": scenario.login,
+            "access_token": "e2e-token",
+        });
+        std::fs::write(dir.join("data/config.json"), config.to_string()).unwrap();
+        std::fs::write(dir.join(format!("data/{}.json", scenario.login)), session.to_string()).unwrap();
+        std::fs::write(dir.join("lists/games.txt"), format!("{}\n", scenario.game)).unwrap();
+        std::fs::write(dir.join("lists/proxies.txt"), "").unwrap();
+
+        let log = std::fs::File::create(dir.join("farm.log")).unwrap();
+        let child = Command::new(env!("CARGO_BIN_EXE_twitchdrops_miner"))
+            .args(["--headless", "--log-format=logfmt"])
+            .args(args)
+            .current_dir(&dir)
+            .env("SSL_CERT_FILE", CA_CERT)
+            // Autostart is turned off on every start, keep that away from the real desktop session.
+            .env("HOME", &dir)
+            .env("XDG_CONFIG_HOME", dir.join(".config"))
+            .env_remove("HTTP_PROXY").env_remove("HTTPS_PROXY").env_remove("ALL_PROXY")
+            .env_remove("http_proxy").env_remove("https_proxy").env_remove("all_proxy")
+            .stdin(Stdio::null())
+            .stdout(log.try_clone().unwrap())
+            .stderr(log)
+            .kill_on_drop(true)
+            .spawn()
+            .expect("start the farm");
+        Farm { dir, child, mock }
+    }
+
+    /// Waits until `found` holds for what the farm sent the mock, failing with the farm's log after `limit`.
+    async fn wait_for (&mut self, what: &str, limit: Duration, found: impl Fn(&[Hit]) -> bool) {
+        self.wait_until(what, limit, |farm| found(&farm.mock.hits())).await;
+    }
+
+    async fn wait_until (&mut self, what: &str, limit: Duration, found: impl Fn(&Self) -> bool) {
+        let deadline = Instant::now() + limit;
+        while Instant::now() < deadline {
+            if found(self) {
+                return;
+            }
+            if let Ok(Some(status)) = self.child.try_wait() {
+                panic!("the farm exited with {status} while waiting for {what}\n{}", self.log());
+            }
+            sleep(Duration::from_millis(250)).await;
+        }
+        panic!("timed out waiting for {what}\n{}", self.log());
+    }
+
+    fn cache (&self) -> String {
+        std::fs::read_to_string(self.dir.join("data/cache.json")).unwrap_or_default()
+    }
+
+    fn log (&self) -> String {
+        let log = std::fs::read_to_string(self.dir.join("farm.log")).unwrap_or_default();
+        let lines: Vec<&str> = log.lines().collect();
+        lines[lines.len().saturating_sub(60)..].join("\n")
+    }
+}
+
+impl Drop for Farm {
+    fn drop (&mut self) {
+        let _ = self.child.start_kill();
+        let _ = std::fs::remove_dir_all(&self.dir);
+    }
+}
+
+fn watched (hits: &[Hit], login: &str) -> bool {
+    hits.iter().any(|hit| matches!(hit, Hit::Watch { channel } if channel == login))
+}
+
+fn listening (hits: &[Hit], channel_id: &str) -> bool {
+    let topic = format!("video-playback-by-id.{channel_id}");
+    hits.iter().any(|hit| matches!(hit, Hit::Listen { topics } if topics.contains(&topic)))
+}
+
+fn claimed (hits: &[Hit], instance_id: &str) -> bool {
+    hits.iter().any(|hit| matches!(hit, Hit::Gql { operation, variables } if operation == "DropsPage_ClaimDropRewards" && variables["input"]["dropInstanceID"] == instance_id))
+}
+
+#[tokio::test]
+async fn farms_an_allow_listed_channel_and_claims_the_drop () {
+    let scenario = Scenario::new(LOGIN)
+        .channel("3001", "offlinestreamer", 5000, false)
+        .channel("3002", "allowedstreamer", 40, true)
+        .channel("3003", "otherstreamer", 9000, true)
+        .allow_list(&["offlinestreamer", "allowedstreamer"])
+        .progress(15, 15);
+    let instance_id = scenario.drop_instance_id.clone();
+    let allowed_id = scenario.channel_id("allowedstreamer");
+    let mut farm = Farm::start("claim", scenario, &[]).await;
+
+    farm.wait_for("a heartbeat on the live allow-listed channel", WATCH_WAIT, |hits| watched(hits, "allowedstreamer")).await;
+    farm.wait_for("a PubSub subscription for the watched channel", WATCH_WAIT, |hits| listening(hits, &allowed_id)).await;
+    farm.wait_for("the drop claim", CLAIM_WAIT, |hits| claimed(hits, &instance_id)).await;
+
+    let hits = farm.mock.hits();
+    assert!(!watched(&hits, "offlinestreamer") && !watched(&hits, "otherstreamer"), "only the live allow-listed channel should be watched");
+    assert!(farm.mock.scenario().claimed);
+    let cache = tokio::fs::read_to_string(farm.dir.join("data/cache.json")).await.unwrap_or_default();
+    assert!(cache.contains("drop-1"), "the claimed drop should be cached, got {cache}");
+}
+
+/// Records a claim against the mock, then replays the capture with the mock out of reach.
+#[tokio::test]
+async fn replays_a_recorded_claim_offline () {
+    let scenario = Scenario::new(LOGIN)
+        .channel("5001", "recordedstreamer", 300, true)
+        .progress(15, 15);
+    let instance_id = scenario.drop_instance_id.clone();
+    let capture = std::env::temp_dir().join(format!("dropsentry-e2e-capture-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&capture);
+    let mut record = Farm::start("record", scenario.clone(), &["--record", capture.to_str().unwrap()]).await;
+
+    record.wait_for("the drop claim", CLAIM_WAIT, |hits| claimed(hits, &instance_id)).await;
+    record.wait_until("the claimed drop in the cache", WATCH_WAIT, |farm| farm.cache().contains("drop-1")).await;
+    drop(record);
+
+    let recorded = std::fs::read_to_string(capture.join("capture.jsonl")).unwrap();
+    assert!(!recorded.contains("e2e-token"), "the access token should be redacted from the capture");
+    assert!(recorded.contains(r#""operation":"claim_drop""#) && recorded.contains(instance_id.as_str()));
+
+    // The replay talks to nobody, so its fresh mock must stay untouched.
+    let mut replay = Farm::start("replay", scenario, &["--replay", capture.to_str().unwrap()]).await;
+    replay.wait_until("the replayed claim in the cache", CLAIM_WAIT, |farm| farm.cache().contains("drop-1")).await;
+    assert!(replay.mock.hits().is_empty(), "the replay shouldn't reach Twitch, got {:?}", replay.mock.hits());
+    let _ = std::fs::remove_dir_all(&capture);
+}
+
+#[tokio::test]
+async fn moves_on_when_the_watched_channel_goes_offline () {
+    let scenario = Scenario::new(LOGIN)
+        .channel("4001", "bigstreamer", 900, true)
+        .channel("4002", "smallstreamer", 100, true)
+        .progress(5, 60);
+    let big_id = scenario.channel_id("bigstreamer");
+    let mut farm = Farm::start("offline", scenario, &[]).await;
+
+    farm.wait_for("a heartbeat on the best ranked directory channel", WATCH_WAIT, |hits| watched(hits, "bigstreamer")).await;
+    farm.wait_for("a PubSub subscription for the watched channel", WATCH_WAIT, |hits| listening(hits, &big_id)).await;
+    assert!(!watched(&farm.mock.hits(), "smallstreamer"));
+
+    farm.mock.stream_down("bigstreamer");
+    farm.wait_for("a heartbeat on the next channel", Duration::from_secs(90), |hits| watched(hits, "smallstreamer")).await;
+}
@@ -0,0 +1,467 @@
+//! A scripted stand-in for Twitch: GQL, the channel page and spade beacon behind the watch heartbeat, and PubSub.
+//!
+//! twitch-gql-rs always talks to `gql.twitch.tv`, so the farm reaches the mock through its proxy setting.
+//! The mock accepts the CONNECT, terminates TLS with the certificate in `tests/fixtures/tls` and answers
+//! from the [`Scenario`]. The farm trusts the fixture CA through `SSL_CERT_FILE`. The fixtures were made with:
+//!
+//! ```sh
+//! openssl req -x509 -newkey ec -pkeyopt ec_paramgen_curve:prime256v1 -nodes -keyout ca.key -out ca.pem -days 36500 \
+//!     -subj "/CN=DropSentry test CA" -addext "basicConstraints=critical,CA:TRUE" -addext "keyUsage=critical,keyCertSign,cRLSign"
+//! openssl req -newkey ec -pkeyopt ec_paramgen_curve:prime256v1 -nodes -keyout key.pem -out leaf.csr -subj "/CN=twitch.tv"
+//! openssl x509 -req -in leaf.csr -CA ca.pem -CAkey ca.key -CAcreateserial -out cert.pem -days 36500 -extfile leaf.ext
+//! ```
+//!
+//! with `subjectAltName=DNS:twitch.tv,DNS:*.twitch.tv` in `leaf.ext`.
+
+use std::{net::SocketAddr, sync::{Arc, Mutex}};
+
+use base64::{Engine, engine::general_purpose::STANDARD};
+use futures_util::{SinkExt, StreamExt};
+use percent_encoding::percent_decode_str;
+use serde_json::{Value, json};
+use tokio::{io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader}, net::{TcpListener, TcpStream}, sync::broadcast, task::JoinHandle};
+use tokio_rustls::{TlsAcceptor, rustls::{ServerConfig, pki_types::{CertificateDer, PrivateKeyDer, pem::PemObject}}};
+use tokio_tungstenite::tungstenite::Message;
+use twitch_gql_rs::structs::*;
+
+pub const CA_CERT: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/tls/ca.pem");
+/// Plain `ws://`, so the configurable PubSub URL is what gets the farm here.
+pub const PUBSUB_URL: &str = "ws://pubsub-edge.twitch.tv/v1";
+const PUBSUB_HOST: &str = "pubsub-edge.twitch.tv";
+const SPADE_URL: &str = "https://spade.twitch.tv/track";
+const START_AT: &str = "2020-01-01T00:00:00Z";
+const END_AT: &str = "2099-01-01T00:00:00Z";
+
+#[derive(Debug, Clone)]
+pub struct MockChannel {
+    pub id: String,
+    pub login: String,
+    pub live: bool,
+    pub viewers: u64,
+}
+
+/// One account farming one campaign with a single time-based drop.
+#[derive(Debug, Clone)]
+pub struct Scenario {
+    pub login: String,
+    pub user_id: String,
+    pub game: String,
+    pub game_id: String,
+    pub slug: String,
+    pub campaign_id: String,
+    /// Logins the campaign is restricted to, `None` to farm from the game directory.
+    pub allow_list: Option<Vec<String>>,
+    pub drop_id: String,
+    pub drop_instance_id: String,
+    pub required_minutes: u64,
+    pub watched_minutes: u64,
+    pub claimed: bool,
+    pub channels: Vec<MockChannel>,
+}
+
+impl Scenario {
+    pub fn new (login: &str) -> Self {
+        Scenario {
+            login: login.to_string(),
+            user_id: "1000".to_string(),
+            game: "Mock Game".to_string(),
+            game_id: "2000".to_string(),
+            slug: "mock-game".to_string(),
+            campaign_id: "campaign-1".to_string(),
+            allow_list: None,
+            drop_id: "drop-1".to_string(),
+            drop_instance_id: "1000#campaign-1#drop-1".to_string(),
+            required_minutes: 60,
+            watched_minutes: 0,
+            claimed: false,
+            channels: Vec::new(),
+        }
+    }
+
+    pub fn channel (mut self, id: &str, login: &str, viewers: u64, live: bool) -> Self {
+        self.channels.push(MockChannel { id: id.to_string(), login: login.to_string(), live, viewers });
+        self
+    }
+
+    pub fn allow_list (mut self, logins: &[&str]) -> Self {
+        self.allow_list = Some(logins.iter().map(|login| login.to_string()).collect());
+        self
+    }
+
+    pub fn progress (mut self, watched: u64, required: u64) -> Self {
+        self.watched_minutes = watched;
+        self.required_minutes = required;
+        self
+    }
+
+    pub fn channel_id (&self, login: &str) -> String {
+        self.find(login).map(|c| c.id.clone()).unwrap_or_else(|| panic!("no channel {login} in the scenario"))
+    }
+
+    fn find (&self, login: &str) -> Option<&MockChannel> {
+        self.channels.iter().find(|c| c.login.eq_ignore_ascii_case(login))
+    }
+}
+
+/// Something the farm did that a test can wait for.
+#[derive(Debug, Clone)]
+pub enum Hit {
+    Gql { operation: String, variables: Value },
+    /// A minute-watched beacon for a channel login.
+    Watch { channel: String },
+    Listen { topics: Vec<String> },
+}
+
+struct Shared {
+    scenario: Mutex<Scenario>,
+    hits: Mutex<Vec<Hit>>,
+    pubsub: broadcast::Sender<String>,
+    tls: TlsAcceptor,
+}
+
+pub struct MockTwitch {
+    addr: SocketAddr,
+    shared: Arc<Shared>,
+    server: JoinHandle<()>,
+}
+
+impl MockTwitch {
+    pub async fn start (scenario: Scenario) -> Self {
+        let certs = CertificateDer::pem_slice_iter(include_bytes!("../fixtures/tls/cert.pem")).collect::<Result<Vec<_>, _>>().expect("fixture certificate");
+        let key = PrivateKeyDer::from_pem_slice(include_bytes!("../fixtures/tls/key.pem")).expect("fixture key");
+        let mut tls = ServerConfig::builder().with_no_client_auth().with_single_cert(certs, key).expect("fixture TLS config");
+        tls.alpn_protocols = vec![b"http/1.1".to_vec()];
+
+        let shared = Arc::new(Shared {
+            scenario: Mutex::new(scenario),
+            hits: Mutex::default(),
+            pubsub: broadcast::channel(16).0,
+            tls: TlsAcceptor::from(Arc::new(tls)),
+        });
+        let listener = TcpListener::bind("127.0.0.1:0").await.expect("bind mock server");
+        let addr = listener.local_addr().expect("mock server address");
+        let accepting = shared.clone();
+        let server = tokio::spawn(async move {
+            while let Ok((tcp, _)) = listener.accept().await {
+                let shared = accepting.clone();
+                tokio::spawn(async move { let _ = connection(tcp, shared).await; });
+            }
+        });
+        MockTwitch { addr, shared, server }
+    }
+
+    /// The proxy URL to pin the account to.
+    pub fn proxy_url (&self) -> String {
+        format!("http://{}", self.addr)
+    }
+
+    pub fn hits (&self) -> Vec<Hit> {
+        self.shared.hits.lock().unwrap().clone()
+    }
+
+    pub fn scenario (&self) -> Scenario {
+        self.shared.scenario.lock().unwrap().clone()
+    }
+
+    /// Takes the channel offline and announces it over PubSub like Twitch does.
+    pub fn stream_down (&self, login: &str) {
+        let id = {
+            let mut scenario = self.shared.scenario.lock().unwrap();
+            let channel = scenario.channels.iter_mut().find(|c| c.login == login).expect("channel in the scenario");
+            channel.live = false;
+            channel.id.clone()
+        };
+        let message = json!({ "type": "stream-down", "server_time": 0 }).to_string();
+        let frame = json!({ "type": "MESSAGE", "data": { "topic": format!("video-playback-by-id.{id}"), "message": message } });
+        let _ = self.shared.pubsub.send(frame.to_string());
+    }
+}
+
+impl Drop for MockTwitch {
+    fn drop (&mut self) {
+        self.server.abort();
+    }
+}
+
+/// Every connection opens with a CONNECT, like the ones reqwest and the PubSub tunnel send to a proxy.
+async fn connection (mut tcp: TcpStream, shared: Arc<Shared>) -> std::io::Result<()> {
+    let mut head = Vec::new();
+    while !head.ends_with(b"\r\n\r\n") {
+        head.push(tcp.read_u8().await?);
+    }
+    let head = String::from_utf8_lossy(&head);
+    let mut request_line = head.split_whitespace();
+    let (Some("CONNECT"), Some(target)) = (request_line.next(), request_line.next()) else {
+        return tcp.write_all(b"HTTP/1.1 405 Method Not Allowed\r\nContent-Length: 0\r\n\r\n").await;
+    };
+    let (host, port) = target.rsplit_once(':').unwrap_or((target, "443"));
+    let host = host.to_string();
+    tcp.write_all(b"HTTP/1.1 200 Connection Established\r\n\r\n").await?;
+
+    if port == "443" {
+        let tls = shared.tls.accept(tcp).await?;
+        serve(tls, &host, shared).await
+    } else {
+        serve(tcp, &host, shared).await
+    }
+}
+
+async fn serve<S: AsyncRead + AsyncWrite + Unpin> (stream: S, host: &str, shared: Arc<Shared>) -> std::io::Result<()> {
+    if host == PUBSUB_HOST {
+        return pubsub(stream, shared).await;
+    }
+    let mut stream = BufReader::new(stream);
+    while let Some((method, body)) = read_request(&mut stream).await? {
+        let (status, body) = shared.respond(host, &method, &body);
+        let response = match status {
+            204 => "HTTP/1.1 204 No Content\r\n\r\n".to_string(),
+            status => format!("HTTP/1.1 {status} {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{body}", if status == 200 { "OK" } else { "Not Found" }, body.len()),
+        };
+        stream.get_mut().write_all(response.as_bytes()).await?;
+        stream.get_mut().flush().await?;
+    }
+    Ok(())
+}
+
+/// Reads one HTTP/1.1 request, returning its method and body, or `None` once the client hangs up.
+async fn read_request<S: AsyncRead + Unpin> (stream: &mut BufReader<S>) -> std::io::Result<Option<(String, Vec<u8>)>> {
+    let mut line = String::new();
+    if stream.read_line(&mut line).await? == 0 {
+        return Ok(None);
+    }
+    let method = line.split_whitespace().next().unwrap_or_default().to_string();
+    let mut length = 0;
+    loop {
+        line.clear();
+        stream.read_line(&mut line).await?;
+        let header = line.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = header.split_once(':') && name.eq_ignore_ascii_case("content-length") {
+            length = value.trim().parse().unwrap_or(0);
+        }
+    }
+    let mut body = vec![0; length];
+    stream.read_exact(&mut body).await?;
+    Ok(Some((method, body)))
+}
+
+async fn pubsub<S: AsyncRead + AsyncWrite + Unpin> (stream: S, shared: Arc<Shared>) -> std::io::Result<()> {
+    let mut ws = tokio_tungstenite::accept_async(stream).await.map_err(std::io::Error::other)?;
+    let mut outgoing = shared.pubsub.subscribe();
+    loop {
+        tokio::select! {
+            frame = ws.next() => {
+                let Some(Ok(frame)) = frame else { return Ok(()) };
+                let Message::Text(text) = frame else { continue };
+                let Ok(request) = serde_json::from_str::<Value>(&text) else { continue };
+                let reply = match request["type"].as_str() {
+                    Some("LISTEN") => {
+                        let topics = request["data"]["topics"].as_array().into_iter().flatten().filter_map(Value::as_str).map(str::to_string).collect();
+                        shared.hits.lock().unwrap().push(Hit::Listen { topics });
+                        json!({ "type": "RESPONSE", "nonce": request["nonce"], "error": "" })
+                    },
+                    Some("PING") => json!({ "type": "PONG" }),
+                    _ => continue,
+                };
+                ws.send(Message::Text(reply.to_string().into())).await.map_err(std::io::Error::other)?;
+            },
+            Ok(frame) = outgoing.recv() => ws.send(Message::Text(frame.into())).await.map_err(std::io::Error::other)?,
+        }
+    }
+}
+
+impl Shared {
+    fn respond (&self, host: &str, method: &str, body: &[u8]) -> (u16, String) {
+        match (host, method) {
+            ("gql.twitch.tv", "POST") => {
+                let request: Value = serde_json::from_slice(body).unwrap_or_default();
+                let response = match request {
+                    Value::Array(batch) => Value::Array(batch.iter().map(|op| self.gql(op)).collect()),
+                    op => self.gql(&op),
+                };
+                (200, response.to_string())
+            },
+            ("www.twitch.tv", "GET") => (200, format!(r#"<script>window.__config = {{"spade_url": "{SPADE_URL}"}}</script>"#)),
+            ("spade.twitch.tv", "POST") => {
+                if let Some(channel) = watched_channel(body) {
+                    self.hits.lock().unwrap().push(Hit::Watch { channel });
+                }
+                (204, String::new())
+            },
+            _ => (404, String::new()),
+        }
+    }
+
+    fn gql (&self, request: &Value) -> Value {
+        let operation = request["operationName"].as_str().unwrap_or_default().to_string();
+        let variables = request["variables"].clone();
+        self.hits.lock().unwrap().push(Hit::Gql { operation: operation.clone(), variables: variables.clone() });
+
+        let mut scenario = self.scenario.lock().unwrap();
+        let data = match operation.as_str() {
+            "ViewerDropsDashboard" => json!({ "currentUser": campaigns(&scenario) }),
+            "DropCampaignDetails" => json!({ "user": { "dropCampaign": campaign_details(&scenario) } }),
+            "VideoPlayerStreamInfoOverlayChannel" => json!({ "user": variables["channel"].as_str().and_then(|login| stream_info(&scenario, login)) }),
+            "DropsHighlightService_AvailableDrops" => json!({ "channel": variables["channelID"].as_str().map(|id| available_drops(&scenario, id)) }),
+            "DropCurrentSessionContext" => json!({ "currentUser": { "dropCurrentSession": current_drop(&scenario, variables["channelLogin"].as_str().unwrap_or_default()) } }),
+            "Inventory" => json!({ "currentUser": inventory(&scenario) }),
+            "DirectoryPage_Game" => directory(&scenario),
+            "DropsPage_ClaimDropRewards" => {
+                let instance = variables["input"]["dropInstanceID"].as_str().unwrap_or_default();
+                if instance != scenario.drop_instance_id {
+                    return json!({ "data": { "claimDropRewards": null } });
+                }
+                let status = if scenario.claimed { "DROP_INSTANCE_ALREADY_CLAIMED" } else { "ELIGIBLE_FOR_ALL" };
+                scenario.claimed = true;
+                json!({ "claimDropRewards": claim(&scenario, status) })
+            },
+            _ => Value::Null,
+        };
+        json!({ "data": data })
+    }
+}
+
+/// The channel login in a spade `data=` form body, a base64 JSON batch of events.
+fn watched_channel (body: &[u8]) -> Option<String> {
+    let body = std::str::from_utf8(body).ok()?.strip_prefix("data=")?;
+    let decoded = STANDARD.decode(percent_decode_str(body).decode_utf8().ok()?.as_bytes()).ok()?;
+    let events: Value = serde_json::from_slice(&decoded).ok()?;
+    events[0]["properties"]["channel"].as_str().map(str::to_string)
+}
+
+fn to_json (value: impl serde::Serialize) -> Value {
+    serde_json::to_value(value).expect("twitch-gql-rs structs serialize")
+}
+
+fn campaigns (s: &Scenario) -> Value {
+    to_json(Drops {
+        user_id: s.user_id.clone(),
+        login: s.login.clone(),
+        dropCampaigns: vec![DropCampaigns {
+            id: s.campaign_id.clone(),
+            name: format!("{} campaign", s.game),
+            game: CampaignGame { id: s.game_id.clone(), displayName: s.game.clone(), boxArtURL: String::new() },
+            status: "ACTIVE".to_string(),
+            startAt: START_AT.to_string(),
+            endAt: END_AT.to_string(),
+            connecting: CampaignSelf { isAccountConnected: true },
+            ..Default::default()
+        }],
+    })
+}
+
+fn campaign_details (s: &Scenario) -> Value {
+    let channels = s.allow_list.as_ref().map(|logins| {
+        logins.iter().filter_map(|login| s.find(login)).map(|c| Channels { id: c.id.clone(), name: c.login.clone(), displayName: Some(c.login.clone()) }).collect()
+    });
+    to_json(CampaignDetails {
+        id: s.campaign_id.clone(),
+        name: format!("{} campaign", s.game),
+        status: "ACTIVE".to_string(),
+        startAt: START_AT.to_string(),
+        endAt: END_AT.to_string(),
+        self_drop: CampaignSelf { isAccountConnected: true },
+        allow: Allow { isEnabled: channels.is_some(), channels },
+        game: CampaignDetailsGame { id: s.game_id.clone(), slug: s.slug.clone(), displayName: s.game.clone() },
+        timeBasedDrops: vec![TimeBasedDropsCampaignDetails {
+            id: s.drop_id.clone(),
+            name: "Mock reward".to_string(),
+            startAt: START_AT.to_string(),
+            endAt: END_AT.to_string(),
+            requiredMinutesWatched: s.required_minutes,
+            ..Default::default()
+        }],
+        ..Default::default()
+    })
+}
+
+fn stream_info (s: &Scenario, login: &str) -> Option<Value> {
+    let channel = s.find(login)?;
+    Some(to_json(StreamInfo {
+        broadcastSettings: BroadcastSettings {
+            id: channel.id.clone(),
+            title: String::new(),
+            game: StreamGame { displayName: s.game.clone(), slug: s.slug.clone(), name: s.game.clone(), id: s.game_id.clone() },
+        },
+        displayName: channel.login.clone(),
+        id: channel.id.clone(),
+        login: channel.login.clone(),
+        stream: channel.live.then(|| Stream { id: format!("stream-{}", channel.id), viewersCount: channel.viewers, tags: Vec::new() }),
+        ..Default::default()
+    }))
+}
+
+fn available_drops (s: &Scenario, channel_id: &str) -> Value {
+    let campaign = ViewerDropCampaigns { id: s.campaign_id.clone(), game: Some(GameDrops { id: s.game_id.clone(), name: s.game.clone() }), ..Default::default() };
+    to_json(AvailableDrops { id: channel_id.to_string(), viewerDropCampaigns: Some(vec![campaign]) })
+}
+
+/// Progress towards the scenario's drop on any of its channels, nothing once it's claimed.
+fn current_drop (s: &Scenario, login: &str) -> Value {
+    let Some(channel) = s.find(login).filter(|_| !s.claimed) else {
+        return to_json(CurrentDrop::default());
+    };
+    to_json(CurrentDrop {
+        channel: Some(Channels { id: channel.id.clone(), name: channel.login.clone(), displayName: None }),
+        currentMinutesWatched: s.watched_minutes,
+        dropID: s.drop_id.clone(),
+        game: Some(CurrentGame { displayName: s.game.clone(), id: s.game_id.clone() }),
+        requiredMinutesWatched: s.required_minutes,
+    })
+}
+
+fn inventory (s: &Scenario) -> Value {
+    let drop = InventoryTimeBasedDrops {
+        id: s.drop_id.clone(),
+        name: "Mock reward".to_string(),
+        requiredMinutesWatched: s.required_minutes,
+        campaign: InventoryCampaign { id: s.campaign_id.clone(), ..Default::default() },
+        self_drop: InventorySelf {
+            hasPreconditionsMet: true,
+            currentMinutesWatched: s.watched_minutes,
+            isClaimed: s.claimed,
+            dropInstanceID: Some(s.drop_instance_id.clone()),
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    to_json(GetInventory {
+        id: s.user_id.clone(),
+        inventory: Inventory {
+            dropCampaignsInProgress: Some(vec![DropCampaignsInProgress {
+                id: s.campaign_id.clone(),
+                name: format!("{} campaign", s.game),
+                status: "ACTIVE".to_string(),
+                game: InventoryGame { id: s.game_id.clone(), slug: s.slug.clone(), name: s.game.clone(), boxArtURL: String::new() },
+                timeBasedDrops: vec![drop],
+                ..Default::default()
+            }]),
+        },
+    })
+}
+
+/// Every live channel on a single page.
+fn directory (s: &Scenario) -> Value {
+    let edges: Vec<Value> = s.channels.iter().filter(|c| c.live).map(|c| {
+        let node = to_json(GameDirectory {
+            id: format!("stream-{}", c.id),
+            r#type: "live".to_string(),
+            viewersCount: c.viewers,
+            broadcaster: Broadcaster { id: c.id.clone(), login: c.login.clone(), displayName: c.login.clone(), profileImageURL: String::new() },
+            game: Game { id: s.game_id.clone(), name: s.game.clone(), displayName: Some(s.game.clone()), slug: s.slug.clone(), boxArtURL: String::new() },
+            ..Default::default()
+        });
+        json!({ "cursor": c.id, "node": node })
+    }).collect();
+    json!({ "game": { "streams": { "edges": edges, "pageInfo": { "hasNextPage": false } } } })
+}
+
+fn claim (s: &Scenario, status: &str) -> Value {
+    to_json(ClaimDrop {
+        isUserAccountConnected: true,
+        status: status.to_string(),
+        dropType: DropType { id: s.drop_id.clone(), campaign: ClaimCampaign { id: s.campaign_id.clone(), ..Default::default() } },
+    })
+}